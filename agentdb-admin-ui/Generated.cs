@@ -17,12 +17,14 @@ namespace AgentdbAdmin
         public struct ClientDesc {
             public DateTime lastActiveTs;
             public string name;
-            public (uint,uint) partitions;
+            public uint weight;
+            public List<uint> partitions;
         }
         public struct NoResult {
         }
         public struct MessageDesc {
             public Guid messageId;
+            public Guid blobId;
             public Guid recipientId;
             public Nullable<DateTime> scheduledFor;
         }
@@ -39,6 +41,12 @@ namespace AgentdbAdmin
             public SortedDictionary<uint,PartitionDesc> partitions;
             public long agentCount;
         }
+        public struct RepartitionProgress {
+            public (uint,uint) partitionRangeFrom;
+            public (uint,uint) partitionRangeTo;
+            public SortedDictionary<uint,long> remainingMessages;
+            public Nullable<DateTime> estimatedCompletion;
+        }
         public struct KeyValueDesc {
             public List<byte> keyBytes;
             public List<string> keyDecoded;
@@ -83,6 +91,22 @@ namespace AgentdbAdmin
         ) {
             _FnChangePartitions(((_OpaqueHandle)(con)).ToInner(12102493904878135483),_AllocStr(root),((Func<(uint,uint), _RawTuple3>)(_arg18 => new _RawTuple3 { elem0 = _arg18.Item1,elem1 = _arg18.Item2 }))(partitionRange),((Func<Action<NoResult,string>, _RawDelegate>)(_arg19 => _AllocDelegate(new _LocalDelegate22((_arg19_arg0,_arg19_arg1) => _arg19(_DecodeOption(_arg19_arg0, _arg20 => (_arg20).Decode()),_DecodeOption(_arg19_arg1, _arg21 => _FreeStr(_arg21)))), _arg19)))(continuation));
         }
+        public static void ChangePartitionsWithProgress(
+            IOpaqueHandle con,
+            string root,
+            (uint,uint) partitionRange,
+            Action<RepartitionProgress> progress,
+            Action<NoResult,string> continuation
+        ) {
+            _FnChangePartitionsWithProgress(((_OpaqueHandle)(con)).ToInner(12102493904878135483),_AllocStr(root),((Func<(uint,uint), _RawTuple3>)(_arg76 => new _RawTuple3 { elem0 = _arg76.Item1,elem1 = _arg76.Item2 }))(partitionRange),((Func<Action<RepartitionProgress>, _RawDelegate>)(_arg77 => _AllocDelegate(new _LocalDelegate78((_arg77_arg0) => _arg77((_arg77_arg0).Decode())), _arg77)))(progress),((Func<Action<NoResult,string>, _RawDelegate>)(_arg79 => _AllocDelegate(new _LocalDelegate82((_arg79_arg0,_arg79_arg1) => _arg79(_DecodeOption(_arg79_arg0, _arg80 => (_arg80).Decode()),_DecodeOption(_arg79_arg1, _arg81 => _FreeStr(_arg81)))), _arg79)))(continuation));
+        }
+        public static void AbortChangePartitions(
+            IOpaqueHandle con,
+            string root,
+            Action<bool,string> continuation
+        ) {
+            _FnAbortChangePartitions(((_OpaqueHandle)(con)).ToInner(12102493904878135483),_AllocStr(root),((Func<Action<bool,string>, _RawDelegate>)(_arg83 => _AllocDelegate(new _LocalDelegate86((_arg83_arg0,_arg83_arg1) => _arg83(_DecodeOption(_arg83_arg0, _arg84 => (_arg84 != 0)),_DecodeOption(_arg83_arg1, _arg85 => _FreeStr(_arg85)))), _arg83)))(continuation));
+        }
         public static void ListAgents(
             IOpaqueHandle con,
             string root,
@@ -121,19 +145,22 @@ namespace AgentdbAdmin
         private struct _StructClientDesc {
             public long lastActiveTs;
             public _RawSlice name;
-            public _RawTuple3 partitions;
+            public uint weight;
+            public _RawSlice partitions;
             public static _StructClientDesc Encode(ClientDesc structArg) {
                 return new _StructClientDesc {
                     lastActiveTs = (structArg.lastActiveTs).ToUniversalTime().Ticks,
                     name = _AllocStr(structArg.name),
-                    partitions = ((Func<(uint,uint), _RawTuple3>)(_arg46 => new _RawTuple3 { elem0 = _arg46.Item1,elem1 = _arg46.Item2 }))(structArg.partitions)
+                    weight = structArg.weight,
+                    partitions = _AllocSlice<uint, uint>(structArg.partitions, 4, 4, _arg46 => _arg46)
                 };
             }
             public ClientDesc Decode() {
                 return new ClientDesc {
                     lastActiveTs = new DateTime(this.lastActiveTs, DateTimeKind.Utc),
                     name = _FreeStr(this.name),
-                    partitions = ((Func<_RawTuple3, (uint,uint)>)(_arg47 => (_arg47.elem0,_arg47.elem1)))(this.partitions)
+                    weight = this.weight,
+                    partitions = _FreeSlice<uint, uint, List<uint>>(this.partitions, 4, 4, _arg47 => _arg47)
                 };
             }
         }
@@ -151,11 +178,13 @@ namespace AgentdbAdmin
         [StructLayout(LayoutKind.Sequential)]
         private struct _StructMessageDesc {
             public Guid messageId;
+            public Guid blobId;
             public Guid recipientId;
             public _RawTuple6 scheduledFor;
             public static _StructMessageDesc Encode(MessageDesc structArg) {
                 return new _StructMessageDesc {
                     messageId = structArg.messageId,
+                    blobId = structArg.blobId,
                     recipientId = structArg.recipientId,
                     scheduledFor = _EncodeOption(structArg.scheduledFor, _arg48 => (_arg48.Value).ToUniversalTime().Ticks)
                 };
@@ -163,6 +192,7 @@ namespace AgentdbAdmin
             public MessageDesc Decode() {
                 return new MessageDesc {
                     messageId = this.messageId,
+                    blobId = this.blobId,
                     recipientId = this.recipientId,
                     scheduledFor = _DecodeOption(this.scheduledFor, _arg49 => new Nullable<DateTime>(new DateTime(_arg49, DateTimeKind.Utc)))
                 };
@@ -176,17 +206,17 @@ namespace AgentdbAdmin
             public byte batchedMessagesOverflow;
             public static _StructPartitionDesc Encode(PartitionDesc structArg) {
                 return new _StructPartitionDesc {
-                    pendingMessages = _AllocSlice<MessageDesc, _StructMessageDesc>(structArg.pendingMessages, 48, 8, _arg50 => _StructMessageDesc.Encode(_arg50)),
+                    pendingMessages = _AllocSlice<MessageDesc, _StructMessageDesc>(structArg.pendingMessages, 64, 8, _arg50 => _StructMessageDesc.Encode(_arg50)),
                     pendingMessagesOverflow = (structArg.pendingMessagesOverflow ? (byte)1 : (byte)0),
-                    batchedMessages = _AllocSlice<MessageDesc, _StructMessageDesc>(structArg.batchedMessages, 48, 8, _arg51 => _StructMessageDesc.Encode(_arg51)),
+                    batchedMessages = _AllocSlice<MessageDesc, _StructMessageDesc>(structArg.batchedMessages, 64, 8, _arg51 => _StructMessageDesc.Encode(_arg51)),
                     batchedMessagesOverflow = (structArg.batchedMessagesOverflow ? (byte)1 : (byte)0)
                 };
             }
             public PartitionDesc Decode() {
                 return new PartitionDesc {
-                    pendingMessages = _FreeSlice<MessageDesc, _StructMessageDesc, List<MessageDesc>>(this.pendingMessages, 48, 8, _arg52 => (_arg52).Decode()),
+                    pendingMessages = _FreeSlice<MessageDesc, _StructMessageDesc, List<MessageDesc>>(this.pendingMessages, 64, 8, _arg52 => (_arg52).Decode()),
                     pendingMessagesOverflow = (this.pendingMessagesOverflow != 0),
-                    batchedMessages = _FreeSlice<MessageDesc, _StructMessageDesc, List<MessageDesc>>(this.batchedMessages, 48, 8, _arg53 => (_arg53).Decode()),
+                    batchedMessages = _FreeSlice<MessageDesc, _StructMessageDesc, List<MessageDesc>>(this.batchedMessages, 64, 8, _arg53 => (_arg53).Decode()),
                     batchedMessagesOverflow = (this.batchedMessagesOverflow != 0)
                 };
             }
@@ -218,6 +248,29 @@ namespace AgentdbAdmin
             }
         }
         [StructLayout(LayoutKind.Sequential)]
+        private struct _StructRepartitionProgress {
+            public _RawTuple3 partitionRangeFrom;
+            public _RawTuple3 partitionRangeTo;
+            public _RawSlice remainingMessages;
+            public _RawTuple6 estimatedCompletion;
+            public static _StructRepartitionProgress Encode(RepartitionProgress structArg) {
+                return new _StructRepartitionProgress {
+                    partitionRangeFrom = ((Func<(uint,uint), _RawTuple3>)(_arg87 => new _RawTuple3 { elem0 = _arg87.Item1,elem1 = _arg87.Item2 }))(structArg.partitionRangeFrom),
+                    partitionRangeTo = ((Func<(uint,uint), _RawTuple3>)(_arg88 => new _RawTuple3 { elem0 = _arg88.Item1,elem1 = _arg88.Item2 }))(structArg.partitionRangeTo),
+                    remainingMessages = _AllocDict<uint, long, _RawTuple9>(structArg.remainingMessages, 16, 8, _arg89 => ((Func<(uint,long), _RawTuple9>)(_arg90 => new _RawTuple9 { elem0 = _arg90.Item1,elem1 = _arg90.Item2 }))(_arg89)),
+                    estimatedCompletion = _EncodeOption(structArg.estimatedCompletion, _arg91 => (_arg91.Value).ToUniversalTime().Ticks)
+                };
+            }
+            public RepartitionProgress Decode() {
+                return new RepartitionProgress {
+                    partitionRangeFrom = ((Func<_RawTuple3, (uint,uint)>)(_arg92 => (_arg92.elem0,_arg92.elem1)))(this.partitionRangeFrom),
+                    partitionRangeTo = ((Func<_RawTuple3, (uint,uint)>)(_arg93 => (_arg93.elem0,_arg93.elem1)))(this.partitionRangeTo),
+                    remainingMessages = _FreeDict<uint, long, _RawTuple9, SortedDictionary<uint, long>>(this.remainingMessages, 16, 8, _arg94 => ((Func<_RawTuple9, (uint,long)>)(_arg95 => (_arg95.elem0,_arg95.elem1)))(_arg94)),
+                    estimatedCompletion = _DecodeOption(this.estimatedCompletion, _arg96 => new Nullable<DateTime>(new DateTime(_arg96, DateTimeKind.Utc)))
+                };
+            }
+        }
+        [StructLayout(LayoutKind.Sequential)]
         private struct _StructKeyValueDesc {
             public _RawSlice keyBytes;
             public _RawSlice keyDecoded;
@@ -286,6 +339,20 @@ namespace AgentdbAdmin
             _RawTuple3 partitionRange,
             _RawDelegate continuation
         );
+        [DllImport("agentdb_admin", EntryPoint = "rnet_export_change_partitions_with_progress", CallingConvention = CallingConvention.Cdecl)]
+        private static extern void _FnChangePartitionsWithProgress(
+            _RawOpaqueHandle con,
+            _RawSlice root,
+            _RawTuple3 partitionRange,
+            _RawDelegate progress,
+            _RawDelegate continuation
+        );
+        [DllImport("agentdb_admin", EntryPoint = "rnet_export_abort_change_partitions", CallingConvention = CallingConvention.Cdecl)]
+        private static extern void _FnAbortChangePartitions(
+            _RawOpaqueHandle con,
+            _RawSlice root,
+            _RawDelegate continuation
+        );
         [DllImport("agentdb_admin", EntryPoint = "rnet_export_list_agents", CallingConvention = CallingConvention.Cdecl)]
         private static extern void _FnListAgents(
             _RawOpaqueHandle con,
@@ -320,6 +387,9 @@ namespace AgentdbAdmin
         [UnmanagedFunctionPointer(CallingConvention.Cdecl)] delegate void _LocalDelegate11(_RawTuple1 arg0,_RawTuple0 arg1);
         [UnmanagedFunctionPointer(CallingConvention.Cdecl)] delegate void _LocalDelegate17(_RawTuple2 arg0,_RawTuple0 arg1);
         [UnmanagedFunctionPointer(CallingConvention.Cdecl)] delegate void _LocalDelegate22(_RawTuple4 arg0,_RawTuple0 arg1);
+        [UnmanagedFunctionPointer(CallingConvention.Cdecl)] delegate void _LocalDelegate78(_StructRepartitionProgress arg0);
+        [UnmanagedFunctionPointer(CallingConvention.Cdecl)] delegate void _LocalDelegate82(_RawTuple4 arg0,_RawTuple0 arg1);
+        [UnmanagedFunctionPointer(CallingConvention.Cdecl)] delegate void _LocalDelegate86(_RawTuple10 arg0,_RawTuple0 arg1);
         [UnmanagedFunctionPointer(CallingConvention.Cdecl)] delegate void _LocalDelegate27(_RawTuple0 arg0,_RawTuple0 arg1);
         [UnmanagedFunctionPointer(CallingConvention.Cdecl)] delegate void _LocalDelegate34(_RawTuple0 arg0,_RawTuple0 arg1);
         [UnmanagedFunctionPointer(CallingConvention.Cdecl)] delegate void _LocalDelegate40(_RawTuple0 arg0,_RawTuple0 arg1);
@@ -482,6 +552,30 @@ namespace AgentdbAdmin
                 throw new RustException(_FreeStr(arg.elem1));
             }
         }
+        [StructLayout(LayoutKind.Sequential)]
+        private struct _RawTuple9 {
+            public uint elem0;
+            public long elem1;
+        }
+        [StructLayout(LayoutKind.Sequential)]
+        private struct _RawTuple10 {
+            public byte elem0;
+            public byte elem1;
+        }
+        private static _RawTuple10 _EncodeOption<T>(T arg, Func<T, byte> converter) {
+            if (arg != null) {
+                return new _RawTuple10 { elem0 = converter(arg), elem1 = 1 };
+            } else {
+                return new _RawTuple10 { elem0 = default(byte), elem1 = 0 };
+            }
+        }
+        private static T _DecodeOption<T>(_RawTuple10 arg, Func<byte, T> converter) {
+            if (arg.elem1 != 0) {
+                return converter(arg.elem0);
+            } else {
+                return default(T);
+            }
+        }
 
 
         [UnmanagedFunctionPointer(CallingConvention.Cdecl)]
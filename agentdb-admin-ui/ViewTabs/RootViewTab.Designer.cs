@@ -70,6 +70,7 @@ namespace AgentdbAdmin
             this.actionsFlowLayout = new System.Windows.Forms.FlowLayoutPanel();
             this.repartitionButton = new System.Windows.Forms.Button();
             this.listAgentsButton = new System.Windows.Forms.Button();
+            this.abortRepartitionButton = new System.Windows.Forms.Button();
             this.agentCountLabel = new System.Windows.Forms.Label();
             this.agentCountBox = new System.Windows.Forms.TextBox();
             splitContainer = new System.Windows.Forms.SplitContainer();
@@ -503,6 +504,7 @@ namespace AgentdbAdmin
             this.actionsFlowLayout.AutoSize = true;
             this.actionsFlowLayout.Controls.Add(this.repartitionButton);
             this.actionsFlowLayout.Controls.Add(this.listAgentsButton);
+            this.actionsFlowLayout.Controls.Add(this.abortRepartitionButton);
             this.actionsFlowLayout.Dock = System.Windows.Forms.DockStyle.Fill;
             this.actionsFlowLayout.Font = new System.Drawing.Font("Microsoft Sans Serif", 12F, System.Drawing.FontStyle.Regular, System.Drawing.GraphicsUnit.Point, ((byte)(254)));
             this.actionsFlowLayout.Location = new System.Drawing.Point(185, 153);
@@ -532,6 +534,17 @@ namespace AgentdbAdmin
             this.listAgentsButton.UseVisualStyleBackColor = true;
             this.listAgentsButton.Click += new System.EventHandler(this.listAgentsButton_Click);
             // 
+            // abortRepartitionButton
+            // 
+            this.abortRepartitionButton.AutoSize = true;
+            this.abortRepartitionButton.Location = new System.Drawing.Point(243, 3);
+            this.abortRepartitionButton.Name = "abortRepartitionButton";
+            this.abortRepartitionButton.Size = new System.Drawing.Size(144, 30);
+            this.abortRepartitionButton.TabIndex = 2;
+            this.abortRepartitionButton.Text = "Abort re-partition";
+            this.abortRepartitionButton.UseVisualStyleBackColor = true;
+            this.abortRepartitionButton.Click += new System.EventHandler(this.abortRepartitionButton_Click);
+            // 
             // agentCountLabel
             // 
             this.agentCountLabel.AutoSize = true;
@@ -613,6 +626,7 @@ namespace AgentdbAdmin
         private System.Windows.Forms.FlowLayoutPanel actionsFlowLayout;
         private System.Windows.Forms.Button repartitionButton;
         private System.Windows.Forms.Button listAgentsButton;
+        private System.Windows.Forms.Button abortRepartitionButton;
         private System.Windows.Forms.TextBox agentCountBox;
         private System.Windows.Forms.Label agentCountLabel;
     }
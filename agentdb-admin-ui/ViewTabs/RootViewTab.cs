@@ -17,6 +17,7 @@ namespace AgentdbAdmin
         AgentdbAdmin.IOpaqueHandle connectionHandle;
         private string root;
         private AgentdbAdmin.RootDesc rootDesc;
+        private AgentdbAdmin.RepartitionProgress? repartitionProgress;
         private TreeNode overviewNode;
         private TreeNode clientsNode;
         private TreeNode partitionsNode;
@@ -59,6 +60,7 @@ namespace AgentdbAdmin
             recvPartitionBox.Visible = tag == null;
             recvPartitionLabel.Visible = tag == null;
             repartitionButton.Visible = tag == null;
+            abortRepartitionButton.Visible = tag == null && rootDesc.partitionRangeRecv != rootDesc.partitionRangeSend;
             actionsLabel.Visible = repartitionButton.Visible;
 
             var selectedPartitions = new SortedSet<uint>();
@@ -66,13 +68,10 @@ namespace AgentdbAdmin
             if (client.HasValue)
             {
                 detailsNameBox.Text = client.Value.name;
-                detailsTypeBox.Text = "Client";
+                detailsTypeBox.Text = $"Client (weight {client.Value.weight})";
                 lastActiveBox.Text = client.Value.lastActiveTs.ToString(Utils.DateFormat);
-                includedPartitionsBox.Text = $"{client.Value.partitions.Item1} to {client.Value.partitions.Item2 - 1}";
-                for (var p = client.Value.partitions.Item1; p < client.Value.partitions.Item2; ++p)
-                {
-                    selectedPartitions.Add(p);
-                }
+                includedPartitionsBox.Text = string.Join(", ", client.Value.partitions);
+                selectedPartitions.UnionWith(client.Value.partitions);
             } else if (partition.HasValue)
             {
                 detailsNameBox.Text = "Partition " + partition.Value.Key;
@@ -105,6 +104,14 @@ namespace AgentdbAdmin
                 {
                     includedPartitionsBox.Text = $"{recvPartitionBox.Text}, {sendPartitionBox.Text}";
                 }
+
+                if (repartitionProgress.HasValue)
+                {
+                    var progress = repartitionProgress.Value;
+                    var remaining = progress.remainingMessages.Values.Sum();
+                    var completion = progress.estimatedCompletion.HasValue ? $", done by {progress.estimatedCompletion.Value.ToString(Utils.DateFormat)}" : "";
+                    sendPartitionBox.Text += $" ({remaining} messages left to move{completion})";
+                }
             }
 
             messagesView.BeginUpdate();
@@ -267,7 +274,7 @@ namespace AgentdbAdmin
                 {
                     parent.OpenPage(new ConnectionTab.BlobPageId() {
                         Root = root,
-                        BlobId = message.Value.messageId,
+                        BlobId = message.Value.blobId,
                     });
                 }
             }
@@ -308,10 +315,47 @@ namespace AgentdbAdmin
             dialog.NewPartitionRange = rootDesc.partitionRangeSend;
             if (dialog.ShowDialog(parent.MainForm) == DialogResult.OK)
             {
-                await parent.MainForm.PerformAsync<AgentdbAdmin.NoResult>("Re-partitioning root", continuation =>
+                try
                 {
-                    AgentdbAdmin.ChangePartitions(connectionHandle, this.root, dialog.NewPartitionRange, continuation);
-                });
+                    await parent.MainForm.PerformAsync<AgentdbAdmin.NoResult>("Re-partitioning root", continuation =>
+                    {
+                        AgentdbAdmin.ChangePartitionsWithProgress(connectionHandle, this.root, dialog.NewPartitionRange, progress => this.BeginInvoke(new Action(() =>
+                        {
+                            repartitionProgress = progress;
+                            PerformRefresh();
+                        })), continuation);
+                    });
+                }
+                catch (AgentdbAdmin.RustException ex)
+                {
+                    // The re-partition fails if it is aborted while in progress
+                    MessageBox.Show(parent.MainForm, ex.Message, "Re-partition stopped", MessageBoxButtons.OK, MessageBoxIcon.Information);
+                }
+                finally
+                {
+                    repartitionProgress = null;
+                    PerformRefresh();
+                }
+            }
+        }
+
+        private async void abortRepartitionButton_Click(object sender, EventArgs e)
+        {
+            var oldRange = $"{rootDesc.partitionRangeRecv.Item1} to {rootDesc.partitionRangeRecv.Item2 - 1}";
+            var result = MessageBox.Show(parent.MainForm, $"Move all messages back to partitions {oldRange}? No messages will be delivered from those partitions until this completes.", "Abort re-partition", MessageBoxButtons.OKCancel, MessageBoxIcon.Warning);
+            if (result == DialogResult.OK)
+            {
+                try
+                {
+                    await parent.MainForm.PerformAsync<bool>("Aborting re-partition", continuation =>
+                    {
+                        AgentdbAdmin.AbortChangePartitions(connectionHandle, this.root, continuation);
+                    });
+                }
+                finally
+                {
+                    PerformRefresh();
+                }
             }
         }
 
@@ -10,7 +10,7 @@ use foundationdb::{
 };
 use futures::{stream::TryStreamExt, FutureExt};
 use lazy_static::lazy_static;
-use rnet::{net, Delegate2, Net, ToNet};
+use rnet::{net, Delegate1, Delegate2, Net, ToNet};
 use tokio::runtime::Runtime;
 
 use agentdb_core::{
@@ -62,7 +62,8 @@ pub struct Connection {
 pub struct ClientDesc {
     last_active_ts: DateTime<Utc>,
     name: String,
-    partitions: Range<u32>,
+    weight: u32,
+    partitions: Vec<u32>,
 }
 
 #[derive(Net)]
@@ -79,7 +80,8 @@ impl From<admin::ClientDesc> for ClientDesc {
         Self {
             last_active_ts: other.last_active_ts().into(),
             name: other.name().into(),
-            partitions: other.partitions(),
+            weight: other.weight(),
+            partitions: other.partitions().into(),
         }
     }
 }
@@ -87,6 +89,7 @@ impl From<admin::ClientDesc> for ClientDesc {
 #[derive(Net)]
 pub struct MessageDesc {
     message_id: Uuid,
+    blob_id: Uuid,
     recipient_id: Uuid,
     scheduled_for: Option<DateTime<Utc>>,
 }
@@ -95,6 +98,7 @@ impl From<admin::MessageDesc> for MessageDesc {
     fn from(other: admin::MessageDesc) -> Self {
         Self {
             message_id: other.message_id(),
+            blob_id: other.blob_id(),
             recipient_id: other.recipient_id(),
             scheduled_for: other.scheduled_for().map(Into::into),
         }
@@ -212,6 +216,55 @@ fn change_partitions(
     });
 }
 
+#[derive(Net)]
+pub struct RepartitionProgress {
+    partition_range_from: Range<u32>,
+    partition_range_to: Range<u32>,
+    remaining_messages: BTreeMap<u32, i64>,
+    estimated_completion: Option<DateTime<Utc>>,
+}
+
+impl From<admin::RepartitionProgress> for RepartitionProgress {
+    fn from(other: admin::RepartitionProgress) -> Self {
+        Self {
+            partition_range_from: other.partition_range_from(),
+            partition_range_to: other.partition_range_to(),
+            remaining_messages: other
+                .remaining_messages()
+                .iter()
+                .map(|(&k, &v)| (k, v as i64))
+                .collect(),
+            estimated_completion: other.estimated_completion().map(Into::into),
+        }
+    }
+}
+
+#[net]
+fn change_partitions_with_progress(
+    con: Arc<Connection>,
+    root: String,
+    partition_range: Range<u32>,
+    progress: Delegate1<(), RepartitionProgress>,
+    continuation: Continuation<NoResult>,
+) {
+    wrap_async(continuation, async move {
+        admin::change_partitions_with_progress(con.global.clone(), &root, partition_range)
+            .try_for_each(|item| {
+                progress.call(item.into());
+                async { Ok(()) }
+            })
+            .await
+            .map(Into::into)
+    });
+}
+
+#[net]
+fn abort_change_partitions(con: Arc<Connection>, root: String, continuation: Continuation<bool>) {
+    wrap_async(continuation, async move {
+        admin::abort_change_partitions(&con.global, &root).await
+    });
+}
+
 #[net]
 fn list_agents(
     con: Arc<Connection>,
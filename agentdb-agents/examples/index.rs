@@ -77,5 +77,11 @@ async fn main() -> Result<(), Error> {
     }
     ctx.run(&global).await?;
 
-    run(default_client_name(), global, MY_ROOT).await
+    run(
+        default_client_name(),
+        global,
+        MY_ROOT,
+        ClientConfig::default(),
+    )
+    .await
 }
@@ -89,5 +89,11 @@ async fn main() -> Result<(), Error> {
     ctx.construct(MY_ROOT, MyConstructor)?;
     ctx.run(&global).await?;
 
-    run(default_client_name(), global, MY_ROOT).await
+    run(
+        default_client_name(),
+        global,
+        MY_ROOT,
+        ClientConfig::default(),
+    )
+    .await
 }
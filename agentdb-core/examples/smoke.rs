@@ -1,6 +1,9 @@
 use std::sync::Arc;
 
-use agentdb_core::{id, Global, OutboundMessage, StateFnInput, StateFnOutput, Timestamp};
+use agentdb_core::{
+    id, ClientConfig, Error, Global, OutboundMessage, Priority, StateFnInput, StateFnOutput,
+    Timestamp,
+};
 use foundationdb::TransactOption;
 use futures::FutureExt;
 use serde::{Deserialize, Serialize};
@@ -23,7 +26,7 @@ enum Message {
     Hello(String),
 }
 
-async fn state_fn(input: StateFnInput<'_>) -> Result<StateFnOutput, ()> {
+async fn state_fn(input: StateFnInput<'_>) -> Result<StateFnOutput, Error> {
     let mut state = if let Some(state) = input.state {
         postcard::from_bytes(&state).unwrap()
     } else {
@@ -47,6 +50,7 @@ async fn state_fn(input: StateFnInput<'_>) -> Result<StateFnOutput, ()> {
     Ok(StateFnOutput {
         state: Some(postcard::to_stdvec(&state).unwrap()),
         messages: Vec::new(),
+        message_changes: Vec::new(),
         commit_hook: Box::new(|_ctx| {}),
     })
 }
@@ -63,10 +67,15 @@ async fn say_hello(global: &Global, id: Uuid, from: &str) -> anyhow::Result<()>
                         tx,
                         global,
                         &[OutboundMessage {
+                            message_id: id::new(),
                             recipient_root: ROOT.into(),
                             recipient_id: id,
                             operation_id: id::new(),
                             when: Timestamp::now(),
+                            priority: Priority::Normal,
+                            expires_at: None,
+                            message_type: None,
+                            attachment: None,
                             content,
                         }],
                         0,
@@ -101,6 +110,7 @@ async fn main() -> anyhow::Result<()> {
         global,
         ROOT.into(),
         Arc::new(|input| Box::pin(state_fn(input))),
+        ClientConfig::default(),
     )
     .await?;
 
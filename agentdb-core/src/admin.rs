@@ -12,17 +12,32 @@ use std::{
 use anyhow::anyhow;
 use byteorder::{ByteOrder, LittleEndian};
 use foundationdb::{
-    directory::Directory, options::StreamingMode, RangeOption, TransactOption, Transaction,
+    directory::Directory,
+    options::{MutationType, StreamingMode},
+    tuple::Versionstamp,
+    KeySelector, RangeOption, TransactOption, Transaction,
 };
 use futures::{stream, FutureExt, Stream, StreamExt, TryFutureExt, TryStreamExt};
+use serde::{de::DeserializeOwned, Serialize};
 use uuid::Uuid;
 
 use crate::{
-    client::{ClientValue, PartitionAssignment, PartitionRange},
+    blob::{self, Reencrypted, BLOB_STRIPE_SIZE},
+    client::{
+        mark_clients_modified, AssignedClient, ClientValue, PartitionAssignment, PartitionRange,
+    },
+    dead_letter::DeadLetterValue,
     directories::{Global, PartitionSpace, RootSpace, AGENTDB_LAYER},
-    partition::mark_partition_modified,
-    utils::{load_partition_range, range_is_empty, save_value},
-    Error, MessageHeader, Timestamp,
+    history::{self, StateVersion},
+    message::{change_scheduled_message, release_message_content},
+    partition::{mark_partition_modified, RetryAtState},
+    policy::{
+        AssignmentPolicy, BudgetPolicy, ChangeFeedPolicy, CompressionPolicy, DeadLetterPolicy,
+        HistoryPolicy, IdempotencyPolicy, TracePolicy,
+    },
+    trace::TraceEntry,
+    utils::{load_partition_range, load_value, next_key, partition_for_recipient, save_value},
+    Error, MessageHeader, Priority, Timestamp, TypedSubspace,
 };
 
 /// Look for AgentDB roots present in the provided database.
@@ -74,7 +89,8 @@ pub fn search_for_roots(global: &Arc<Global>) -> impl Stream<Item = Result<Strin
 pub struct ClientDesc {
     last_active_ts: Timestamp,
     name: String,
-    partitions: Range<u32>,
+    weight: u32,
+    partitions: Vec<u32>,
 }
 
 impl ClientDesc {
@@ -86,9 +102,14 @@ impl ClientDesc {
     pub fn last_active_ts(&self) -> Timestamp {
         self.last_active_ts
     }
-    /// The range of partitions owned by this client.
-    pub fn partitions(&self) -> Range<u32> {
-        self.partitions.clone()
+    /// The capacity of this client relative to the other clients.
+    pub fn weight(&self) -> u32 {
+        self.weight
+    }
+    /// The partitions owned by this client, in ascending order, including any
+    /// partitions pinned to this client.
+    pub fn partitions(&self) -> &[u32] {
+        &self.partitions
     }
 }
 
@@ -96,8 +117,11 @@ impl ClientDesc {
 #[derive(Debug, Clone)]
 pub struct MessageDesc {
     message_id: Uuid,
+    blob_id: Uuid,
     recipient_id: Uuid,
     scheduled_for: Option<Timestamp>,
+    priority: Priority,
+    expires_at: Option<Timestamp>,
 }
 
 impl MessageDesc {
@@ -105,6 +129,10 @@ impl MessageDesc {
     pub fn message_id(&self) -> Uuid {
         self.message_id
     }
+    /// The ID of the blob holding the message content.
+    pub fn blob_id(&self) -> Uuid {
+        self.blob_id
+    }
     /// The ID of the receiving agent.
     pub fn recipient_id(&self) -> Uuid {
         self.recipient_id
@@ -114,6 +142,15 @@ impl MessageDesc {
     pub fn scheduled_for(&self) -> Option<Timestamp> {
         self.scheduled_for
     }
+    /// The priority with which this message will be delivered.
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+    /// The time after which this message will be dropped rather than
+    /// delivered, if any.
+    pub fn expires_at(&self) -> Option<Timestamp> {
+        self.expires_at
+    }
 }
 
 /// Information about a partition within an AgentDB root.
@@ -123,6 +160,8 @@ pub struct PartitionDesc {
     pending_messages_overflow: bool,
     batched_messages: Vec<MessageDesc>,
     batched_messages_overflow: bool,
+    learned_batch_sizes: Vec<(Uuid, usize)>,
+    learned_batch_sizes_overflow: bool,
 }
 
 impl PartitionDesc {
@@ -150,6 +189,19 @@ impl PartitionDesc {
     pub fn batched_messages_overflow(&self) -> bool {
         self.batched_messages_overflow
     }
+    /// A list of the first N agents within this partition whose batches have
+    /// been limited to fewer messages than the configured maximum, along with
+    /// the batch size learned for each one. Agents are added to this list when
+    /// their batches repeatedly fail to commit, and removed once the batch size
+    /// has grown back to the maximum.
+    pub fn learned_batch_sizes(&self) -> &[(Uuid, usize)] {
+        &self.learned_batch_sizes
+    }
+    /// Returns `true` if the `learned_batch_sizes` list was cut off because there
+    /// were too many agents to return.
+    pub fn learned_batch_sizes_overflow(&self) -> bool {
+        self.learned_batch_sizes_overflow
+    }
 }
 
 /// Information about an AgentDB root.
@@ -160,6 +212,8 @@ pub struct RootDesc {
     clients: Vec<ClientDesc>,
     partitions: BTreeMap<u32, PartitionDesc>,
     agent_count: i64,
+    expired_message_count: i64,
+    assignment_policy: AssignmentPolicy,
 }
 
 impl RootDesc {
@@ -189,43 +243,70 @@ impl RootDesc {
     pub fn agent_count(&self) -> i64 {
         self.agent_count
     }
+    /// The total number of messages sent to agents within this root which were
+    /// dropped because they expired before they could be delivered.
+    pub fn expired_message_count(&self) -> i64 {
+        self.expired_message_count
+    }
+    /// The policy used to assign partitions to clients, including any pinned
+    /// partitions.
+    pub fn assignment_policy(&self) -> &AssignmentPolicy {
+        &self.assignment_policy
+    }
 }
 
 async fn describe_clients(
     tx: &Transaction,
     root: &RootSpace,
     partition_range: PartitionRange,
+    policy: AssignmentPolicy,
 ) -> Result<Vec<ClientDesc>, Error> {
     // Scan for all the active clients
     let client_range = root.clients.range().into();
     let mut kv_stream = tx.get_ranges(client_range, true);
-    let mut clients = Vec::new();
+    let mut assigned_clients = Vec::new();
+    let mut last_active = Vec::new();
     while let Some(kvs) = kv_stream.try_next().await? {
         for kv in kvs {
-            if let Ok(client_value) = postcard::from_bytes::<ClientValue>(kv.value()) {
-                clients.push(client_value);
+            if let (Ok(client_id), Some(client_value)) = (
+                root.clients.unpack(kv.key()),
+                ClientValue::decode(kv.value()),
+            ) {
+                assigned_clients.push(AssignedClient {
+                    id: client_id,
+                    name: client_value.name,
+                    weight: client_value.weight,
+                });
+                last_active.push(client_value.last_active_ts);
             }
         }
     }
-    let client_count = clients.len() as u32;
-    let clients = clients
+    let mut partition_assignment = PartitionAssignment {
+        partition_range,
+        policy,
+        clients: assigned_clients,
+        client_index: 0,
+    };
+    let clients = last_active
         .into_iter()
         .enumerate()
-        .map(|(i, client_value)| ClientDesc {
-            name: client_value.name,
-            last_active_ts: client_value.last_active_ts,
-            partitions: PartitionAssignment {
-                client_count,
-                client_index: i as u32,
-                partition_range,
+        .map(|(i, last_active_ts)| {
+            partition_assignment.client_index = i;
+            let client = &partition_assignment.clients[i];
+            ClientDesc {
+                name: client.name.clone(),
+                weight: client.weight,
+                last_active_ts,
+                partitions: partition_assignment.partitions(),
             }
-            .range(),
         })
         .collect();
     Ok(clients)
 }
 
 const DESC_LIMIT: usize = 1000;
+// Re-encrypt at most this many bytes of blob data in each transaction
+const REENCRYPT_BYTES_PER_TRANSACTION: usize = 1024 * 1024;
 
 async fn describe_partition(
     tx: &Transaction,
@@ -240,16 +321,19 @@ async fn describe_partition(
     while let Some(batch) = pending_message_stream.try_next().await? {
         pending_messages_overflow &= batch.more();
         for item in batch {
-            if let Ok((ts, _, _)) = partition.message.unpack(item.key()) {
-                if let Ok(msg_hdr) = postcard::from_bytes::<MessageHeader>(item.value()) {
+            if let Ok((_, ts, _, _)) = partition.message.unpack(item.key()) {
+                if let Ok(msg_hdr) = MessageHeader::decode(item.value()) {
                     pending_messages.push(MessageDesc {
-                        message_id: msg_hdr.blob_id,
+                        message_id: msg_hdr.message_id,
+                        blob_id: msg_hdr.blob_id,
                         recipient_id: msg_hdr.recipient_id,
                         scheduled_for: if ts == Timestamp::zero() {
                             None
                         } else {
                             Some(ts)
                         },
+                        priority: msg_hdr.priority,
+                        expires_at: msg_hdr.expires_at,
                     });
                 }
             }
@@ -265,21 +349,44 @@ async fn describe_partition(
     while let Some(batch) = batched_message_stream.try_next().await? {
         batched_messages_overflow &= batch.more();
         for item in batch {
-            if let Ok(msg_hdr) = postcard::from_bytes::<MessageHeader>(item.value()) {
+            if let Ok(msg_hdr) = MessageHeader::decode(item.value()) {
                 batched_messages.push(MessageDesc {
-                    message_id: msg_hdr.blob_id,
+                    message_id: msg_hdr.message_id,
+                    blob_id: msg_hdr.blob_id,
                     recipient_id: msg_hdr.recipient_id,
                     scheduled_for: None,
+                    priority: msg_hdr.priority,
+                    expires_at: msg_hdr.expires_at,
                 });
             }
         }
     }
 
+    // Load learned batch sizes
+    let mut learned_batch_sizes_range: RangeOption = partition.agent_batch_size.range().into();
+    learned_batch_sizes_range.limit = Some(DESC_LIMIT);
+    let mut learned_batch_size_stream = tx.get_ranges(learned_batch_sizes_range, true);
+    let mut learned_batch_sizes = Vec::new();
+    let mut learned_batch_sizes_overflow = true;
+    while let Some(batch) = learned_batch_size_stream.try_next().await? {
+        learned_batch_sizes_overflow &= batch.more();
+        for item in batch {
+            if let (Ok(agent_id), Ok(batch_size)) = (
+                partition.agent_batch_size.unpack(item.key()),
+                postcard::from_bytes::<u64>(item.value()),
+            ) {
+                learned_batch_sizes.push((agent_id, batch_size as usize));
+            }
+        }
+    }
+
     Ok(PartitionDesc {
         pending_messages,
         pending_messages_overflow,
         batched_messages,
         batched_messages_overflow,
+        learned_batch_sizes,
+        learned_batch_sizes_overflow,
     })
 }
 
@@ -304,16 +411,20 @@ fn convert_range(range: PartitionRange) -> Range<u32> {
     range.offset..(range.offset + range.count)
 }
 
-async fn calculate_agent_count(tx: &Transaction, root: &RootSpace) -> Result<i64, Error> {
-    let agent_count_range: RangeOption = root.agent_counts.range().into();
-    let mut stream = tx.get_ranges(agent_count_range, true);
-    let mut agent_count = 0;
+// Sums a counter which is sharded across multiple keys to avoid conflicts
+async fn calculate_total_count(
+    tx: &Transaction,
+    counts: &TypedSubspace<u32>,
+) -> Result<i64, Error> {
+    let count_range: RangeOption = counts.range().into();
+    let mut stream = tx.get_ranges(count_range, true);
+    let mut total_count = 0;
     while let Some(item) = stream.try_next().await? {
         for value in item {
-            agent_count += LittleEndian::read_i64(value.value());
+            total_count += LittleEndian::read_i64(value.value());
         }
     }
-    Ok(agent_count)
+    Ok(total_count)
 }
 
 /// Obtain information about a given root.
@@ -330,7 +441,14 @@ pub async fn describe_root(global: &Global, root: &str) -> Result<RootDesc, Erro
                     let partition_range_recv =
                         load_partition_range(tx, &root.partition_range_recv, true).await?;
 
-                    let clients = describe_clients(tx, root, partition_range_recv).await?;
+                    let assignment_policy =
+                        load_value::<AssignmentPolicy>(tx, &root.assignment_policy, true)
+                            .await?
+                            .unwrap_or_default();
+
+                    let clients =
+                        describe_clients(tx, root, partition_range_recv, assignment_policy.clone())
+                            .await?;
                     let partitions = describe_partitions(
                         tx,
                         global,
@@ -340,7 +458,9 @@ pub async fn describe_root(global: &Global, root: &str) -> Result<RootDesc, Erro
                     )
                     .await?;
 
-                    let agent_count = calculate_agent_count(tx, root).await?;
+                    let agent_count = calculate_total_count(tx, &root.agent_counts).await?;
+                    let expired_message_count =
+                        calculate_total_count(tx, &root.expired_counts).await?;
 
                     Ok(RootDesc {
                         partition_range_send: convert_range(partition_range_send),
@@ -348,6 +468,8 @@ pub async fn describe_root(global: &Global, root: &str) -> Result<RootDesc, Erro
                         clients,
                         partitions,
                         agent_count,
+                        expired_message_count,
+                        assignment_policy,
                     })
                 }
                 .boxed()
@@ -357,59 +479,175 @@ pub async fn describe_root(global: &Global, root: &str) -> Result<RootDesc, Erro
         .await
 }
 
-async fn wait_for_empty_partitions(
-    global: &Global,
-    root: &RootSpace,
-    partition_range: PartitionRange,
-) -> Result<(), Error> {
-    while !global
-        .db()
-        .transact_boxed(
-            (global, root),
-            |tx, &mut (global, root)| {
-                async move {
-                    for partition_idx in
-                        partition_range.offset..(partition_range.offset + partition_range.count)
-                    {
-                        let partition = root.partition(global, partition_idx).await?;
-                        let message_range: RangeOption = partition.message.range().into();
-                        let batch_range: RangeOption = partition.batch.range().into();
-                        if !range_is_empty(tx, message_range, true).await?
-                            || !range_is_empty(tx, batch_range, true).await?
-                        {
-                            return Ok(false);
+/// Progress of an operation to change the partitions within a root.
+#[derive(Debug, Clone)]
+pub struct RepartitionProgress {
+    partition_range_from: Range<u32>,
+    partition_range_to: Range<u32>,
+    remaining_messages: BTreeMap<u32, u64>,
+    estimated_completion: Option<Timestamp>,
+}
+
+impl RepartitionProgress {
+    /// The range of partitions which messages are being moved away from.
+    pub fn partition_range_from(&self) -> Range<u32> {
+        self.partition_range_from.clone()
+    }
+    /// The range of partitions which messages are being moved to.
+    pub fn partition_range_to(&self) -> Range<u32> {
+        self.partition_range_to.clone()
+    }
+    /// A mapping from each old partition to the number of messages which have
+    /// yet to be processed or moved away from it. To keep this cheap to
+    /// calculate, partitions with very large backlogs report a lower bound.
+    pub fn remaining_messages(&self) -> &BTreeMap<u32, u64> {
+        &self.remaining_messages
+    }
+    /// The total number of messages remaining across all the old partitions.
+    pub fn total_remaining_messages(&self) -> u64 {
+        self.remaining_messages.values().sum()
+    }
+    /// Returns `true` once all messages have left the old partitions, and
+    /// clients have been allowed to process messages from the new partitions.
+    pub fn is_complete(&self) -> bool {
+        self.total_remaining_messages() == 0
+    }
+    /// When the operation is expected to complete, based on how quickly messages
+    /// have left the old partitions so far. Returns `None` until some progress
+    /// has been observed.
+    pub fn estimated_completion(&self) -> Option<Timestamp> {
+        self.estimated_completion
+    }
+}
+
+const REPARTITION_POLL_INTERVAL: Duration = Duration::from_secs(5);
+const REPARTITION_COUNT_LIMIT: usize = 10000;
+
+async fn count_remaining_messages(
+    tx: &Transaction,
+    partition: &PartitionSpace,
+) -> Result<u64, Error> {
+    let mut count = 0;
+    for range in [partition.message.range(), partition.batch.range()] {
+        let mut range: RangeOption = range.into();
+        range.limit = Some(REPARTITION_COUNT_LIMIT);
+        range.mode = StreamingMode::WantAll;
+        let mut stream = tx.get_ranges(range, true);
+        while let Some(values) = stream.try_next().await? {
+            count += values.len() as u64;
+        }
+    }
+    Ok(count)
+}
+
+// Follows a partition change operation, completing it once all messages have left
+// the old partitions.
+struct RepartitionTracker {
+    from: PartitionRange,
+    to: PartitionRange,
+    initial: Option<(Timestamp, u64)>,
+}
+
+impl RepartitionTracker {
+    fn new(from: PartitionRange, to: PartitionRange) -> Self {
+        Self {
+            from,
+            to,
+            initial: None,
+        }
+    }
+    async fn poll(
+        &mut self,
+        global: &Global,
+        root: &RootSpace,
+    ) -> Result<RepartitionProgress, Error> {
+        // Count the messages in each old partition separately, to stay within
+        // transaction limits
+        let mut remaining_messages = BTreeMap::new();
+        for partition_idx in convert_range(self.from) {
+            let partition = root.partition(global, partition_idx).await?;
+            let count = global
+                .db()
+                .transact_boxed(
+                    &partition,
+                    |tx, &mut partition| count_remaining_messages(tx, partition).boxed(),
+                    TransactOption::idempotent(),
+                )
+                .await?;
+            remaining_messages.insert(partition_idx, count);
+        }
+        let total: u64 = remaining_messages.values().sum();
+
+        let (from, to) = (self.from, self.to);
+        global
+            .db()
+            .transact_boxed(
+                root,
+                |tx, &mut root| {
+                    async move {
+                        let partition_range_recv =
+                            load_partition_range(tx, &root.partition_range_recv, false).await?;
+                        let partition_range_send =
+                            load_partition_range(tx, &root.partition_range_send, false).await?;
+                        if partition_range_send != to {
+                            return Err(Error(anyhow!("Partition change operation was aborted")));
+                        }
+
+                        // Allow clients to begin processing from the new partitions
+                        if total == 0 && partition_range_recv == from {
+                            save_value(tx, &root.partition_range_recv, &to);
+                            mark_clients_modified(tx, root);
                         }
+                        Ok::<_, Error>(())
                     }
+                    .boxed()
+                },
+                TransactOption::idempotent(),
+            )
+            .await?;
 
-                    Ok::<_, Error>(true)
-                }
-                .boxed()
-            },
-            TransactOption::idempotent(),
-        )
-        .await?
-    {
-        tokio::time::sleep(Duration::from_secs(5)).await;
+        // Extrapolate from the rate at which messages have drained since we started
+        let now = Timestamp::now();
+        let estimated_completion = match self.initial {
+            _ if total == 0 => Some(now),
+            Some((initial_ts, initial_total)) if total < initial_total => {
+                let elapsed = now - initial_ts;
+                let ratio = total as f64 / (initial_total - total) as f64;
+                Some(now + elapsed.mul_f64(ratio))
+            }
+            Some(_) => None,
+            None => {
+                self.initial = Some((now, total));
+                None
+            }
+        };
+
+        Ok(RepartitionProgress {
+            partition_range_from: convert_range(self.from),
+            partition_range_to: convert_range(self.to),
+            remaining_messages,
+            estimated_completion,
+        })
+    }
+    async fn wait(&mut self, global: &Global, root: &RootSpace) -> Result<(), Error> {
+        while !self.poll(global, root).await?.is_complete() {
+            tokio::time::sleep(REPARTITION_POLL_INTERVAL).await;
+        }
+        Ok(())
     }
-    Ok(())
 }
 
-/// Change the number of partitions within a root. If this operation is interrupted,
-/// it should be retried with the same arguments, or else it will return an error.
-pub async fn change_partitions(
+// Begins a partition change operation, returning the range of partitions which messages
+// must leave before it can complete, or `None` if the root already has the desired range.
+async fn begin_change_partitions(
     global: &Global,
-    root: &str,
-    desired_partition_range: Range<u32>,
-) -> Result<(), Error> {
-    let root = global.root(root).await?;
-    let desired_partition_range = PartitionRange {
-        offset: desired_partition_range.start,
-        count: desired_partition_range.end - desired_partition_range.start,
-    };
-    if let Some(old_partition_range) = global
+    root: &RootSpace,
+    desired_partition_range: PartitionRange,
+) -> Result<Option<PartitionRange>, Error> {
+    global
         .db()
         .transact_boxed(
-            (global, &root),
+            (global, root),
             |tx, &mut (global, root)| {
                 async move {
                     let partition_range_recv =
@@ -427,9 +665,7 @@ pub async fn change_partitions(
                         save_value(tx, &root.partition_range_send, &desired_partition_range);
 
                         // Wake up all the old partitions
-                        for partition_idx in partition_range_recv.offset
-                            ..(partition_range_recv.offset + partition_range_recv.count)
-                        {
+                        for partition_idx in convert_range(partition_range_recv) {
                             let partition = root.partition(global, partition_idx).await?;
                             mark_partition_modified(tx, &partition);
                         }
@@ -448,32 +684,133 @@ pub async fn change_partitions(
             },
             TransactOption::idempotent(),
         )
-        .await?
+        .await
+}
+
+fn convert_to_partition_range(range: Range<u32>) -> PartitionRange {
+    PartitionRange {
+        offset: range.start,
+        count: range.end - range.start,
+    }
+}
+
+/// Change the number of partitions within a root. If this operation is interrupted,
+/// it should be retried with the same arguments, or else it will return an error.
+/// Returns an error if the operation is aborted by [abort_change_partitions].
+pub async fn change_partitions(
+    global: &Global,
+    root: &str,
+    desired_partition_range: Range<u32>,
+) -> Result<(), Error> {
+    let root = global.root(root).await?;
+    let desired_partition_range = convert_to_partition_range(desired_partition_range);
+    if let Some(old_partition_range) =
+        begin_change_partitions(global, &root, desired_partition_range).await?
     {
         // Wait for all messages to be migrated away from the old partitions
-        wait_for_empty_partitions(global, &root, old_partition_range).await?;
+        RepartitionTracker::new(old_partition_range, desired_partition_range)
+            .wait(global, &root)
+            .await?;
+    }
+    Ok(())
+}
 
-        // Allow clients to begin processing from the new partitions
-        global
-            .db()
-            .transact_boxed(
-                &root,
-                |tx, &mut root| {
-                    async move {
-                        let partition_range_recv =
-                            load_partition_range(tx, &root.partition_range_recv, false).await?;
-                        if partition_range_recv == old_partition_range {
-                            save_value(tx, &root.partition_range_recv, &desired_partition_range);
-                        }
-                        Ok::<_, Error>(())
+/// Change the number of partitions within a root, as with [change_partitions], but
+/// report progress as the operation runs. The stream ends once the operation has
+/// completed, and is empty if the root already has the desired partitions.
+pub fn change_partitions_with_progress(
+    global: Arc<Global>,
+    root: &str,
+    desired_partition_range: Range<u32>,
+) -> impl Stream<Item = Result<RepartitionProgress, Error>> + 'static {
+    let root = root.to_owned();
+    let desired_partition_range = convert_to_partition_range(desired_partition_range);
+    async move {
+        let root = global.root(&root).await?;
+        let maybe_tracker = begin_change_partitions(&global, &root, desired_partition_range)
+            .await?
+            .map(|old_partition_range| {
+                RepartitionTracker::new(old_partition_range, desired_partition_range)
+            });
+        Ok::<_, Error>(stream::try_unfold(
+            maybe_tracker.map(|tracker| (tracker, true)),
+            move |state| {
+                let global = global.clone();
+                let root = root.clone();
+                async move {
+                    let (mut tracker, first) = if let Some(state) = state {
+                        state
+                    } else {
+                        return Ok(None);
+                    };
+                    if !first {
+                        tokio::time::sleep(REPARTITION_POLL_INTERVAL).await;
                     }
-                    .boxed()
-                },
-                TransactOption::idempotent(),
-            )
+                    let progress = tracker.poll(&global, &root).await?;
+                    let state = if progress.is_complete() {
+                        None
+                    } else {
+                        Some((tracker, false))
+                    };
+                    Ok::<_, Error>(Some((progress, state)))
+                }
+            },
+        ))
+    }
+    .try_flatten_stream()
+}
+
+/// Abort a partition change operation which is in progress, returning the root to
+/// its previous range of partitions. Any messages which were already moved to the
+/// new partitions are moved back, and this function waits for that to complete.
+/// Returns `false` if there was no partition change operation in progress. Aborting
+/// an operation which is itself being aborted resumes the original operation.
+///
+/// While the messages are moved back, clients only process the new partitions, so
+/// no messages are delivered from the old partitions until the abort completes.
+/// This includes messages sent while the abort is in progress.
+pub async fn abort_change_partitions(global: &Global, root: &str) -> Result<bool, Error> {
+    let root = global.root(root).await?;
+    if let Some((from, to)) = global
+        .db()
+        .transact_boxed(
+            (global, &root),
+            |tx, &mut (global, root)| {
+                async move {
+                    let partition_range_recv =
+                        load_partition_range(tx, &root.partition_range_recv, false).await?;
+                    let partition_range_send =
+                        load_partition_range(tx, &root.partition_range_send, false).await?;
+                    if partition_range_recv == partition_range_send {
+                        return Ok(None);
+                    }
+
+                    // Swap the ranges, so that clients process the new partitions in order
+                    // to move their messages back to the old partitions
+                    save_value(tx, &root.partition_range_recv, &partition_range_send);
+                    save_value(tx, &root.partition_range_send, &partition_range_recv);
+                    for partition_idx in convert_range(partition_range_send) {
+                        let partition = root.partition(global, partition_idx).await?;
+                        mark_partition_modified(tx, &partition);
+                    }
+                    mark_clients_modified(tx, root);
+
+                    Ok::<_, Error>(Some((partition_range_send, partition_range_recv)))
+                }
+                .boxed()
+            },
+            TransactOption::idempotent(),
+        )
+        .await?
+    {
+        // Wait for all messages to be moved back to the old partitions
+        RepartitionTracker::new(from, to)
+            .wait(global, &root)
             .await?;
+        Ok(true)
+    } else {
+        Ok(false)
     }
-    Ok(())
 }
 
 /// List the agents within a given root starting from the provided ID.
@@ -512,3 +849,1152 @@ pub async fn list_agents(
         )
         .await
 }
+
+async fn load_policy<T: DeserializeOwned + Default + Send + 'static>(
+    global: &Global,
+    key: &[u8],
+) -> Result<T, Error> {
+    global
+        .db()
+        .transact_boxed(
+            key,
+            |tx, &mut key| {
+                async move {
+                    Ok::<_, Error>(load_value::<T>(tx, key, false).await?.unwrap_or_default())
+                }
+                .boxed()
+            },
+            TransactOption::idempotent(),
+        )
+        .await
+}
+
+async fn save_policy<T: Serialize + Sync>(
+    global: &Global,
+    key: &[u8],
+    policy: &T,
+) -> Result<(), Error> {
+    global
+        .db()
+        .transact_boxed(
+            (key, policy),
+            |tx, &mut (key, policy)| {
+                async move {
+                    save_value(tx, key, policy);
+                    Ok::<_, Error>(())
+                }
+                .boxed()
+            },
+            TransactOption::idempotent(),
+        )
+        .await
+}
+
+/// Obtain the dead-letter policy for a given root.
+pub async fn get_dead_letter_policy(
+    global: &Global,
+    root: &str,
+) -> Result<DeadLetterPolicy, Error> {
+    let root = global.root(root).await?;
+    load_policy(global, &root.dead_letter_policy).await
+}
+
+/// Change the dead-letter policy for a given root. The new policy will be
+/// used the next time the state function fails for an agent in this root.
+pub async fn set_dead_letter_policy(
+    global: &Global,
+    root: &str,
+    policy: DeadLetterPolicy,
+) -> Result<(), Error> {
+    let root = global.root(root).await?;
+    save_policy(global, &root.dead_letter_policy, &policy).await
+}
+
+/// Obtain the operation budget policy for a given root.
+pub async fn get_budget_policy(global: &Global, root: &str) -> Result<BudgetPolicy, Error> {
+    let root = global.root(root).await?;
+    load_policy(global, &root.budget_policy).await
+}
+
+/// Change the operation budget policy for a given root. The new policy applies
+/// to all messages sent to agents within this root from now on.
+pub async fn set_budget_policy(
+    global: &Global,
+    root: &str,
+    policy: BudgetPolicy,
+) -> Result<(), Error> {
+    let root = global.root(root).await?;
+    save_policy(global, &root.budget_policy, &policy).await
+}
+
+/// Obtain the partition assignment policy for a given root.
+pub async fn get_assignment_policy(global: &Global, root: &str) -> Result<AssignmentPolicy, Error> {
+    let root = global.root(root).await?;
+    load_policy(global, &root.assignment_policy).await
+}
+
+/// Change the partition assignment policy for a given root. Connected clients
+/// will re-balance their partitions immediately. All clients should be
+/// running a version which supports the new policy before it is changed.
+/// This replaces any existing pinned partitions.
+pub async fn set_assignment_policy(
+    global: &Global,
+    root: &str,
+    policy: AssignmentPolicy,
+) -> Result<(), Error> {
+    let root = global.root(root).await?;
+    global
+        .db()
+        .transact_boxed(
+            (&root, &policy),
+            |tx, &mut (root, policy)| {
+                async move {
+                    save_value(tx, &root.assignment_policy, policy);
+                    mark_clients_modified(tx, root);
+                    Ok::<_, Error>(())
+                }
+                .boxed()
+            },
+            TransactOption::idempotent(),
+        )
+        .await
+}
+
+async fn update_assignment_policy(
+    global: &Global,
+    root: &str,
+    f: impl Fn(&mut AssignmentPolicy) + Send + Sync,
+) -> Result<(), Error> {
+    let root = global.root(root).await?;
+    global
+        .db()
+        .transact_boxed(
+            (&root, &f),
+            |tx, &mut (root, f)| {
+                async move {
+                    let mut policy =
+                        load_value::<AssignmentPolicy>(tx, &root.assignment_policy, false)
+                            .await?
+                            .unwrap_or_default();
+                    f(&mut policy);
+                    save_value(tx, &root.assignment_policy, &policy);
+                    mark_clients_modified(tx, root);
+                    Ok::<_, Error>(())
+                }
+                .boxed()
+            },
+            TransactOption::idempotent(),
+        )
+        .await
+}
+
+/// Pin a partition to the client with the given name. While a client with
+/// that name is connected, it will own the partition regardless of the
+/// assignment strategy.
+pub async fn pin_partition(
+    global: &Global,
+    root: &str,
+    partition: u32,
+    client_name: &str,
+) -> Result<(), Error> {
+    update_assignment_policy(global, root, |policy| {
+        policy.pinned.insert(partition, client_name.into());
+    })
+    .await
+}
+
+/// Remove any pin from a partition, so that it is assigned according to the
+/// assignment strategy.
+pub async fn unpin_partition(global: &Global, root: &str, partition: u32) -> Result<(), Error> {
+    update_assignment_policy(global, root, |policy| {
+        policy.pinned.remove(&partition);
+    })
+    .await
+}
+
+async fn change_pending_message(
+    global: &Global,
+    root: &str,
+    message_id: Uuid,
+    when: Option<Timestamp>,
+) -> Result<bool, Error> {
+    let root = global.root(root).await?;
+    global
+        .db()
+        .transact_boxed(
+            (global, &root),
+            |tx, &mut (global, root)| {
+                change_scheduled_message(tx, global, root, message_id, when, 0, 0).boxed()
+            },
+            TransactOption::idempotent(),
+        )
+        .await
+}
+
+/// Cancel a pending message which is scheduled for later delivery. Returns `false`
+/// if the message was not found, or is no longer pending.
+pub async fn cancel_pending_message(
+    global: &Global,
+    root: &str,
+    message_id: Uuid,
+) -> Result<bool, Error> {
+    change_pending_message(global, root, message_id, None).await
+}
+
+/// Change when a pending message which is scheduled for later delivery will be
+/// delivered. Returns `false` if the message was not found, or is no longer pending.
+pub async fn reschedule_pending_message(
+    global: &Global,
+    root: &str,
+    message_id: Uuid,
+    when: Timestamp,
+) -> Result<bool, Error> {
+    change_pending_message(global, root, message_id, Some(when)).await
+}
+
+/// Information about an agent whose state function returned an error, and which
+/// is backing off or being retried.
+#[derive(Debug, Clone)]
+pub struct RetryingAgentDesc {
+    agent_id: Uuid,
+    partition: u32,
+    retry_at: Timestamp,
+    backoff: Duration,
+    attempts: u32,
+    first_attempt_ts: Timestamp,
+    last_error: Option<String>,
+}
+
+impl RetryingAgentDesc {
+    /// The ID of the agent.
+    pub fn agent_id(&self) -> Uuid {
+        self.agent_id
+    }
+    /// The partition containing the agent's pending messages.
+    pub fn partition(&self) -> u32 {
+        self.partition
+    }
+    /// The earliest time at which delivery will be attempted again.
+    pub fn retry_at(&self) -> Timestamp {
+        self.retry_at
+    }
+    /// The delay which will be added before the attempt after next. This doubles
+    /// with each failed attempt.
+    pub fn backoff(&self) -> Duration {
+        self.backoff
+    }
+    /// The number of attempts made so far.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+    /// The time of the first failed attempt.
+    pub fn first_attempt_ts(&self) -> Timestamp {
+        self.first_attempt_ts
+    }
+    /// The error returned by the state function on the most recent failed attempt,
+    /// or `None` if another attempt is in progress.
+    pub fn last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+}
+
+/// List up to `limit` agents within a given root whose state function has
+/// returned an error, and which have not yet been delivered to successfully,
+/// ordered by partition and then by agent ID. The list starts after the provided
+/// partition and agent ID, or from the beginning if `None`, so a caller can
+/// continue from the last agent returned.
+pub async fn list_retrying_agents(
+    global: &Global,
+    root: &str,
+    after: Option<(u32, Uuid)>,
+    limit: usize,
+) -> Result<Vec<RetryingAgentDesc>, Error> {
+    let root = global.root(root).await?;
+    let partition_idxs = global
+        .db()
+        .transact_boxed(
+            &root,
+            |tx, &mut root| {
+                async move {
+                    let partition_range_send =
+                        load_partition_range(tx, &root.partition_range_send, true).await?;
+                    let partition_range_recv =
+                        load_partition_range(tx, &root.partition_range_recv, true).await?;
+                    let mut partition_idxs: Vec<_> = convert_range(partition_range_recv)
+                        .chain(convert_range(partition_range_send))
+                        .collect();
+                    partition_idxs.sort_unstable();
+                    partition_idxs.dedup();
+                    Ok::<_, Error>(partition_idxs)
+                }
+                .boxed()
+            },
+            TransactOption::idempotent(),
+        )
+        .await?;
+
+    // Read each partition in chunks, so that a root with many agents retrying is
+    // split across several transactions
+    let mut result = Vec::new();
+    for partition_idx in partition_idxs {
+        let mut cursor = match after {
+            Some((after_partition, _)) if partition_idx < after_partition => continue,
+            Some((after_partition, agent_id)) if partition_idx == after_partition => Some(agent_id),
+            _ => None,
+        };
+        let partition = root.partition(global, partition_idx).await?;
+        while result.len() < limit {
+            let chunk_limit = (limit - result.len()).min(DESC_LIMIT);
+            let (states, done) = global
+                .db()
+                .transact_boxed(
+                    (&partition, cursor, chunk_limit),
+                    |tx, &mut (partition, cursor, chunk_limit)| {
+                        async move {
+                            let mut range: RangeOption = partition.agent_retry.range().into();
+                            if let Some(agent_id) = cursor {
+                                range.begin = KeySelector::first_greater_than(
+                                    partition.agent_retry.pack(&agent_id),
+                                );
+                            }
+                            range.limit = Some(chunk_limit);
+                            range.mode = StreamingMode::WantAll;
+                            let values = tx.get_range(&range, 0, true).await?;
+                            let states = values
+                                .iter()
+                                .map(|value| {
+                                    Ok((
+                                        partition.agent_retry.unpack(value.key())?,
+                                        postcard::from_bytes::<RetryAtState>(value.value()).ok(),
+                                    ))
+                                })
+                                .collect::<Result<Vec<_>, Error>>()?;
+                            Ok::<_, Error>((states, values.len() < chunk_limit))
+                        }
+                        .boxed()
+                    },
+                    TransactOption::idempotent(),
+                )
+                .await?;
+
+            for (agent_id, state) in states {
+                cursor = Some(agent_id);
+
+                // Agents are tracked from the start of their first attempt, so skip
+                // those which are on their first attempt and haven't failed yet
+                let state = match state {
+                    Some(state) if state.attempts > 1 || state.last_error.is_some() => state,
+                    _ => continue,
+                };
+                result.push(RetryingAgentDesc {
+                    agent_id,
+                    partition: partition_idx,
+                    retry_at: state.retry_at,
+                    backoff: state.backoff,
+                    attempts: state.attempts,
+                    first_attempt_ts: state.first_attempt_ts,
+                    last_error: state.last_error,
+                });
+            }
+            if done {
+                break;
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Reset the backoff of an agent whose state function returned an error, so that
+/// delivery is attempted again immediately. The attempt count is also reset, so
+/// the agent gets a fresh start with respect to the dead-letter policy. Returns
+/// `false` if the agent has not failed, or if its next attempt is already in
+/// progress, in which case its state is left alone.
+pub async fn reset_agent_retry(global: &Global, root: &str, agent_id: Uuid) -> Result<bool, Error> {
+    let root = global.root(root).await?;
+    global
+        .db()
+        .transact_boxed(
+            (global, &root),
+            |tx, &mut (global, root)| {
+                async move {
+                    // The agent's messages may be in either partition during a re-partition
+                    let mut found = false;
+                    for partition_range in [&root.partition_range_recv, &root.partition_range_send]
+                    {
+                        let partition_range =
+                            load_partition_range(tx, partition_range, false).await?;
+                        let partition_idx = partition_for_recipient(agent_id, partition_range);
+                        let partition = root.partition(global, partition_idx).await?;
+                        let retry_at_key = partition.agent_retry.pack(&agent_id);
+
+                        // The last error is cleared whenever an attempt starts, so its
+                        // presence means the agent is backing off rather than in flight
+                        let backing_off = load_value::<RetryAtState>(tx, &retry_at_key, false)
+                            .await?
+                            .map_or(false, |state| state.last_error.is_some());
+                        if backing_off {
+                            tx.clear(&retry_at_key);
+                            mark_partition_modified(tx, &partition);
+                            found = true;
+                        }
+                    }
+                    Ok::<_, Error>(found)
+                }
+                .boxed()
+            },
+            TransactOption::idempotent(),
+        )
+        .await
+}
+
+/// Information about a message in the dead-letter queue.
+#[derive(Debug, Clone)]
+pub struct DeadLetterDesc {
+    message_id: Uuid,
+    recipient_id: Uuid,
+    operation_id: Uuid,
+    error: String,
+    attempts: u32,
+    first_attempt_ts: Timestamp,
+    dead_lettered_ts: Timestamp,
+}
+
+impl DeadLetterDesc {
+    fn new(value: DeadLetterValue) -> Self {
+        Self {
+            message_id: value.header.message_id,
+            recipient_id: value.header.recipient_id,
+            operation_id: value.header.operation_id,
+            error: value.failure.error,
+            attempts: value.failure.attempts,
+            first_attempt_ts: value.failure.first_attempt_ts,
+            dead_lettered_ts: value.failure.dead_lettered_ts,
+        }
+    }
+    /// The ID of the message.
+    pub fn message_id(&self) -> Uuid {
+        self.message_id
+    }
+    /// The ID of the agent which failed to receive the message.
+    pub fn recipient_id(&self) -> Uuid {
+        self.recipient_id
+    }
+    /// The ID of the operation this message belongs to.
+    pub fn operation_id(&self) -> Uuid {
+        self.operation_id
+    }
+    /// The error returned by the state function on the final attempt.
+    pub fn error(&self) -> &str {
+        &self.error
+    }
+    /// The number of attempts made to deliver the message.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+    /// The time of the first failed attempt to deliver the message.
+    pub fn first_attempt_ts(&self) -> Timestamp {
+        self.first_attempt_ts
+    }
+    /// The time when the message was moved to the dead-letter queue.
+    pub fn dead_lettered_ts(&self) -> Timestamp {
+        self.dead_lettered_ts
+    }
+}
+
+/// List the messages in the dead-letter queue of a given root, starting from the
+/// provided message ID.
+pub async fn list_dead_letters(
+    global: &Global,
+    root: &str,
+    from: Uuid,
+    limit: usize,
+    reverse: bool,
+) -> Result<Vec<DeadLetterDesc>, Error> {
+    let root = global.root(root).await?;
+    global
+        .db()
+        .transact_boxed(
+            &root,
+            |tx, &mut root| {
+                async move {
+                    let mut range: RangeOption = if reverse {
+                        root.dead_letter.subrange(..=from)
+                    } else {
+                        root.dead_letter.subrange(from..)
+                    }
+                    .into();
+                    range.limit = Some(limit);
+                    range.mode = StreamingMode::WantAll;
+                    range.reverse = reverse;
+                    let values = tx.get_range(&range, 0, true).await?;
+                    Ok(values
+                        .into_iter()
+                        .flat_map(|value| postcard::from_bytes::<DeadLetterValue>(value.value()))
+                        .map(DeadLetterDesc::new)
+                        .collect())
+                }
+                .boxed()
+            },
+            TransactOption::idempotent(),
+        )
+        .await
+}
+
+/// Inspect a single message in the dead-letter queue, returning both its description
+/// and its contents. Returns `None` if the message is not in the dead-letter queue.
+pub async fn inspect_dead_letter(
+    global: &Global,
+    root: &str,
+    message_id: Uuid,
+) -> Result<Option<(DeadLetterDesc, Vec<u8>)>, Error> {
+    let root = global.root(root).await?;
+    global
+        .db()
+        .transact_boxed(
+            (global, &root),
+            |tx, &mut (global, root)| {
+                async move {
+                    let dead_letter_key = root.dead_letter.pack(&message_id);
+                    let dead_letter = if let Some(dead_letter) =
+                        load_value::<DeadLetterValue>(tx, &dead_letter_key, true).await?
+                    {
+                        dead_letter
+                    } else {
+                        return Ok(None);
+                    };
+                    let blob_id = dead_letter.header.blob_id;
+                    let content = blob::load_internal(tx, global, root, blob_id, true)
+                        .await?
+                        .ok_or_else(|| Error(anyhow!("Blob not found: {}", blob_id)))?;
+                    Ok::<_, Error>(Some((DeadLetterDesc::new(dead_letter), content)))
+                }
+                .boxed()
+            },
+            TransactOption::idempotent(),
+        )
+        .await
+}
+
+/// Move a message from the dead-letter queue back into the inbox of its original
+/// recipient, where it will be delivered immediately. Any expiry deadline is
+/// removed from the message, so it is delivered even if it expired while in the
+/// dead-letter queue. Returns `false` if the message was not in the dead-letter
+/// queue.
+pub async fn replay_dead_letter(
+    global: &Global,
+    root: &str,
+    message_id: Uuid,
+) -> Result<bool, Error> {
+    let root = global.root(root).await?;
+    global
+        .db()
+        .transact_boxed(
+            (global, &root),
+            |tx, &mut (global, root)| {
+                async move {
+                    let dead_letter_key = root.dead_letter.pack(&message_id);
+                    let mut header = if let Some(dead_letter) =
+                        load_value::<DeadLetterValue>(tx, &dead_letter_key, false).await?
+                    {
+                        dead_letter.header
+                    } else {
+                        return Ok(false);
+                    };
+
+                    // The message may have expired while it was in the dead-letter queue,
+                    // but replaying it is an explicit request to deliver it
+                    header.expires_at = None;
+
+                    let partition_range =
+                        load_partition_range(tx, &root.partition_range_send, false).await?;
+                    let partition_idx =
+                        partition_for_recipient(header.recipient_id, partition_range);
+                    let partition = root.partition(global, partition_idx).await?;
+                    let key = partition.message.pack(&(
+                        header.priority.lane(),
+                        Timestamp::zero(),
+                        Versionstamp::incomplete(0),
+                        0,
+                    ));
+                    tx.atomic_op(&key, &header.encode(), MutationType::SetVersionstampedKey);
+                    mark_partition_modified(tx, &partition);
+                    tx.clear(&dead_letter_key);
+
+                    Ok::<_, Error>(true)
+                }
+                .boxed()
+            },
+            TransactOption::idempotent(),
+        )
+        .await
+}
+
+/// Permanently delete a message from the dead-letter queue. Returns `false` if
+/// the message was not in the dead-letter queue.
+pub async fn discard_dead_letter(
+    global: &Global,
+    root: &str,
+    message_id: Uuid,
+) -> Result<bool, Error> {
+    let root = global.root(root).await?;
+    global
+        .db()
+        .transact_boxed(
+            &root,
+            |tx, &mut root| {
+                async move {
+                    let dead_letter_key = root.dead_letter.pack(&message_id);
+                    let dead_letter = if let Some(dead_letter) =
+                        load_value::<DeadLetterValue>(tx, &dead_letter_key, false).await?
+                    {
+                        dead_letter
+                    } else {
+                        return Ok(false);
+                    };
+                    release_message_content(tx, root, &dead_letter.header).await?;
+                    tx.clear(&dead_letter_key);
+                    Ok::<_, Error>(true)
+                }
+                .boxed()
+            },
+            TransactOption::idempotent(),
+        )
+        .await
+}
+
+/// Obtain the causality trace policy for a given root.
+pub async fn get_trace_policy(global: &Global, root: &str) -> Result<TracePolicy, Error> {
+    let root = global.root(root).await?;
+    load_policy(global, &root.trace_policy).await
+}
+
+/// Change the causality trace policy for a given root. Only messages sent to
+/// agents within this root after tracing is enabled will be traced.
+pub async fn set_trace_policy(
+    global: &Global,
+    root: &str,
+    policy: TracePolicy,
+) -> Result<(), Error> {
+    let root = global.root(root).await?;
+    save_policy(global, &root.trace_policy, &policy).await
+}
+
+/// Information about the delivery of a traced message.
+#[derive(Debug, Clone)]
+pub struct TracedMessageDesc {
+    message_id: Uuid,
+    sender_id: Option<Uuid>,
+    recipient_id: Uuid,
+    message_type: Option<String>,
+    caused_by: Vec<Uuid>,
+    sent_ts: Timestamp,
+    delivered_ts: Timestamp,
+}
+
+impl TracedMessageDesc {
+    fn new(entry: TraceEntry) -> Self {
+        Self {
+            message_id: entry.message_id,
+            sender_id: entry.info.sender_id,
+            recipient_id: entry.recipient_id,
+            message_type: entry.info.message_type,
+            caused_by: entry.info.caused_by,
+            sent_ts: entry.info.sent_ts,
+            delivered_ts: entry.delivered_ts,
+        }
+    }
+    /// The ID of the message.
+    pub fn message_id(&self) -> Uuid {
+        self.message_id
+    }
+    /// The ID of the agent which sent the message, or `None` if the message
+    /// was sent from outside the AgentDB system.
+    pub fn sender_id(&self) -> Option<Uuid> {
+        self.sender_id
+    }
+    /// The ID of the agent which received the message.
+    pub fn recipient_id(&self) -> Uuid {
+        self.recipient_id
+    }
+    /// The type of the message, if known.
+    pub fn message_type(&self) -> Option<&str> {
+        self.message_type.as_deref()
+    }
+    /// The IDs of the messages in this operation which were being processed
+    /// by the sender when this message was sent.
+    pub fn caused_by(&self) -> &[Uuid] {
+        &self.caused_by
+    }
+    /// The time when the message was sent.
+    pub fn sent_ts(&self) -> Timestamp {
+        self.sent_ts
+    }
+    /// The time when the message was delivered.
+    pub fn delivered_ts(&self) -> Timestamp {
+        self.delivered_ts
+    }
+}
+
+/// Obtain the causality trace for an operation, in the order that messages were
+/// delivered. Messages are only traced if tracing was enabled for the recipient's
+/// root when they were sent.
+pub async fn trace_operation(
+    global: &Global,
+    root: &str,
+    operation_id: Uuid,
+) -> Result<Vec<TracedMessageDesc>, Error> {
+    let root = global.root(root).await?;
+    global
+        .db()
+        .transact_boxed(
+            &root,
+            |tx, &mut root| {
+                async move {
+                    let mut range: RangeOption = root.trace.nested_range(&(operation_id,)).into();
+                    range.mode = StreamingMode::WantAll;
+                    let mut stream = tx.get_ranges(range, true);
+                    let mut result = Vec::new();
+                    while let Some(values) = stream.try_next().await? {
+                        for value in values {
+                            let entry: TraceEntry = postcard::from_bytes(value.value())?;
+                            result.push(TracedMessageDesc::new(entry));
+                        }
+                    }
+                    Ok::<_, Error>(result)
+                }
+                .boxed()
+            },
+            TransactOption::idempotent(),
+        )
+        .await
+}
+
+/// Obtain the state history policy for a given root.
+pub async fn get_history_policy(global: &Global, root: &str) -> Result<HistoryPolicy, Error> {
+    let root = global.root(root).await?;
+    load_policy(global, &root.history_policy).await
+}
+
+/// Change the state history policy for a given root. The new policy applies the
+/// next time the state of each agent is written. Disabling history does not remove
+/// versions which have already been retained: use [clear_agent_history] for that.
+pub async fn set_history_policy(
+    global: &Global,
+    root: &str,
+    policy: HistoryPolicy,
+) -> Result<(), Error> {
+    let root = global.root(root).await?;
+    save_policy(global, &root.history_policy, &policy).await
+}
+
+/// List the retained versions of an agent's state, from oldest to newest.
+pub async fn list_agent_history(
+    global: &Global,
+    root: &str,
+    agent_id: Uuid,
+) -> Result<Vec<StateVersion>, Error> {
+    let root = global.root(root).await?;
+    global
+        .db()
+        .transact_boxed(
+            &root,
+            |tx, &mut root| history::list_internal(tx, root, agent_id, true).boxed(),
+            TransactOption::idempotent(),
+        )
+        .await
+}
+
+/// Load a previous version of an agent's state. Returns `None` if the version
+/// is not retained, or if the agent did not exist in that version.
+pub async fn load_agent_state_at(
+    global: &Global,
+    root: &str,
+    agent_id: Uuid,
+    version: Versionstamp,
+) -> Result<Option<Vec<u8>>, Error> {
+    let root = global.root(root).await?;
+    global
+        .db()
+        .transact_boxed(
+            (global, &root),
+            |tx, &mut (global, root)| {
+                history::load_at_internal(tx, global, root, agent_id, version, true).boxed()
+            },
+            TransactOption::idempotent(),
+        )
+        .await
+}
+
+/// Remove every retained version of an agent's state.
+pub async fn clear_agent_history(global: &Global, root: &str, agent_id: Uuid) -> Result<(), Error> {
+    let root = global.root(root).await?;
+    global
+        .db()
+        .transact_boxed(
+            &root,
+            |tx, &mut root| {
+                async move {
+                    let (begin, end) = root.history_index.nested_range(&(agent_id,));
+                    tx.clear_range(&begin, &end);
+                    let (begin, end) = root.history.nested_range(&(agent_id,));
+                    tx.clear_range(&begin, &end);
+                    Ok::<_, Error>(())
+                }
+                .boxed()
+            },
+            TransactOption::idempotent(),
+        )
+        .await
+}
+
+/// Obtain the idempotency policy for a given root.
+pub async fn get_idempotency_policy(
+    global: &Global,
+    root: &str,
+) -> Result<IdempotencyPolicy, Error> {
+    let root = global.root(root).await?;
+    load_policy(global, &root.idempotency_policy).await
+}
+
+/// Change the idempotency policy for a given root. The new retention period only
+/// applies to idempotency keys recorded after the change.
+pub async fn set_idempotency_policy(
+    global: &Global,
+    root: &str,
+    policy: IdempotencyPolicy,
+) -> Result<(), Error> {
+    let root = global.root(root).await?;
+    save_policy(global, &root.idempotency_policy, &policy).await
+}
+
+/// Obtain the change feed policy for a given root.
+pub async fn get_change_feed_policy(
+    global: &Global,
+    root: &str,
+) -> Result<ChangeFeedPolicy, Error> {
+    let root = global.root(root).await?;
+    load_policy(global, &root.change_feed_policy).await
+}
+
+/// Change the change feed policy for a given root. Changes to agent state are
+/// only recorded while the change feed is enabled.
+pub async fn set_change_feed_policy(
+    global: &Global,
+    root: &str,
+    policy: ChangeFeedPolicy,
+) -> Result<(), Error> {
+    let root = global.root(root).await?;
+    save_policy(global, &root.change_feed_policy, &policy).await
+}
+
+/// Obtain the blob compression policy for a given root.
+pub async fn get_compression_policy(
+    global: &Global,
+    root: &str,
+) -> Result<CompressionPolicy, Error> {
+    let root = global.root(root).await?;
+    load_policy(global, &root.compression_policy).await
+}
+
+/// Change the blob compression policy for a given root. The new policy applies
+/// to blobs stored from now on: existing blobs are not re-compressed. All clients
+/// should be running a version which supports compression before it is enabled.
+pub async fn set_compression_policy(
+    global: &Global,
+    root: &str,
+    policy: CompressionPolicy,
+) -> Result<(), Error> {
+    let root = global.root(root).await?;
+    save_policy(global, &root.compression_policy, &policy).await
+}
+
+/// Re-encrypt every blob within a root which is not encrypted with the current key
+/// for that root, so that old keys can be retired. At most about 1MB of data is
+/// re-encrypted in each transaction, with large blobs being re-encrypted across
+/// several transactions, so this can be run while clients are connected. Blobs are
+/// not considered modified by re-encryption, so watchers are not woken up. Returns
+/// the number of blobs which were re-encrypted. All clients should be running a
+/// version which supports incremental re-encryption before this is run.
+pub async fn reencrypt_blobs(global: &Global, root: &str) -> Result<u64, Error> {
+    let root = global.root(root).await?;
+    let mut begin = root.blob_modified.range().0;
+    let mut total = 0;
+    loop {
+        let (count, next_begin) = global
+            .db()
+            .transact_boxed(
+                (global, &root, &begin),
+                |tx, &mut (global, root, begin)| {
+                    async move {
+                        // Examine at most as many blobs as could be re-encrypted if they
+                        // each had a single stripe
+                        let mut range: RangeOption =
+                            (begin.clone(), root.blob_modified.range().1).into();
+                        range.limit = Some(REENCRYPT_BYTES_PER_TRANSACTION / BLOB_STRIPE_SIZE);
+                        let values = tx.get_range(&range, 0, true).await?;
+
+                        let mut budget = REENCRYPT_BYTES_PER_TRANSACTION;
+                        let mut count = 0;
+                        let mut next_begin = None;
+                        for value in values.iter() {
+                            if budget == 0 {
+                                break;
+                            }
+                            let blob_id = root.blob_modified.unpack(value.key())?;
+                            match blob::reencrypt_internal(tx, global, root, blob_id, &mut budget)
+                                .await?
+                            {
+                                Reencrypted::Unchanged => {}
+                                Reencrypted::Complete => count += 1,
+                                Reencrypted::Partial => {
+                                    // Continue with the same blob in the next transaction
+                                    next_begin = Some(value.key().to_vec());
+                                    break;
+                                }
+                            }
+                            next_begin = Some(next_key(value.key()));
+                        }
+                        Ok::<_, Error>((count, next_begin))
+                    }
+                    .boxed()
+                },
+                TransactOption::idempotent(),
+            )
+            .await?;
+        total += count;
+        if let Some(next_begin) = next_begin {
+            begin = next_begin;
+        } else {
+            return Ok(total);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        dead_letter::{move_to_dead_letter, DeadLetterFailure},
+        test_utils::{commit, test_header, TestRoot},
+        DEFAULT_PARTITION_RANGE,
+    };
+
+    #[tokio::test]
+    #[ignore]
+    async fn replayed_dead_letter_no_longer_expires() -> Result<(), Error> {
+        let root = TestRoot::new().await;
+        let recipient_id = Uuid::new_v4();
+        let partition = root
+            .partition(partition_for_recipient(
+                recipient_id,
+                DEFAULT_PARTITION_RANGE,
+            ))
+            .await;
+
+        // Dead-letter a message which has since expired
+        let mut header = test_header(recipient_id);
+        header.expires_at = Some(Timestamp::now() - Duration::from_secs(60));
+        let message_id = header.message_id;
+        let lane = header.priority.lane();
+        let tx = root.tx();
+        let key_parts = (lane, recipient_id, Versionstamp::complete([0; 10], 0));
+        let batch_key = partition.batch.pack(&key_parts);
+        tx.set(&batch_key, &header.encode());
+        let failure = DeadLetterFailure {
+            error: "failed".into(),
+            attempts: 1,
+            first_attempt_ts: Timestamp::now(),
+            dead_lettered_ts: Timestamp::now(),
+        };
+        move_to_dead_letter(
+            &tx,
+            &root.space,
+            &partition,
+            lane,
+            recipient_id,
+            &batch_key,
+            &failure,
+        )
+        .await?;
+        commit(tx).await?;
+
+        assert!(replay_dead_letter(&root.global, &root.name, message_id).await?);
+
+        // The message is back in the partition without its deadline
+        let tx = root.tx();
+        let values = tx
+            .get_range(&partition.message.range().into(), 0, true)
+            .await?;
+        assert_eq!(values.len(), 1);
+        let replayed = MessageHeader::decode(values[0].value())?;
+        assert_eq!(replayed.message_id, message_id);
+        assert!(replayed.expires_at.is_none());
+        assert!(tx
+            .get(&root.space.dead_letter.pack(&message_id), true)
+            .await?
+            .is_none());
+
+        root.cleanup().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn only_failed_agents_are_listed_and_reset() -> Result<(), Error> {
+        let root = TestRoot::new().await;
+        let now = Timestamp::now();
+        let retry_state = |retry_at, attempts, last_error: Option<&str>| RetryAtState {
+            retry_at,
+            backoff: Duration::from_secs(60),
+            attempts,
+            first_attempt_ts: now,
+            last_error: last_error.map(Into::into),
+        };
+        let later = now + Duration::from_secs(60);
+        let states = [
+            // Backing off
+            retry_state(later, 2, Some("failed")),
+            // Due to retry now
+            retry_state(now - Duration::from_secs(60), 2, Some("failed")),
+            // Retry in progress
+            retry_state(later, 3, None),
+            // First attempt in progress
+            retry_state(later, 1, None),
+        ];
+        let mut agents = Vec::new();
+        let tx = root.tx();
+        for state in &states {
+            let agent_id = Uuid::new_v4();
+            let partition_idx = partition_for_recipient(agent_id, DEFAULT_PARTITION_RANGE);
+            let partition = root.partition(partition_idx).await;
+            save_value(&tx, &partition.agent_retry.pack(&agent_id), state);
+            agents.push((partition_idx, agent_id));
+        }
+        commit(tx).await?;
+        let mut failed = agents[..3].to_vec();
+        failed.sort_unstable();
+
+        let list = |after, limit| list_retrying_agents(&root.global, &root.name, after, limit);
+        let ids = |descs: &[RetryingAgentDesc]| {
+            descs
+                .iter()
+                .map(|desc| (desc.partition(), desc.agent_id()))
+                .collect::<Vec<_>>()
+        };
+        let listed = list(None, 10).await?;
+        assert_eq!(ids(&listed), failed);
+        assert_eq!(
+            listed
+                .iter()
+                .filter(|desc| desc.last_error().is_none())
+                .count(),
+            1
+        );
+
+        // Continue after the last agent listed
+        let first = list(None, 1).await?;
+        assert_eq!(ids(&first), failed[..1].to_vec());
+        let next = list(Some((first[0].partition(), first[0].agent_id())), 10).await?;
+        assert_eq!(ids(&next), failed[1..].to_vec());
+
+        // Only agents which are backing off are reset
+        let reset =
+            |(_, agent_id): (u32, Uuid)| reset_agent_retry(&root.global, &root.name, agent_id);
+        assert!(reset(agents[0]).await?);
+        assert!(!reset(agents[0]).await?);
+        assert!(!reset(agents[2]).await?);
+        assert!(!reset(agents[3]).await?);
+        let tx = root.tx();
+        for &(partition_idx, agent_id) in &agents[2..] {
+            let partition = root.partition(partition_idx).await;
+            assert!(tx
+                .get(&partition.agent_retry.pack(&agent_id), true)
+                .await?
+                .is_some());
+        }
+
+        root.cleanup().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn partition_change_reports_progress_until_complete() -> Result<(), Error> {
+        let root = TestRoot::new().await;
+        let old_range = convert_range(DEFAULT_PARTITION_RANGE);
+        let new_range = old_range.end..old_range.end + 10;
+
+        // With no messages to move, the change completes on the first poll
+        let progress: Vec<_> =
+            change_partitions_with_progress(root.global.clone(), &root.name, new_range.clone())
+                .try_collect()
+                .await?;
+        let last = progress.last().expect("No progress was reported");
+        assert!(last.is_complete());
+        assert_eq!(last.partition_range_from(), old_range);
+        assert_eq!(last.partition_range_to(), new_range);
+        assert_eq!(last.remaining_messages().len(), old_range.len());
+        assert!(last.estimated_completion().is_some());
+
+        let root_desc = describe_root(&root.global, &root.name).await?;
+        assert_eq!(root_desc.partition_range_recv(), new_range);
+        assert_eq!(root_desc.partition_range_send(), new_range);
+
+        // The root already has the desired partitions
+        let progress: Vec<_> =
+            change_partitions_with_progress(root.global.clone(), &root.name, new_range.clone())
+                .try_collect()
+                .await?;
+        assert!(progress.is_empty());
+
+        root.cleanup().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn aborted_partition_change_is_reverted() -> Result<(), Error> {
+        let root = TestRoot::new().await;
+        let old_range = convert_range(DEFAULT_PARTITION_RANGE);
+        let new_range = old_range.end..old_range.end + 10;
+
+        // Leave a message in an old partition so that the change cannot complete
+        let partition = root.partition(old_range.start).await;
+        let header = test_header(Uuid::new_v4());
+        let tx = root.tx();
+        tx.set(
+            &partition.message.pack(&(
+                header.priority.lane(),
+                Timestamp::zero(),
+                Versionstamp::complete([0; 10], 0),
+                0,
+            )),
+            &header.encode(),
+        );
+        commit(tx).await?;
+
+        let mut progress = Box::pin(change_partitions_with_progress(
+            root.global.clone(),
+            &root.name,
+            new_range,
+        ));
+        let first = progress
+            .try_next()
+            .await?
+            .expect("No progress was reported");
+        assert!(!first.is_complete());
+        assert_eq!(first.remaining_messages()[&old_range.start], 1);
+        assert_eq!(first.total_remaining_messages(), 1);
+        assert!(first.estimated_completion().is_none());
+        drop(progress);
+
+        assert!(abort_change_partitions(&root.global, &root.name).await?);
+        let root_desc = describe_root(&root.global, &root.name).await?;
+        assert_eq!(root_desc.partition_range_recv(), old_range);
+        assert_eq!(root_desc.partition_range_send(), old_range);
+        assert!(!abort_change_partitions(&root.global, &root.name).await?);
+
+        root.cleanup().await;
+        Ok(())
+    }
+}
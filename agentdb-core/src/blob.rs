@@ -1,27 +1,212 @@
 //! Contains functions for reading and writing "blobs", or binary large objects.
 //!
-//! Agent state and messages are all stored as blobs by AgentDB.
+//! Agent state and messages are all stored as blobs by AgentDB. Blobs may be
+//! transparently compressed according to the root's [crate::policy::CompressionPolicy],
+//! and encrypted if a [crate::encryption::KeyProvider] is installed.
+//!
+//! Blobs which are too large to read or write within a single transaction can be
+//! written using a [BlobWriter] and read using [read_stream]. Such blobs are
+//! reference counted: use [retain] and [release] when storing their IDs in agent
+//! state, or attach them to messages using [crate::OutboundMessage::attachment].
 
-use std::sync::Arc;
+use std::{borrow::Cow, sync::Arc};
 
+use anyhow::anyhow;
+use byteorder::{ByteOrder, LittleEndian};
 use foundationdb::{
-    options::{ConflictRangeType, MutationType},
-    TransactOption, Transaction,
+    options::{ConflictRangeType, MutationType, StreamingMode},
+    FdbError, RangeOption, TransactOption, Transaction,
 };
 use futures::{stream, Future, FutureExt, Stream, TryFutureExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use crate::{
     directories::{Global, RootSpace},
+    encryption::{decrypt_stripe, encrypt_stripe, Key, KeyId},
     error::Error,
-    utils::next_key,
+    policy::{CompressionAlgorithm, CompressionPolicy},
+    utils::{load_value, next_key},
 };
 
+mod stream;
+
+pub(crate) use stream::{gc_staging, STAGING_TIMEOUT};
+pub use stream::{read_stream, BlobWriter};
+
 // Store blobs in blocks of 16kb to avoid hitting value size limits (typically 100kb)
-const BLOB_STRIPE_SIZE: usize = 1024 * 16;
+pub(crate) const BLOB_STRIPE_SIZE: usize = 1024 * 16;
+
+// Compressed or encrypted blobs have an additional stripe at this index describing
+// their format. Blobs without this stripe are stored as-is.
+const BLOB_HEADER_INDEX: u32 = u32::MAX;
+
+#[derive(Serialize, Deserialize)]
+enum BlobEncoding {
+    Zstd,
+    Lz4,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct BlobHeader {
+    encoding: Option<BlobEncoding>,
+    key_id: Option<KeyId>,
+    // Set while a blob is being re-encrypted across several transactions.
+    rekey: Option<Rekey>,
+}
+
+// Stripes before `next_index` have already been re-encrypted with `key_id`.
+#[derive(Serialize, Deserialize, Copy, Clone)]
+struct Rekey {
+    key_id: Option<KeyId>,
+    next_index: u32,
+}
+
+// The header format used before blobs could be re-encrypted incrementally.
+#[derive(Deserialize)]
+struct LegacyBlobHeader {
+    encoding: Option<BlobEncoding>,
+    key_id: Option<KeyId>,
+}
+
+impl BlobHeader {
+    fn decode(bytes: &[u8]) -> Result<Self, Error> {
+        // Legacy headers are one field short, so never decode as the current format
+        postcard::from_bytes(bytes).or_else(|_| {
+            let legacy: LegacyBlobHeader = postcard::from_bytes(bytes)?;
+            Ok(Self {
+                encoding: legacy.encoding,
+                key_id: legacy.key_id,
+                rekey: None,
+            })
+        })
+    }
+}
+
+// The keys needed to decrypt every stripe of a blob.
+struct StripeKeys {
+    key: Option<Key>,
+    // Stripes before this index are encrypted with the second key instead.
+    rekey: Option<(u32, Option<Key>)>,
+}
+
+impl StripeKeys {
+    fn load(global: &Global, header: &BlobHeader) -> Result<Self, Error> {
+        let load =
+            |key_id: Option<KeyId>| key_id.map(|key_id| load_key(global, key_id)).transpose();
+        Ok(Self {
+            key: load(header.key_id)?,
+            rekey: header
+                .rekey
+                .map(|rekey| Ok::<_, Error>((rekey.next_index, load(rekey.key_id)?)))
+                .transpose()?,
+        })
+    }
+
+    fn decrypt<'a>(
+        &self,
+        blob_id: Uuid,
+        index: u32,
+        stripe: &'a [u8],
+    ) -> Result<Cow<'a, [u8]>, Error> {
+        let key = match &self.rekey {
+            Some((next_index, key)) if index < *next_index => key,
+            _ => &self.key,
+        };
+        Ok(match key {
+            Some(key) => Cow::Owned(decrypt_stripe(key, blob_id, index, stripe)?),
+            None => Cow::Borrowed(stripe),
+        })
+    }
+}
+
+// The outcome of re-encrypting part of a blob.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Reencrypted {
+    // The blob was already encrypted with the current key.
+    Unchanged,
+    // Some stripes were re-encrypted, but the byte budget ran out.
+    Partial,
+    // The last stripes of the blob were re-encrypted.
+    Complete,
+}
+
+fn compress<'a>(
+    data: &'a [u8],
+    policy: &CompressionPolicy,
+) -> (Option<BlobEncoding>, Cow<'a, [u8]>) {
+    if data.len() < policy.min_size {
+        return (None, Cow::Borrowed(data));
+    }
+    let (encoding, compressed) = match policy.algorithm {
+        CompressionAlgorithm::None => return (None, Cow::Borrowed(data)),
+        CompressionAlgorithm::Zstd { level } => (
+            BlobEncoding::Zstd,
+            zstd::encode_all(data, level).expect("Infallible compression"),
+        ),
+        CompressionAlgorithm::Lz4 => (BlobEncoding::Lz4, lz4_flex::compress_prepend_size(data)),
+    };
+    // Don't bother compressing blobs which don't get any smaller
+    if compressed.len() < data.len() {
+        (Some(encoding), Cow::Owned(compressed))
+    } else {
+        (None, Cow::Borrowed(data))
+    }
+}
+
+fn decompress(data: Vec<u8>, encoding: Option<BlobEncoding>) -> Result<Vec<u8>, Error> {
+    Ok(match encoding {
+        None => data,
+        Some(BlobEncoding::Zstd) => zstd::decode_all(&*data)?,
+        Some(BlobEncoding::Lz4) => lz4_flex::decompress_size_prepended(&data)?,
+    })
+}
+
+pub(crate) async fn load_compression_policy(
+    tx: &Transaction,
+    root: &RootSpace,
+) -> Result<CompressionPolicy, FdbError> {
+    Ok(load_value(tx, &root.compression_policy, true)
+        .await?
+        .unwrap_or_default())
+}
+
+pub(crate) fn load_key(global: &Global, key_id: KeyId) -> Result<Key, Error> {
+    global
+        .key_provider()
+        .ok_or_else(|| Error(anyhow!("No key provider to obtain key {}", key_id)))?
+        .key(key_id)
+}
+
+// The key which should be used to encrypt data stored within the root from now on,
+// or `None` if it should be stored unencrypted.
+pub(crate) fn current_key(
+    global: &Global,
+    root: &RootSpace,
+) -> Result<Option<(KeyId, Key)>, Error> {
+    global
+        .key_provider()
+        .and_then(|key_provider| key_provider.current_key_id(&root.root))
+        .map(|key_id| Ok((key_id, load_key(global, key_id)?)))
+        .transpose()
+}
+
+async fn load_header(
+    tx: &Transaction,
+    root: &RootSpace,
+    blob_id: Uuid,
+    snapshot: bool,
+) -> Result<BlobHeader, Error> {
+    let header_key = root.blob_data.pack(&(blob_id, BLOB_HEADER_INDEX));
+    Ok(match tx.get(&header_key, snapshot).await? {
+        Some(bytes) => BlobHeader::decode(&bytes)?,
+        None => BlobHeader::default(),
+    })
+}
 
 pub(crate) async fn load_internal(
     tx: &Transaction,
+    global: &Global,
     root: &RootSpace,
     blob_id: Uuid,
     snapshot: bool,
@@ -38,25 +223,45 @@ pub(crate) async fn load_internal(
         )?;
     }
 
+    let header_key = root.blob_data.pack(&(blob_id, BLOB_HEADER_INDEX));
     let mut stream = tx.get_ranges(range, true);
-    let mut res = Vec::new();
+    let mut stripes = Vec::new();
+    let mut header = BlobHeader::default();
     let mut exists = false;
     while let Some(values) = stream.try_next().await? {
         for value in values {
             exists = true;
-            res.extend_from_slice(value.value());
+            if value.key() == &*header_key {
+                header = BlobHeader::decode(value.value())?;
+            } else {
+                stripes.push(value);
+            }
         }
     }
-    if exists {
-        Ok(Some(res))
-    } else {
-        Ok(None)
+    if !exists {
+        return Ok(None);
     }
+
+    let keys = StripeKeys::load(global, &header)?;
+    let mut res = Vec::new();
+    for (index, stripe) in stripes.iter().enumerate() {
+        res.extend_from_slice(&keys.decrypt(blob_id, index as u32, stripe.value())?);
+    }
+    Ok(Some(decompress(res, header.encoding)?))
 }
 
-pub(crate) fn store_internal(tx: &Transaction, root: &RootSpace, blob_id: Uuid, data: &[u8]) {
+pub(crate) fn store_internal(
+    tx: &Transaction,
+    global: &Global,
+    root: &RootSpace,
+    blob_id: Uuid,
+    data: &[u8],
+    policy: &CompressionPolicy,
+) -> Result<(), Error> {
     let modified_key = root.blob_modified.pack(&blob_id);
     let range = root.blob_data.nested_range(&(blob_id,));
+    let (encoding, data) = compress(data, policy);
+    let key = current_key(global, root)?;
 
     tx.atomic_op(
         &modified_key,
@@ -65,9 +270,108 @@ pub(crate) fn store_internal(tx: &Transaction, root: &RootSpace, blob_id: Uuid,
     );
     tx.clear_range(&range.0, &range.1);
     for (index, chunk) in data.chunks(BLOB_STRIPE_SIZE).enumerate() {
-        let key = root.blob_data.pack(&(blob_id, index as u32));
-        tx.set(&key, chunk);
+        let index = index as u32;
+        let stripe_key = root.blob_data.pack(&(blob_id, index));
+        if let Some((_, key)) = &key {
+            tx.set(&stripe_key, &encrypt_stripe(key, blob_id, index, chunk));
+        } else {
+            tx.set(&stripe_key, chunk);
+        }
+    }
+    if encoding.is_some() || key.is_some() {
+        let header_key = root.blob_data.pack(&(blob_id, BLOB_HEADER_INDEX));
+        let header = BlobHeader {
+            encoding,
+            key_id: key.map(|(key_id, _)| key_id),
+            rekey: None,
+        };
+        tx.set(
+            &header_key,
+            &postcard::to_stdvec(&header).expect("Infallible serialization"),
+        );
     }
+    Ok(())
+}
+
+// Re-encrypts the stripes of a blob with the current key for its root, if it was stored
+// with a different key, spending at most `budget` bytes. Large blobs are re-encrypted
+// across several calls, and the blob is not marked as modified, since its contents are
+// unchanged. A re-encryption which is already in progress is always finished with the
+// key it was started with.
+pub(crate) async fn reencrypt_internal(
+    tx: &Transaction,
+    global: &Global,
+    root: &RootSpace,
+    blob_id: Uuid,
+    budget: &mut usize,
+) -> Result<Reencrypted, Error> {
+    // The blob may have been deleted since it was listed
+    if tx
+        .get(&root.blob_modified.pack(&blob_id), false)
+        .await?
+        .is_none()
+    {
+        return Ok(Reencrypted::Unchanged);
+    }
+    let mut header = load_header(tx, root, blob_id, false).await?;
+    let (target_key_id, next_index) = match header.rekey {
+        Some(rekey) => (rekey.key_id, rekey.next_index),
+        None => {
+            let current_key_id = global
+                .key_provider()
+                .and_then(|key_provider| key_provider.current_key_id(&root.root));
+            if header.key_id == current_key_id {
+                return Ok(Reencrypted::Unchanged);
+            }
+            (current_key_id, 0)
+        }
+    };
+    let keys = StripeKeys::load(global, &header)?;
+    let target_key = target_key_id
+        .map(|key_id| load_key(global, key_id))
+        .transpose()?;
+
+    let limit = (*budget / BLOB_STRIPE_SIZE).max(1);
+    let mut range: RangeOption = (
+        root.blob_data.pack(&(blob_id, next_index)),
+        root.blob_data.pack(&(blob_id, BLOB_HEADER_INDEX)),
+    )
+        .into();
+    range.limit = Some(limit);
+    range.mode = StreamingMode::WantAll;
+    let values = tx.get_range(&range, 0, false).await?;
+    for (offset, value) in values.iter().enumerate() {
+        let index = next_index + offset as u32;
+        let data = keys.decrypt(blob_id, index, value.value())?;
+        if let Some(key) = &target_key {
+            tx.set(value.key(), &encrypt_stripe(key, blob_id, index, &data));
+        } else {
+            tx.set(value.key(), &data);
+        }
+        *budget = budget.saturating_sub(value.value().len());
+    }
+
+    let header_key = root.blob_data.pack(&(blob_id, BLOB_HEADER_INDEX));
+    let res = if values.len() < limit {
+        header.key_id = target_key_id;
+        header.rekey = None;
+        Reencrypted::Complete
+    } else {
+        header.rekey = Some(Rekey {
+            key_id: target_key_id,
+            next_index: next_index + values.len() as u32,
+        });
+        Reencrypted::Partial
+    };
+    if header.encoding.is_none() && header.key_id.is_none() && header.rekey.is_none() {
+        tx.clear(&header_key);
+    } else {
+        tx.set(
+            &header_key,
+            &postcard::to_stdvec(&header).expect("Infallible serialization"),
+        );
+    }
+    Ok(res)
 }
 
 pub(crate) fn delete_internal(tx: &Transaction, root: &RootSpace, blob_id: Uuid) {
@@ -77,6 +381,68 @@ pub(crate) fn delete_internal(tx: &Transaction, root: &RootSpace, blob_id: Uuid)
     tx.clear_range(&range.0, &range.1);
 }
 
+// Adds `count` references to a shared blob.
+pub(crate) fn add_refs(tx: &Transaction, root: &RootSpace, blob_id: Uuid, count: i64) {
+    tx.atomic_op(
+        &root.blob_refs.pack(&blob_id),
+        &count.to_le_bytes(),
+        MutationType::Add,
+    );
+}
+
+// Releases a reference to a shared blob, deleting the blob if that was the last reference.
+// A blob with no recorded references is treated as having a single reference.
+pub(crate) async fn release_ref(
+    tx: &Transaction,
+    root: &RootSpace,
+    blob_id: Uuid,
+) -> Result<(), Error> {
+    let refs_key = root.blob_refs.pack(&blob_id);
+    tx.atomic_op(&refs_key, &(-1i64).to_le_bytes(), MutationType::Add);
+
+    // Use a snapshot read so that recipients releasing the same blob concurrently don't
+    // conflict. If they all miss the last reference, the blob is cleaned up by `gc_refs`.
+    let remaining = tx
+        .get(&refs_key, true)
+        .await?
+        .map(|slice| LittleEndian::read_i64(&slice))
+        .unwrap_or_default();
+    if remaining <= 0 {
+        delete_internal(tx, root, blob_id);
+        tx.clear(&refs_key);
+    }
+    Ok(())
+}
+
+// Deletes shared blobs within the range which no longer have any references.
+pub(crate) async fn gc_refs(
+    tx: &Transaction,
+    root: &RootSpace,
+    range: impl Into<RangeOption<'_>>,
+    reverse: bool,
+    limit: usize,
+) -> Result<(), Error> {
+    let mut range = range.into();
+    range.limit = Some(limit);
+    range.reverse = reverse;
+    let mut stream = tx.get_ranges(range, true);
+    while let Some(values) = stream.try_next().await? {
+        for value in values {
+            if LittleEndian::read_i64(value.value()) <= 0 {
+                // Make sure the blob was not referenced again in the meantime
+                if let Some(slice) = tx.get(value.key(), false).await? {
+                    if LittleEndian::read_i64(&slice) <= 0 {
+                        let blob_id = root.blob_refs.unpack(value.key())?;
+                        delete_internal(tx, root, blob_id);
+                        tx.clear(value.key());
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
 pub(crate) fn watch_internal(
     tx: &Transaction,
     root: &RootSpace,
@@ -102,10 +468,10 @@ pub(crate) fn watch_stream_internal(
                 global
                     .db()
                     .transact_boxed(
-                        root,
-                        move |tx, root| {
+                        (global.clone(), root),
+                        move |tx, (global, root)| {
                             async move {
-                                let blob = load_internal(tx, root, blob_id, true).await?;
+                                let blob = load_internal(tx, global, root, blob_id, true).await?;
                                 let fut = watch_internal(tx, root, blob_id);
                                 Ok::<_, Error>((blob, Some(fut)))
                             }
@@ -128,10 +494,11 @@ pub async fn load(
     snapshot: bool,
 ) -> Result<Option<Vec<u8>>, Error> {
     let root = global.root(root).await?;
-    load_internal(tx, &root, blob_id, snapshot).await
+    load_internal(tx, global, &root, blob_id, snapshot).await
 }
 
-/// Store a blob. Will overwrite any existing blob with this ID.
+/// Store a blob. Will overwrite any existing blob with this ID. The blob will be
+/// compressed according to the root's compression policy.
 pub async fn store(
     tx: &Transaction,
     global: &Global,
@@ -140,8 +507,8 @@ pub async fn store(
     data: &[u8],
 ) -> Result<(), Error> {
     let root = global.root(root).await?;
-    store_internal(tx, &root, blob_id, data);
-    Ok(())
+    let policy = load_compression_policy(tx, &root).await?;
+    store_internal(tx, global, &root, blob_id, data, &policy)
 }
 
 /// Delete a blob. Will have no effect if the blob does not exist.
@@ -156,6 +523,31 @@ pub async fn delete(
     Ok(())
 }
 
+/// Add a reference to a blob published by a [BlobWriter], so that it is kept until
+/// the reference is released with [release].
+pub async fn retain(
+    tx: &Transaction,
+    global: &Global,
+    root: &str,
+    blob_id: Uuid,
+) -> Result<(), Error> {
+    let root = global.root(root).await?;
+    add_refs(tx, &root, blob_id, 1);
+    Ok(())
+}
+
+/// Release a reference to a blob published by a [BlobWriter]. The blob is deleted
+/// once its last reference has been released.
+pub async fn release(
+    tx: &Transaction,
+    global: &Global,
+    root: &str,
+    blob_id: Uuid,
+) -> Result<(), Error> {
+    let root = global.root(root).await?;
+    release_ref(tx, &root, blob_id).await
+}
+
 /// Watch for changes to a blob. The returned future will resolve when
 /// the blob first changes.
 pub async fn watch(
@@ -184,3 +576,318 @@ pub fn watch_stream(
         .map_ok(move |root| watch_stream_internal(global, root, blob_id))
         .try_flatten_stream()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        encryption::KeyProvider,
+        test_utils::{commit, TestRoot},
+    };
+
+    fn compressible_data() -> Vec<u8> {
+        b"agentdb "
+            .iter()
+            .copied()
+            .cycle()
+            .take(BLOB_STRIPE_SIZE * 3)
+            .collect()
+    }
+
+    #[test]
+    fn compression_round_trips() {
+        let data = compressible_data();
+        for &algorithm in &[
+            CompressionAlgorithm::Zstd { level: 0 },
+            CompressionAlgorithm::Lz4,
+        ] {
+            let policy = CompressionPolicy {
+                algorithm,
+                min_size: 1024,
+            };
+            let (encoding, compressed) = compress(&data, &policy);
+            assert!(encoding.is_some());
+            assert!(compressed.len() < data.len());
+            assert_eq!(decompress(compressed.into_owned(), encoding).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn small_or_incompressible_blobs_are_stored_as_is() {
+        let policy = CompressionPolicy {
+            algorithm: CompressionAlgorithm::Zstd { level: 0 },
+            min_size: 1024,
+        };
+        let small = vec![0; 100];
+        assert!(compress(&small, &policy).0.is_none());
+
+        // Pseudo-random data doesn't get any smaller
+        let mut x = 0x2545_f491_4f6c_dd1du64;
+        let incompressible: Vec<u8> = (0..4096)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect();
+        let (encoding, data) = compress(&incompressible, &policy);
+        assert!(encoding.is_none());
+        assert_eq!(&*data, &*incompressible);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn compressed_blob_round_trips() -> Result<(), Error> {
+        let root = TestRoot::new().await;
+        let policy = CompressionPolicy {
+            algorithm: CompressionAlgorithm::Lz4,
+            min_size: 0,
+        };
+        let blob_id = Uuid::new_v4();
+        let data = compressible_data();
+
+        let tx = root.tx();
+        store_internal(&tx, &root.global, &root.space, blob_id, &data, &policy)?;
+        commit(tx).await?;
+
+        let tx = root.tx();
+        let stripe_count = tx
+            .get_range(
+                &root.space.blob_data.nested_range(&(blob_id,)).into(),
+                0,
+                true,
+            )
+            .await?
+            .len();
+        assert_eq!(stripe_count, 2, "One data stripe and a header");
+        assert_eq!(
+            load_internal(&tx, &root.global, &root.space, blob_id, true).await?,
+            Some(data)
+        );
+
+        root.cleanup().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn shared_blob_deleted_after_last_release() -> Result<(), Error> {
+        let root = TestRoot::new().await;
+        let blob_id = Uuid::new_v4();
+
+        let tx = root.tx();
+        store_internal(
+            &tx,
+            &root.global,
+            &root.space,
+            blob_id,
+            b"shared",
+            &CompressionPolicy::default(),
+        )?;
+        add_refs(&tx, &root.space, blob_id, 2);
+        commit(tx).await?;
+
+        let tx = root.tx();
+        release_ref(&tx, &root.space, blob_id).await?;
+        commit(tx).await?;
+        let tx = root.tx();
+        assert!(load_internal(&tx, &root.global, &root.space, blob_id, true)
+            .await?
+            .is_some());
+        release_ref(&tx, &root.space, blob_id).await?;
+        commit(tx).await?;
+
+        let tx = root.tx();
+        assert!(load_internal(&tx, &root.global, &root.space, blob_id, true)
+            .await?
+            .is_none());
+        assert!(tx
+            .get(&root.space.blob_refs.pack(&blob_id), true)
+            .await?
+            .is_none());
+
+        root.cleanup().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn gc_removes_unreferenced_shared_blobs() -> Result<(), Error> {
+        let root = TestRoot::new().await;
+        let (orphan_id, live_id) = (Uuid::new_v4(), Uuid::new_v4());
+
+        // Simulate concurrent releases which all missed the last reference
+        let tx = root.tx();
+        for &blob_id in &[orphan_id, live_id] {
+            store_internal(
+                &tx,
+                &root.global,
+                &root.space,
+                blob_id,
+                b"shared",
+                &CompressionPolicy::default(),
+            )?;
+        }
+        add_refs(&tx, &root.space, orphan_id, 0);
+        add_refs(&tx, &root.space, live_id, 1);
+        commit(tx).await?;
+
+        let tx = root.tx();
+        gc_refs(&tx, &root.space, root.space.blob_refs.range(), false, 10).await?;
+        commit(tx).await?;
+
+        let tx = root.tx();
+        assert!(
+            load_internal(&tx, &root.global, &root.space, orphan_id, true)
+                .await?
+                .is_none()
+        );
+        assert!(load_internal(&tx, &root.global, &root.space, live_id, true)
+            .await?
+            .is_some());
+
+        root.cleanup().await;
+        Ok(())
+    }
+
+    #[test]
+    fn legacy_headers_are_decoded() {
+        let legacy = postcard::to_stdvec(&(Some(BlobEncoding::Lz4), Some(3u32))).unwrap();
+        let header = BlobHeader::decode(&legacy).unwrap();
+        assert!(matches!(header.encoding, Some(BlobEncoding::Lz4)));
+        assert_eq!(header.key_id, Some(3));
+        assert!(header.rekey.is_none());
+
+        let header = BlobHeader {
+            encoding: None,
+            key_id: Some(3),
+            rekey: Some(Rekey {
+                key_id: Some(4),
+                next_index: 2,
+            }),
+        };
+        let header = BlobHeader::decode(&postcard::to_stdvec(&header).unwrap()).unwrap();
+        assert_eq!(header.key_id, Some(3));
+        assert!(matches!(
+            header.rekey,
+            Some(Rekey {
+                key_id: Some(4),
+                next_index: 2
+            })
+        ));
+    }
+
+    struct TestKeyProvider {
+        current_key_id: parking_lot::Mutex<Option<KeyId>>,
+    }
+
+    impl KeyProvider for TestKeyProvider {
+        fn current_key_id(&self, _root: &str) -> Option<KeyId> {
+            *self.current_key_id.lock()
+        }
+        fn key(&self, key_id: KeyId) -> Result<Key, Error> {
+            Ok([key_id as u8; 32])
+        }
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn large_blob_is_reencrypted_incrementally() -> Result<(), Error> {
+        let root = TestRoot::new().await;
+        let key_provider = Arc::new(TestKeyProvider {
+            current_key_id: parking_lot::Mutex::new(Some(1)),
+        });
+        root.global.set_key_provider(key_provider.clone());
+        let blob_id = Uuid::new_v4();
+        let data = compressible_data();
+
+        let tx = root.tx();
+        store_internal(
+            &tx,
+            &root.global,
+            &root.space,
+            blob_id,
+            &data,
+            &CompressionPolicy::default(),
+        )?;
+        commit(tx).await?;
+        let modified_key = root.space.blob_modified.pack(&blob_id);
+        let modified = root.tx().get(&modified_key, true).await?.unwrap().to_vec();
+
+        // A budget of one stripe leaves the blob readable with a mix of keys
+        *key_provider.current_key_id.lock() = Some(2);
+        let tx = root.tx();
+        let mut budget = BLOB_STRIPE_SIZE;
+        assert_eq!(
+            reencrypt_internal(&tx, &root.global, &root.space, blob_id, &mut budget).await?,
+            Reencrypted::Partial
+        );
+        commit(tx).await?;
+        let tx = root.tx();
+        let header = load_header(&tx, &root.space, blob_id, true).await?;
+        assert_eq!(header.key_id, Some(1));
+        assert!(matches!(
+            header.rekey,
+            Some(Rekey {
+                key_id: Some(2),
+                next_index: 1
+            })
+        ));
+        assert_eq!(
+            load_internal(&tx, &root.global, &root.space, blob_id, true).await?,
+            Some(data.clone())
+        );
+        let streamed: Vec<Vec<u8>> = read_stream(root.global.clone(), &root.name, blob_id)
+            .try_collect()
+            .await?;
+        assert_eq!(streamed.concat(), data);
+
+        let tx = root.tx();
+        let mut budget = usize::MAX;
+        assert_eq!(
+            reencrypt_internal(&tx, &root.global, &root.space, blob_id, &mut budget).await?,
+            Reencrypted::Complete
+        );
+        commit(tx).await?;
+        let tx = root.tx();
+        let header = load_header(&tx, &root.space, blob_id, true).await?;
+        assert_eq!(header.key_id, Some(2));
+        assert!(header.rekey.is_none());
+        assert_eq!(
+            load_internal(&tx, &root.global, &root.space, blob_id, true).await?,
+            Some(data.clone())
+        );
+        let mut budget = usize::MAX;
+        assert_eq!(
+            reencrypt_internal(&tx, &root.global, &root.space, blob_id, &mut budget).await?,
+            Reencrypted::Unchanged
+        );
+
+        // Decrypting the blob removes its header, and never marks it as modified
+        *key_provider.current_key_id.lock() = None;
+        assert_eq!(
+            crate::admin::reencrypt_blobs(&root.global, &root.name).await?,
+            1
+        );
+        let tx = root.tx();
+        assert!(tx
+            .get(
+                &root.space.blob_data.pack(&(blob_id, BLOB_HEADER_INDEX)),
+                true
+            )
+            .await?
+            .is_none());
+        assert_eq!(
+            load_internal(&tx, &root.global, &root.space, blob_id, true).await?,
+            Some(data)
+        );
+        assert_eq!(
+            tx.get(&modified_key, true).await?.unwrap().to_vec(),
+            modified
+        );
+
+        root.cleanup().await;
+        Ok(())
+    }
+}
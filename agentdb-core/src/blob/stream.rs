@@ -0,0 +1,463 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use byteorder::{ByteOrder, LittleEndian};
+use foundationdb::{
+    options::{MutationType, StreamingMode},
+    RangeOption, TransactOption, Transaction,
+};
+use futures::{
+    future::BoxFuture, io::AsyncWrite, ready, stream, FutureExt, Stream, TryFutureExt, TryStreamExt,
+};
+use uuid::Uuid;
+
+use super::{
+    add_refs, current_key, delete_internal, load_header, load_internal, BlobHeader, StripeKeys,
+    BLOB_HEADER_INDEX, BLOB_STRIPE_SIZE,
+};
+use crate::{
+    directories::{Global, RootSpace},
+    encryption::{encrypt_stripe, Key, KeyId},
+    error::Error,
+    id, Timestamp,
+};
+
+// Write at most this many bytes to a staged blob in each transaction
+const STAGING_BYTES_PER_TRANSACTION: usize = 1024 * 1024;
+// Read at most this many stripes of a blob in each transaction
+const STRIPES_PER_TRANSACTION: usize = 64;
+// Staged blobs which are not published within this time are removed
+pub(crate) const STAGING_TIMEOUT: Duration = Duration::from_secs(60 * 60 * 24);
+
+fn to_io_error(e: Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.0)
+}
+
+/// Writes a blob which may be too large to store in a single transaction.
+///
+/// Data written to the writer is stored across as many transactions as necessary
+/// under a staging ID, and only becomes visible once [BlobWriter::publish] is
+/// called. Staged blobs which are never published are eventually removed, after
+/// which further writes fail. Streamed blobs are encrypted if a key provider is
+/// installed, but are never compressed.
+pub struct BlobWriter {
+    global: Arc<Global>,
+    root: Arc<RootSpace>,
+    blob_id: Uuid,
+    key: Option<(KeyId, Key)>,
+    buffer: Vec<u8>,
+    next_index: u32,
+    pending: Option<BoxFuture<'static, Result<(), Error>>>,
+}
+
+impl BlobWriter {
+    /// Begin writing a new blob within the given root.
+    pub async fn new(global: Arc<Global>, root: &str) -> Result<Self, Error> {
+        let root = global.root(root).await?;
+        let key = current_key(&global, &root)?;
+        let blob_id = id::new();
+        let staging_key = root.blob_staging.pack(&blob_id);
+        global
+            .db()
+            .transact_boxed(
+                &staging_key,
+                |tx, &mut staging_key| {
+                    async move {
+                        tx.set(staging_key, &Timestamp::now().millis().to_le_bytes());
+                        Ok::<_, Error>(())
+                    }
+                    .boxed()
+                },
+                TransactOption::idempotent(),
+            )
+            .await?;
+        Ok(Self {
+            global,
+            root,
+            blob_id,
+            key,
+            buffer: Vec::with_capacity(STAGING_BYTES_PER_TRANSACTION),
+            next_index: 0,
+            pending: None,
+        })
+    }
+
+    /// The ID the blob will have once it is published.
+    pub fn blob_id(&self) -> Uuid {
+        self.blob_id
+    }
+
+    // Starts writing the buffered data to the database.
+    fn start_write(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let mut stripes = Vec::new();
+        for chunk in self.buffer.chunks(BLOB_STRIPE_SIZE) {
+            let index = self.next_index;
+            self.next_index += 1;
+            let stripe = if let Some((_, key)) = &self.key {
+                encrypt_stripe(key, self.blob_id, index, chunk)
+            } else {
+                chunk.to_vec()
+            };
+            stripes.push((self.root.blob_data.pack(&(self.blob_id, index)), stripe));
+        }
+        self.buffer.clear();
+
+        let global = self.global.clone();
+        let staging_key = self.root.blob_staging.pack(&self.blob_id);
+        let blob_id = self.blob_id;
+        self.pending = Some(
+            async move {
+                global
+                    .db()
+                    .transact_boxed(
+                        (&staging_key, &stripes),
+                        |tx, &mut (staging_key, stripes)| {
+                            async move {
+                                // Don't write stripes for a blob which was already removed
+                                if tx.get(staging_key, false).await?.is_none() {
+                                    return Err(Error(anyhow!("Staged blob expired: {}", blob_id)));
+                                }
+                                for (key, stripe) in stripes {
+                                    tx.set(key, stripe);
+                                }
+                                Ok::<_, Error>(())
+                            }
+                            .boxed()
+                        },
+                        TransactOption::idempotent(),
+                    )
+                    .await
+            }
+            .boxed(),
+        );
+    }
+
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        if let Some(pending) = &mut self.pending {
+            let res = ready!(pending.poll_unpin(cx));
+            self.pending = None;
+            res?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Write any remaining data and publish the blob, returning its ID. The blob
+    /// can then be loaded like any other blob, or streamed using [read_stream].
+    /// The published blob has a single reference, owned by the caller, which
+    /// must eventually be released using [super::release].
+    pub async fn publish(mut self) -> Result<Uuid, Error> {
+        futures::future::poll_fn(|cx| self.poll_pending(cx)).await?;
+        self.start_write();
+        futures::future::poll_fn(|cx| self.poll_pending(cx)).await?;
+
+        let key_id = self.key.map(|(key_id, _)| key_id);
+        let (root, blob_id) = (&self.root, self.blob_id);
+        self.global
+            .db()
+            .transact_boxed(
+                (root, blob_id, key_id),
+                |tx, &mut (root, blob_id, key_id)| {
+                    async move {
+                        let staging_key = root.blob_staging.pack(&blob_id);
+                        if tx.get(&staging_key, false).await?.is_none() {
+                            return Err(Error(anyhow!("Staged blob expired: {}", blob_id)));
+                        }
+                        tx.clear(&staging_key);
+                        add_refs(tx, root, blob_id, 1);
+
+                        if key_id.is_some() {
+                            let header = BlobHeader {
+                                encoding: None,
+                                key_id,
+                                rekey: None,
+                            };
+                            tx.set(
+                                &root.blob_data.pack(&(blob_id, BLOB_HEADER_INDEX)),
+                                &postcard::to_stdvec(&header).expect("Infallible serialization"),
+                            );
+                        }
+                        tx.atomic_op(
+                            &root.blob_modified.pack(&blob_id),
+                            &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+                            MutationType::SetVersionstampedValue,
+                        );
+                        Ok(())
+                    }
+                    .boxed()
+                },
+                TransactOption::idempotent(),
+            )
+            .await?;
+        Ok(self.blob_id)
+    }
+}
+
+impl AsyncWrite for BlobWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx)).map_err(to_io_error)?;
+        if this.buffer.len() >= STAGING_BYTES_PER_TRANSACTION {
+            this.start_write();
+            ready!(this.poll_pending(cx)).map_err(to_io_error)?;
+        }
+        let len = buf
+            .len()
+            .min(STAGING_BYTES_PER_TRANSACTION - this.buffer.len());
+        this.buffer.extend_from_slice(&buf[..len]);
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx)).map_err(to_io_error)?;
+        this.start_write();
+        this.poll_pending(cx).map_err(to_io_error)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+// Removes staged blobs within the range which were never published.
+pub(crate) async fn gc_staging(
+    tx: &Transaction,
+    root: &RootSpace,
+    gc_ts: i64,
+    range: impl Into<RangeOption<'_>>,
+    reverse: bool,
+    limit: usize,
+) -> Result<(), Error> {
+    let mut range = range.into();
+    range.limit = Some(limit);
+    range.reverse = reverse;
+    let mut stream = tx.get_ranges(range, true);
+    while let Some(values) = stream.try_next().await? {
+        for value in values {
+            if LittleEndian::read_i64(value.value()) < gc_ts {
+                let blob_id = root.blob_staging.unpack(value.key())?;
+                delete_internal(tx, root, blob_id);
+                tx.clear(value.key());
+            }
+        }
+    }
+    Ok(())
+}
+
+struct ReadState {
+    next_index: u32,
+    modified: Vec<u8>,
+}
+
+enum ReadChunk {
+    // The blob does not exist
+    Missing,
+    // The blob is compressed, so it must be read in full
+    Whole(Vec<u8>),
+    Stripes(Vec<Vec<u8>>, ReadState),
+}
+
+async fn read_chunk(
+    tx: &Transaction,
+    global: &Global,
+    root: &RootSpace,
+    blob_id: Uuid,
+    state: Option<&ReadState>,
+) -> Result<ReadChunk, Error> {
+    let modified_key = root.blob_modified.pack(&blob_id);
+    let modified = match (tx.get(&modified_key, true).await?, state) {
+        (Some(modified), _) => modified.to_vec(),
+        (None, None) => return Ok(ReadChunk::Missing),
+        (None, Some(_)) => return Err(Error(anyhow!("Blob deleted while reading: {}", blob_id))),
+    };
+
+    // The header is loaded for every chunk, since the blob may be re-encrypted
+    // between chunks without being modified.
+    let header = load_header(tx, root, blob_id, true).await?;
+    let next_index = if let Some(state) = state {
+        if state.modified != modified {
+            return Err(Error(anyhow!("Blob modified while reading: {}", blob_id)));
+        }
+        state.next_index
+    } else {
+        if header.encoding.is_some() {
+            let data = load_internal(tx, global, root, blob_id, true).await?;
+            return Ok(data.map_or(ReadChunk::Missing, ReadChunk::Whole));
+        }
+        0
+    };
+    let keys = StripeKeys::load(global, &header)?;
+
+    let mut range: RangeOption = (
+        root.blob_data.pack(&(blob_id, next_index)),
+        root.blob_data.pack(&(blob_id, BLOB_HEADER_INDEX)),
+    )
+        .into();
+    range.limit = Some(STRIPES_PER_TRANSACTION);
+    range.mode = StreamingMode::WantAll;
+    let values = tx.get_range(&range, 0, true).await?;
+    let mut stripes = Vec::with_capacity(values.len());
+    for (offset, value) in values.iter().enumerate() {
+        let index = next_index + offset as u32;
+        stripes.push(keys.decrypt(blob_id, index, value.value())?.into_owned());
+    }
+    let next_index = next_index + stripes.len() as u32;
+    Ok(ReadChunk::Stripes(
+        stripes,
+        ReadState {
+            next_index,
+            modified,
+        },
+    ))
+}
+
+/// Read a blob which may be too large to load in a single transaction. The
+/// returned stream yields the contents of the blob in order, split into chunks,
+/// and yields nothing if the blob does not exist. An error is returned if the
+/// blob is changed while it is being read.
+pub fn read_stream(
+    global: Arc<Global>,
+    root: &str,
+    blob_id: Uuid,
+) -> impl Stream<Item = Result<Vec<u8>, Error>> + 'static {
+    let root = root.to_owned();
+    let global2 = global.clone();
+    async move { global2.root(&root).await }
+        .map_ok(move |root| {
+            stream::try_unfold(Some(None), move |maybe_state: Option<Option<ReadState>>| {
+                let global = global.clone();
+                let root = root.clone();
+                async move {
+                    let state = if let Some(state) = maybe_state {
+                        state
+                    } else {
+                        return Ok(None);
+                    };
+                    let chunk = global
+                        .db()
+                        .transact_boxed(
+                            (&*global, &root, &state),
+                            |tx, &mut (global, root, state)| {
+                                read_chunk(tx, global, root, blob_id, state.as_ref()).boxed()
+                            },
+                            TransactOption::idempotent(),
+                        )
+                        .await?;
+                    Ok::<_, Error>(match chunk {
+                        ReadChunk::Missing => None,
+                        ReadChunk::Whole(data) => Some((stream::iter(vec![Ok(data)]), None)),
+                        ReadChunk::Stripes(stripes, state) => {
+                            let next_state = if stripes.is_empty() {
+                                None
+                            } else {
+                                Some(Some(state))
+                            };
+                            Some((
+                                stream::iter(stripes.into_iter().map(Ok).collect::<Vec<_>>()),
+                                next_state,
+                            ))
+                        }
+                    })
+                }
+            })
+            .try_flatten()
+        })
+        .try_flatten_stream()
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::AsyncWriteExt;
+
+    use super::*;
+    use crate::{
+        blob::{load_internal, release_ref},
+        test_utils::{commit, TestRoot},
+    };
+
+    #[tokio::test]
+    #[ignore]
+    async fn streamed_blob_round_trips() -> Result<(), Error> {
+        let root = TestRoot::new().await;
+        let data: Vec<u8> = (0..STAGING_BYTES_PER_TRANSACTION * 5 / 2)
+            .map(|i| (i % 251) as u8)
+            .collect();
+
+        let mut writer = BlobWriter::new(root.global.clone(), &root.name).await?;
+        for chunk in data.chunks(100_000) {
+            writer.write_all(chunk).await?;
+        }
+        let blob_id = writer.publish().await?;
+
+        let streamed: Vec<Vec<u8>> = read_stream(root.global.clone(), &root.name, blob_id)
+            .try_collect()
+            .await?;
+        assert!(streamed.len() > 1);
+        assert_eq!(streamed.concat(), data);
+
+        // The blob is deleted once the writer's reference is released
+        let tx = root.tx();
+        assert!(tx
+            .get(&root.space.blob_staging.pack(&blob_id), true)
+            .await?
+            .is_none());
+        release_ref(&tx, &root.space, blob_id).await?;
+        commit(tx).await?;
+        let tx = root.tx();
+        assert!(load_internal(&tx, &root.global, &root.space, blob_id, true)
+            .await?
+            .is_none());
+
+        root.cleanup().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn writes_to_expired_blob_fail() -> Result<(), Error> {
+        let root = TestRoot::new().await;
+        let mut writer = BlobWriter::new(root.global.clone(), &root.name).await?;
+        writer.write_all(b"first").await?;
+        writer.flush().await?;
+
+        let tx = root.tx();
+        gc_staging(
+            &tx,
+            &root.space,
+            i64::MAX,
+            root.space.blob_staging.range(),
+            false,
+            10,
+        )
+        .await?;
+        commit(tx).await?;
+
+        // Late stripes are not written, and the blob cannot be published
+        writer.write_all(b"second").await?;
+        assert!(writer.flush().await.is_err());
+        let blob_id = writer.blob_id();
+        assert!(writer.publish().await.is_err());
+        let range = root.space.blob_data.nested_range(&(blob_id,));
+        assert!(root
+            .tx()
+            .get_range(&range.into(), 0, true)
+            .await?
+            .is_empty());
+
+        root.cleanup().await;
+        Ok(())
+    }
+}
@@ -5,11 +5,12 @@ use std::{
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
+    time::Duration,
 };
 
 use futures::{future::FusedFuture, FutureExt};
 use tokio::{
-    sync::{OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock},
+    sync::{watch, OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock},
     task::{JoinError, JoinHandle},
 };
 
@@ -19,6 +20,7 @@ use tokio::{
 pub struct Cancellation {
     inner: Arc<RwLock<bool>>,
     fut: Pin<Box<dyn Future<Output = OwnedRwLockReadGuard<bool>> + Send + Sync>>,
+    drain: watch::Receiver<bool>,
 }
 
 impl Cancellation {
@@ -26,6 +28,23 @@ impl Cancellation {
     pub fn is_cancelled(&self) -> bool {
         matches!(self.inner.try_read(), Ok(x) if *x)
     }
+    /// Returns `true` if the task has been asked to drain: it should finish any
+    /// work already in progress, but not start any new work, and then exit.
+    pub fn is_draining(&self) -> bool {
+        *self.drain.borrow()
+    }
+    /// Returns a future which completes when the task is asked to drain.
+    pub fn draining(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut drain = self.drain.clone();
+        async move {
+            while !*drain.borrow() {
+                if drain.changed().await.is_err() {
+                    // The handle was forgotten, so we will never be asked to drain
+                    futures::future::pending::<()>().await;
+                }
+            }
+        }
+    }
 }
 
 impl Clone for Cancellation {
@@ -33,6 +52,7 @@ impl Clone for Cancellation {
         Self {
             inner: self.inner.clone(),
             fut: Box::pin(self.inner.clone().read_owned()),
+            drain: self.drain.clone(),
         }
     }
 }
@@ -59,6 +79,7 @@ impl FusedFuture for Cancellation {
 /// the returned value on completion.
 pub struct CancellableHandle<T> {
     guard: Option<OwnedRwLockWriteGuard<bool>>,
+    drain: watch::Sender<bool>,
     inner: JoinHandle<T>,
 }
 
@@ -68,10 +89,26 @@ impl<T> CancellableHandle<T> {
     pub fn cancel(&mut self) {
         self.guard.take();
     }
+    /// Ask the task to drain, without waiting for it to finish. Tasks which do not
+    /// support draining will ignore this signal.
+    pub fn start_drain(&self) {
+        // Sending fails only if the task has already exited.
+        let _ = self.drain.send(true);
+    }
+    /// Ask the task to drain, and wait for it to finish. If the task has not finished
+    /// by the deadline, it will be cancelled.
+    pub async fn drain(mut self, deadline: Duration) -> Result<T, JoinError> {
+        self.start_drain();
+        if let Ok(res) = tokio::time::timeout(deadline, &mut self.inner).await {
+            return res;
+        }
+        self.cancel();
+        self.inner.await
+    }
     /// Drop the cancellation handle without triggering cancellation. Returns a normal
     /// `JoinHandle<T>`.
     pub fn forget(self) -> JoinHandle<T> {
-        let Self { guard, inner } = self;
+        let Self { guard, inner, .. } = self;
         if let Some(mut guard) = guard {
             // Don't trigger cancellation when we release the write lock
             *guard = false;
@@ -99,12 +136,50 @@ pub fn spawn_cancellable<T: Send + 'static, F: Future<Output = T> + Send + 'stat
         .try_write_owned()
         .expect("RwLock can be immediately locked");
     let read_guard = Box::pin(rwlock.clone().read_owned());
+    let (drain_tx, drain_rx) = watch::channel(false);
     let inner = tokio::spawn(f(Cancellation {
         inner: rwlock,
         fut: read_guard,
+        drain: drain_rx,
     }));
     CancellableHandle {
         guard: Some(write_guard),
+        drain: drain_tx,
         inner,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn drain_lets_task_finish() {
+        let handle = spawn_cancellable(|cancellation| async move {
+            cancellation.draining().await;
+            !cancellation.is_cancelled()
+        });
+        assert!(handle.drain(Duration::from_secs(5)).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn drain_cancels_task_after_deadline() {
+        let handle = spawn_cancellable(|cancellation| async move {
+            // Ignore the drain signal, and only stop once cancelled
+            cancellation.await;
+            true
+        });
+        assert!(handle.drain(Duration::from_millis(10)).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn forgotten_handle_never_drains() {
+        let handle = spawn_cancellable(|cancellation| async move {
+            tokio::select! {
+                _ = cancellation.draining() => false,
+                _ = tokio::time::sleep(Duration::from_millis(50)) => !cancellation.is_draining(),
+            }
+        });
+        assert!(handle.forget().await.unwrap());
+    }
+}
@@ -0,0 +1,361 @@
+//! Contains functions for consuming the change feed of a root.
+//!
+//! When enabled by the root's [crate::policy::ChangeFeedPolicy], every change
+//! to an agent's state is recorded in the same transaction as the change itself,
+//! ordered by versionstamp. This allows read models and search indexes to be
+//! kept up to date without missing or re-ordering changes.
+//!
+//! Inline states are encrypted with the current key for the root, if a
+//! [crate::encryption::KeyProvider] is installed. They are not re-encrypted by
+//! [crate::admin::reencrypt_blobs], so keys must remain available until every
+//! change encrypted with them has been removed from the feed.
+
+use std::{ops::Bound, sync::Arc};
+
+use foundationdb::{
+    options::{MutationType, StreamingMode},
+    tuple::Versionstamp,
+    RangeOption, TransactOption, Transaction,
+};
+use futures::{future::BoxFuture, stream, FutureExt, Stream, TryFutureExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    blob,
+    directories::{Global, RootSpace},
+    encryption::{decrypt_stripe, encrypt_stripe, KeyId},
+    error::Error,
+    id,
+    policy::ChangeFeedPolicy,
+    utils::load_value,
+    Timestamp,
+};
+
+// States larger than this are not copied into the change feed.
+const INLINE_STATE_LIMIT: usize = 1024 * 64;
+const CHANGES_PER_TRANSACTION: usize = 256;
+
+/// The kind of change made to an agent.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChangeKind {
+    /// The agent was created.
+    Created,
+    /// The state of an existing agent was updated.
+    Updated,
+    /// The agent was destroyed.
+    Destroyed,
+}
+
+/// The state of an agent following a change.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ChangeState {
+    /// The agent no longer exists.
+    None,
+    /// The new state of the agent.
+    Inline(Vec<u8>),
+    /// The new state was too large to include in the change feed, and must
+    /// be loaded from the blob with this ID. The blob may have been changed
+    /// again since this change was recorded.
+    Blob(Uuid),
+}
+
+// The state as stored in the change feed, which may be encrypted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum StoredState {
+    None,
+    Inline {
+        key_id: Option<KeyId>,
+        data: Vec<u8>,
+    },
+    Blob(Uuid),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ChangeValue {
+    agent_id: Uuid,
+    kind: ChangeKind,
+    ts: Timestamp,
+    // Encrypted states are bound to this ID rather than to the version, since
+    // the versionstamp is not known until the transaction commits.
+    change_id: Uuid,
+    state: StoredState,
+}
+
+/// A single change recorded in the change feed.
+#[derive(Debug, Clone)]
+pub struct StateChange {
+    version: Versionstamp,
+    agent_id: Uuid,
+    kind: ChangeKind,
+    ts: Timestamp,
+    state: ChangeState,
+}
+
+impl StateChange {
+    fn decode(global: &Global, version: Versionstamp, value: ChangeValue) -> Result<Self, Error> {
+        let state = match value.state {
+            StoredState::None => ChangeState::None,
+            StoredState::Inline { key_id: None, data } => ChangeState::Inline(data),
+            StoredState::Inline {
+                key_id: Some(key_id),
+                data,
+            } => {
+                let key = blob::load_key(global, key_id)?;
+                ChangeState::Inline(decrypt_stripe(&key, value.change_id, 0, &data)?)
+            }
+            StoredState::Blob(blob_id) => ChangeState::Blob(blob_id),
+        };
+        Ok(Self {
+            version,
+            agent_id: value.agent_id,
+            kind: value.kind,
+            ts: value.ts,
+            state,
+        })
+    }
+}
+
+impl StateChange {
+    /// The version of the change. Pass this to [changes_since] to resume
+    /// the change feed after this change.
+    pub fn version(&self) -> Versionstamp {
+        self.version
+    }
+    /// The ID of the agent which was changed.
+    pub fn agent_id(&self) -> Uuid {
+        self.agent_id
+    }
+    /// The kind of change.
+    pub fn kind(&self) -> ChangeKind {
+        self.kind
+    }
+    /// The time when the change was made.
+    pub fn ts(&self) -> Timestamp {
+        self.ts
+    }
+    /// The state of the agent following the change.
+    pub fn state(&self) -> &ChangeState {
+        &self.state
+    }
+}
+
+// Records a change to an agent's state, if enabled by the root's change feed policy.
+pub(crate) async fn record_change(
+    tx: &Transaction,
+    global: &Global,
+    root: &RootSpace,
+    agent_id: Uuid,
+    kind: ChangeKind,
+    state: Option<&[u8]>,
+) -> Result<(), Error> {
+    let policy = load_value::<ChangeFeedPolicy>(tx, &root.change_feed_policy, true)
+        .await?
+        .unwrap_or_default();
+    if !policy.enabled {
+        return Ok(());
+    }
+
+    let change_id = id::new();
+    let state = match state {
+        None => StoredState::None,
+        Some(state) if state.len() <= INLINE_STATE_LIMIT => {
+            match blob::current_key(global, root)? {
+                Some((key_id, key)) => StoredState::Inline {
+                    key_id: Some(key_id),
+                    data: encrypt_stripe(&key, change_id, 0, state),
+                },
+                None => StoredState::Inline {
+                    key_id: None,
+                    data: state.to_vec(),
+                },
+            }
+        }
+        Some(_) => StoredState::Blob(agent_id),
+    };
+    let value = ChangeValue {
+        agent_id,
+        kind,
+        ts: Timestamp::now(),
+        change_id,
+        state,
+    };
+    tx.atomic_op(
+        &root.changes.pack(&Versionstamp::incomplete(0)),
+        &postcard::to_stdvec(&value).expect("Infallible serialization"),
+        MutationType::SetVersionstampedKey,
+    );
+
+    // Wake up any consumers waiting for new changes
+    tx.atomic_op(
+        &root.changes_modified,
+        &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        MutationType::SetVersionstampedValue,
+    );
+    Ok(())
+}
+
+// Removes the oldest changes from the feed if they were recorded before `gc_ts`.
+pub(crate) async fn gc_changes(
+    tx: &Transaction,
+    root: &RootSpace,
+    gc_ts: Timestamp,
+    limit: usize,
+) -> Result<(), Error> {
+    let mut range: RangeOption = root.changes.range().into();
+    range.limit = Some(limit);
+    range.mode = StreamingMode::WantAll;
+    let values = tx.get_range(&range, 0, true).await?;
+    for value in values.iter() {
+        let change: ChangeValue = postcard::from_bytes(value.value())?;
+        if change.ts >= gc_ts {
+            break;
+        }
+        tx.clear(value.key());
+    }
+    Ok(())
+}
+
+type WatchFuture = BoxFuture<'static, Result<(), Error>>;
+
+fn changes_since_internal(
+    global: Arc<Global>,
+    root: Arc<RootSpace>,
+    cursor: Option<Versionstamp>,
+) -> impl Stream<Item = Result<StateChange, Error>> + 'static {
+    stream::try_unfold(
+        (cursor, None),
+        move |(cursor, maybe_fut): (Option<Versionstamp>, Option<WatchFuture>)| {
+            let root = root.clone();
+            let global = global.clone();
+            async move {
+                if let Some(fut) = maybe_fut {
+                    fut.await?;
+                }
+                let (changes, fut) = global
+                    .db()
+                    .transact_boxed(
+                        (global.clone(), root),
+                        move |tx, (global, root)| {
+                            async move {
+                                let mut range: RangeOption = if let Some(cursor) = cursor {
+                                    root.changes
+                                        .subrange((Bound::Excluded(cursor), Bound::Unbounded))
+                                } else {
+                                    root.changes.range()
+                                }
+                                .into();
+                                range.limit = Some(CHANGES_PER_TRANSACTION);
+                                range.mode = StreamingMode::WantAll;
+                                let values = tx.get_range(&range, 0, true).await?;
+                                let mut changes = Vec::with_capacity(values.len());
+                                for value in values.iter() {
+                                    changes.push(StateChange::decode(
+                                        &global,
+                                        root.changes.unpack(value.key())?,
+                                        postcard::from_bytes(value.value())?,
+                                    )?);
+                                }
+
+                                // Only wait for more changes once we have caught up
+                                let fut: Option<WatchFuture> = if changes.is_empty() {
+                                    Some(tx.watch(&root.changes_modified).err_into().boxed())
+                                } else {
+                                    None
+                                };
+                                Ok::<_, Error>((changes, fut))
+                            }
+                            .boxed()
+                        },
+                        TransactOption::idempotent(),
+                    )
+                    .await?;
+                let cursor = changes.last().map(StateChange::version).or(cursor);
+                Ok::<_, Error>(Some((
+                    stream::iter(changes.into_iter().map(Ok)),
+                    (cursor, fut),
+                )))
+            }
+        },
+    )
+    .try_flatten()
+}
+
+/// Stream changes from the change feed of a root, starting after the change with
+/// the provided version, or from the oldest retained change if `cursor` is `None`.
+/// The stream waits for new changes once it has caught up, and never ends.
+///
+/// To resume the feed reliably, consumers should persist the version of the last
+/// change they processed, and pass it as the cursor when restarting.
+pub fn changes_since(
+    global: Arc<Global>,
+    root: &str,
+    cursor: Option<Versionstamp>,
+) -> impl Stream<Item = Result<StateChange, Error>> + 'static {
+    let root = root.to_owned();
+    let global2 = global.clone();
+    async move { global2.root(&root).await }
+        .map_ok(move |root| changes_since_internal(global, root, cursor))
+        .try_flatten_stream()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::{
+        admin,
+        test_utils::{commit, TestRoot},
+    };
+
+    #[tokio::test]
+    #[ignore]
+    async fn change_feed_resumes_from_cursor() -> Result<(), Error> {
+        let root = TestRoot::new().await;
+        admin::set_change_feed_policy(
+            &root.global,
+            &root.name,
+            ChangeFeedPolicy {
+                enabled: true,
+                retention: Duration::from_secs(60),
+            },
+        )
+        .await?;
+        let agent_id = Uuid::new_v4();
+        let large_state = vec![0; INLINE_STATE_LIMIT + 1];
+        for (kind, state) in &[
+            (ChangeKind::Created, Some(&b"small"[..])),
+            (ChangeKind::Updated, Some(&large_state[..])),
+            (ChangeKind::Destroyed, None),
+        ] {
+            let tx = root.tx();
+            record_change(&tx, &root.global, &root.space, agent_id, *kind, *state).await?;
+            commit(tx).await?;
+        }
+
+        let changes: Vec<_> = changes_since(root.global.clone(), &root.name, None)
+            .take(3)
+            .try_collect()
+            .await?;
+        assert!(changes.iter().all(|change| change.agent_id() == agent_id));
+        assert_eq!(changes[0].kind(), ChangeKind::Created);
+        assert!(matches!(changes[0].state(), ChangeState::Inline(state) if state == b"small"));
+        assert!(matches!(changes[1].state(), ChangeState::Blob(id) if *id == agent_id));
+        assert!(matches!(changes[2].state(), ChangeState::None));
+
+        // Resuming from the first change skips it
+        let resumed: Vec<_> =
+            changes_since(root.global.clone(), &root.name, Some(changes[0].version()))
+                .take(2)
+                .try_collect()
+                .await?;
+        assert_eq!(resumed[0].version(), changes[1].version());
+        assert_eq!(resumed[1].version(), changes[2].version());
+
+        root.cleanup().await;
+        Ok(())
+    }
+}
@@ -1,36 +1,93 @@
-use std::{collections::BTreeMap, ops::Range, sync::Arc};
+use std::{cmp::Ordering, collections::BTreeMap, ops::Range, sync::Arc};
 
 use byteorder::{ByteOrder, LittleEndian};
 use foundationdb::options::MutationType;
 use foundationdb::{FdbError, RangeOption, TransactOption, Transaction};
+use futures::future::{self, BoxFuture, Fuse};
 use futures::{select, FutureExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::blob::{gc_refs, gc_staging, STAGING_TIMEOUT};
 use crate::cancellation::{spawn_cancellable, CancellableHandle, Cancellation};
+use crate::changes::gc_changes;
 use crate::directories::{Global, RootSpace};
-use crate::message::INITIAL_TS_OFFSET;
+use crate::idempotency;
+use crate::message::load_budget_policy;
 use crate::partition::partition_task;
-use crate::utils::load_partition_range;
-use crate::{id, Error, StateFn, Timestamp, GC_INTERVAL, HEARTBEAT_INTERVAL};
+use crate::policy::{AssignmentPolicy, AssignmentStrategy, ChangeFeedPolicy, TracePolicy};
+use crate::trace::gc_traces;
+use crate::utils::{
+    load_partition_range, load_value, range_is_empty, rendezvous_score, save_value,
+};
+use crate::{id, ClientConfig, Error, StateFn, Timestamp, DEFAULT_PARTITION_RANGE};
 
-// Collect operations more than 5 minutes old
-const GC_AGE_MS: i64 = (1000 * 60 * 5) + INITIAL_TS_OFFSET;
 const GC_COUNT_PER_CLIENT: usize = 256;
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AssignedClient {
+    pub id: Uuid,
+    pub name: String,
+    pub weight: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
 pub struct PartitionAssignment {
     pub partition_range: PartitionRange,
-    pub client_count: u32,
-    pub client_index: u32,
+    pub policy: AssignmentPolicy,
+    pub clients: Vec<AssignedClient>,
+    pub client_index: usize,
 }
 
 impl PartitionAssignment {
-    fn offset(&self, index: u32) -> u32 {
-        (self.partition_range.count * index) / self.client_count + self.partition_range.offset
+    fn all_partitions(&self) -> Range<u32> {
+        self.partition_range.offset..(self.partition_range.offset + self.partition_range.count)
+    }
+    // Splits the partition range into contiguous slices proportional to each client's weight
+    fn contiguous_owner(&self, partition: u32) -> Option<usize> {
+        let total_weight: u64 = self.clients.iter().map(|c| u64::from(c.weight)).sum();
+        if total_weight == 0 {
+            return None;
+        }
+        let relative = u64::from(partition - self.partition_range.offset);
+        let mut cumulative_weight = 0;
+        self.clients.iter().position(|client| {
+            cumulative_weight += u64::from(client.weight);
+            relative < u64::from(self.partition_range.count) * cumulative_weight / total_weight
+        })
+    }
+    // Weighted rendezvous hashing: each client's share of the partitions is
+    // proportional to its weight.
+    fn rendezvous_owner(&self, partition: u32) -> Option<usize> {
+        let weighted_score = |client: &AssignedClient| {
+            let score = rendezvous_score(client.id, partition);
+            let unit = (score as f64 + 1.0) / (u64::MAX as f64 + 2.0);
+            -f64::from(client.weight) / unit.ln()
+        };
+        (0..self.clients.len()).max_by(|&a, &b| {
+            weighted_score(&self.clients[a])
+                .partial_cmp(&weighted_score(&self.clients[b]))
+                .unwrap_or(Ordering::Equal)
+        })
+    }
+    // The index of the client which owns the partition. Pinned partitions are
+    // owned by the first active client with the pinned name, if there is one.
+    pub fn owner(&self, partition: u32) -> Option<usize> {
+        if let Some(name) = self.policy.pinned.get(&partition) {
+            if let Some(index) = self.clients.iter().position(|c| &c.name == name) {
+                return Some(index);
+            }
+        }
+        match self.policy.strategy {
+            AssignmentStrategy::Contiguous => self.contiguous_owner(partition),
+            AssignmentStrategy::Rendezvous => self.rendezvous_owner(partition),
+        }
     }
-    pub fn range(&self) -> Range<u32> {
-        self.offset(self.client_index)..self.offset(self.client_index + 1)
+    // The partitions owned by this client, in ascending order
+    pub fn partitions(&self) -> Vec<u32> {
+        self.all_partitions()
+            .filter(|&partition| self.owner(partition) == Some(self.client_index))
+            .collect()
     }
 }
 
@@ -42,6 +99,7 @@ struct ClientState {
     partition_assignment: PartitionAssignment,
     partition_tasks: BTreeMap<u32, CancellableHandle<()>>,
     state_fn: StateFn,
+    config: Arc<ClientConfig>,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
@@ -54,6 +112,40 @@ pub struct PartitionRange {
 pub struct ClientValue {
     pub last_active_ts: Timestamp,
     pub name: String,
+    pub weight: u32,
+}
+
+// Clients which predate weighted assignment don't store a weight. Postcard ignores
+// trailing bytes, so those clients can still read the current format.
+#[derive(Deserialize)]
+struct LegacyClientValue {
+    last_active_ts: Timestamp,
+    name: String,
+}
+
+impl ClientValue {
+    // Decodes a client entry, treating clients without a weight as having a weight
+    // of one, so that they are not mistaken for inactive clients during an upgrade.
+    pub(crate) fn decode(bytes: &[u8]) -> Option<Self> {
+        postcard::from_bytes(bytes).ok().or_else(|| {
+            let legacy = postcard::from_bytes::<LegacyClientValue>(bytes).ok()?;
+            Some(Self {
+                last_active_ts: legacy.last_active_ts,
+                name: legacy.name,
+                weight: 1,
+            })
+        })
+    }
+}
+
+// Wake up every client connected to the root, so that they re-balance
+// partitions immediately rather than waiting for their next heartbeat.
+pub(crate) fn mark_clients_modified(tx: &Transaction, root: &RootSpace) {
+    tx.atomic_op(
+        &root.clients_modified,
+        &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        MutationType::SetVersionstampedValue,
+    );
 }
 
 impl ClientState {
@@ -63,6 +155,7 @@ impl ClientState {
         root: Arc<RootSpace>,
         client_id: Uuid,
         state_fn: StateFn,
+        config: Arc<ClientConfig>,
     ) -> Self {
         Self {
             name,
@@ -72,6 +165,68 @@ impl ClientState {
             partition_assignment: PartitionAssignment::default(),
             partition_tasks: BTreeMap::new(),
             state_fn,
+            config,
+        }
+    }
+    async fn init_partition_range(&self) -> Result<(), Error> {
+        let initial_partition_range = self.config.initial_partition_range;
+        if initial_partition_range == DEFAULT_PARTITION_RANGE {
+            return Ok(());
+        }
+        self.global
+            .db()
+            .transact_boxed(
+                (&self.global, &self.root),
+                |tx, &mut (global, root)| {
+                    async move {
+                        // Only roots which have never been partitioned can be initialized
+                        if tx.get(&root.partition_range_send, false).await?.is_some() {
+                            return Ok(());
+                        }
+
+                        // Messages may have been sent to the default partitions before
+                        // any client connected, in which case they must be re-partitioned
+                        // properly instead.
+                        for partition_idx in DEFAULT_PARTITION_RANGE.offset
+                            ..(DEFAULT_PARTITION_RANGE.offset + DEFAULT_PARTITION_RANGE.count)
+                        {
+                            let partition = root.partition(global, partition_idx).await?;
+                            if !range_is_empty(tx, partition.message.range().into(), false).await?
+                                || !range_is_empty(tx, partition.batch.range().into(), false)
+                                    .await?
+                            {
+                                log::warn!(
+                                    "Root {} already has pending messages, ignoring initial partition range",
+                                    root.root
+                                );
+                                return Ok(());
+                            }
+                        }
+
+                        save_value(tx, &root.partition_range_send, &initial_partition_range);
+                        save_value(tx, &root.partition_range_recv, &initial_partition_range);
+                        Ok::<_, Error>(())
+                    }
+                    .boxed()
+                },
+                TransactOption::idempotent(),
+            )
+            .await
+    }
+    async fn drain(&mut self, cancellation: &mut Cancellation) {
+        log::info!("Draining client...");
+
+        // Let every partition finish the batch it is currently processing,
+        // unless the drain deadline passes first, in which case dropping the
+        // partition tasks will cancel them.
+        let partition_tasks = std::mem::take(&mut self.partition_tasks);
+        for partition_task in partition_tasks.values() {
+            partition_task.start_drain();
+        }
+        let drained = future::join_all(partition_tasks.into_iter().map(|(_, task)| task));
+        select! {
+            _ = drained.fuse() => {},
+            _ = &mut *cancellation => log::warn!("Drain deadline passed, cancelling partitions"),
         }
     }
     async fn shutdown(&mut self) -> Result<(), Error> {
@@ -81,10 +236,11 @@ impl ClientState {
         self.global
             .db()
             .transact_boxed(
-                client_key,
-                |tx, client_key| {
+                (&self.root, client_key),
+                |tx, &mut (root, ref client_key)| {
                     async move {
                         tx.clear(client_key);
+                        mark_clients_modified(tx, root);
                         Ok::<_, FdbError>(())
                     }
                     .boxed()
@@ -94,7 +250,8 @@ impl ClientState {
             .await?;
         Ok(())
     }
-    async fn tick(&mut self) -> Result<(), Error> {
+    // Returns a future which resolves when the set of connected clients changes.
+    async fn tick(&mut self) -> Result<BoxFuture<'static, Result<(), FdbError>>, Error> {
         log::info!("Client tick");
 
         // Update our own timestamp
@@ -102,15 +259,20 @@ impl ClientState {
         let client_value = ClientValue {
             last_active_ts: current_ts,
             name: self.name.clone(),
+            weight: self.config.weight,
         };
         let client_key = self.root.clients.pack(&self.id);
         let client_bytes = postcard::to_stdvec(&client_value).expect("Infallible serialization");
         self.global
             .db()
             .transact_boxed(
-                (&client_key, client_bytes),
-                |tx, &mut (client_key, ref client_bytes)| {
+                (&self.root, &client_key, client_bytes),
+                |tx, &mut (root, client_key, ref client_bytes)| {
                     async move {
+                        // Let the other clients know we've joined
+                        if tx.get(client_key, true).await?.is_none() {
+                            mark_clients_modified(tx, root);
+                        }
                         tx.set(client_key, client_bytes);
                         Ok::<_, FdbError>(())
                     }
@@ -121,8 +283,8 @@ impl ClientState {
             .await?;
 
         // Check for changed client list
-        let expired_ts = current_ts - HEARTBEAT_INTERVAL * 2;
-        let new_partition_assignment = self
+        let expired_ts = current_ts - self.config.heartbeat_interval * 2;
+        let (new_partition_assignment, clients_modified) = self
             .global
             .db()
             .transact_boxed(
@@ -131,20 +293,26 @@ impl ClientState {
                     async move {
                         // Scan for all the active clients
                         let mut kv_stream = tx.get_ranges(root.clients.range().into(), true);
-                        let mut client_count = 0;
+                        let mut clients = Vec::new();
                         let mut client_index = 0;
                         while let Some(kvs) = kv_stream.try_next().await? {
                             for kv in kvs {
-                                if let Ok(client_value) =
-                                    postcard::from_bytes::<ClientValue>(kv.value())
-                                {
+                                if let (Ok(client_id), Some(client_value)) = (
+                                    root.clients.unpack(kv.key()),
+                                    ClientValue::decode(kv.value()),
+                                ) {
                                     if client_value.last_active_ts < expired_ts {
                                         tx.clear(kv.key());
+                                        mark_clients_modified(tx, root);
                                     } else {
                                         if kv.key() == client_key {
-                                            client_index = client_count;
+                                            client_index = clients.len();
                                         }
-                                        client_count += 1;
+                                        clients.push(AssignedClient {
+                                            id: client_id,
+                                            name: client_value.name,
+                                            weight: client_value.weight,
+                                        });
                                     }
                                 }
                             }
@@ -154,12 +322,22 @@ impl ClientState {
                         let partition_range =
                             load_partition_range(tx, &root.partition_range_recv, true).await?;
 
+                        // Read the assignment strategy and pinned partitions
+                        let policy =
+                            load_value::<AssignmentPolicy>(tx, &root.assignment_policy, true)
+                                .await?
+                                .unwrap_or_default();
+
                         // Return the new partition assignment
-                        Ok::<_, FdbError>(PartitionAssignment {
-                            partition_range,
-                            client_index,
-                            client_count,
-                        })
+                        Ok::<_, Error>((
+                            PartitionAssignment {
+                                partition_range,
+                                policy,
+                                clients,
+                                client_index,
+                            },
+                            tx.watch(&root.clients_modified).boxed(),
+                        ))
                     }
                     .boxed()
                 },
@@ -171,24 +349,28 @@ impl ClientState {
             log::info!("Partition assignment changed");
 
             self.partition_assignment = new_partition_assignment;
-            let partition_range = self.partition_assignment.range();
+            let partitions = self.partition_assignment.partitions();
 
             // Stop tasks for partitions we no longer own
-            self.partition_tasks = self.partition_tasks.split_off(&partition_range.start);
-            self.partition_tasks.split_off(&partition_range.end);
+            self.partition_tasks
+                .retain(|partition, _| partitions.binary_search(partition).is_ok());
 
             // Start tasks for partitions we now own
-            for partition in partition_range {
+            for partition in partitions {
+                let client_id = self.id;
                 let global = self.global.clone();
                 let root = self.root.clone();
                 let state_fn = self.state_fn.clone();
+                let config = self.config.clone();
                 self.partition_tasks.entry(partition).or_insert_with(|| {
-                    spawn_cancellable(|c| partition_task(global, root, partition, state_fn, c))
+                    spawn_cancellable(|c| {
+                        partition_task(client_id, global, root, partition, state_fn, config, c)
+                    })
                 });
             }
         }
 
-        Ok(())
+        Ok(clients_modified)
     }
 
     async fn gc_range(
@@ -215,7 +397,7 @@ impl ClientState {
     async fn gc(&mut self) -> Result<(), Error> {
         let gc_id = Uuid::new_v4();
         let current_ts = Timestamp::now().millis();
-        let gc_ts = current_ts - GC_AGE_MS;
+        let gc_age_ms = self.config.gc_age.as_millis() as i64;
 
         self.global
             .db()
@@ -223,11 +405,65 @@ impl ClientState {
                 &self.root,
                 |tx, &mut root| {
                     async move {
+                        // Operations only become idle once they have accrued their full budget
+                        let budget_policy = load_budget_policy(tx, &root.budget_policy).await?;
+                        let gc_ts = current_ts - (gc_age_ms + budget_policy.initial_ts_offset());
+
                         Self::gc_range(tx, gc_ts, root.operation_ts.subrange(gc_id..), false)
                             .await?;
                         Self::gc_range(tx, gc_ts, root.operation_ts.subrange(..gc_id), true)
                             .await?;
 
+                        // Remove the traces of operations which are past their retention period
+                        let trace_policy = load_value::<TracePolicy>(tx, &root.trace_policy, true)
+                            .await?
+                            .unwrap_or_default();
+                        let trace_gc_ts = current_ts - trace_policy.retention.as_millis() as i64;
+                        for (range, reverse) in [
+                            (root.trace_ts.subrange(gc_id..), false),
+                            (root.trace_ts.subrange(..gc_id), true),
+                        ] {
+                            gc_traces(tx, root, trace_gc_ts, range, reverse, GC_COUNT_PER_CLIENT)
+                                .await?;
+                        }
+
+                        // Remove shared blobs whose last references were released concurrently
+                        for (range, reverse) in [
+                            (root.blob_refs.subrange(gc_id..), false),
+                            (root.blob_refs.subrange(..gc_id), true),
+                        ] {
+                            gc_refs(tx, root, range, reverse, GC_COUNT_PER_CLIENT).await?;
+                        }
+
+                        // Remove streamed blobs which were abandoned before being published
+                        let staging_gc_ts = current_ts - STAGING_TIMEOUT.as_millis() as i64;
+                        for (range, reverse) in [
+                            (root.blob_staging.subrange(gc_id..), false),
+                            (root.blob_staging.subrange(..gc_id), true),
+                        ] {
+                            gc_staging(
+                                tx,
+                                root,
+                                staging_gc_ts,
+                                range,
+                                reverse,
+                                GC_COUNT_PER_CLIENT,
+                            )
+                            .await?;
+                        }
+
+                        // Remove changes from the change feed which are past their retention period
+                        let change_feed_policy =
+                            load_value::<ChangeFeedPolicy>(tx, &root.change_feed_policy, true)
+                                .await?
+                                .unwrap_or_default();
+                        let changes_gc_ts = Timestamp::now() - change_feed_policy.retention;
+                        gc_changes(tx, root, changes_gc_ts, GC_COUNT_PER_CLIENT).await?;
+
+                        // Remove idempotency keys which are past their retention period
+                        idempotency::gc_keys(tx, root, Timestamp::now(), GC_COUNT_PER_CLIENT)
+                            .await?;
+
                         Ok::<_, Error>(())
                     }
                     .boxed()
@@ -240,24 +476,34 @@ impl ClientState {
     }
 }
 
+#[tracing::instrument(name = "client", skip_all, fields(root = %root, name = %name))]
 pub async fn client_task(
     name: String,
     global: Arc<Global>,
     root: String,
     state_fn: StateFn,
+    config: Arc<ClientConfig>,
     mut cancellation: Cancellation,
 ) -> Result<(), Error> {
     let root = global.root(&root).await?;
     let client_id = id::new();
-    let mut client_state = ClientState::new(name, global, root, client_id, state_fn);
-    let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
-    let mut gc_interval = tokio::time::interval(GC_INTERVAL);
+    let mut interval = tokio::time::interval(config.heartbeat_interval);
+    let mut gc_interval = tokio::time::interval(config.gc_interval);
+    let mut client_state = ClientState::new(name, global, root, client_id, state_fn, config);
     log::info!("Starting client...");
+    client_state.init_partition_range().await?;
 
+    let mut draining = cancellation.draining().boxed().fuse();
+    let mut clients_modified = Fuse::terminated();
     loop {
         select! {
             _ = cancellation => break,
-            _ = interval.tick().fuse() => client_state.tick().await?,
+            _ = draining => {
+                client_state.drain(&mut cancellation).await;
+                break;
+            }
+            _ = interval.tick().fuse() => clients_modified = client_state.tick().await?.fuse(),
+            _ = clients_modified => clients_modified = client_state.tick().await?.fuse(),
             _ = gc_interval.tick().fuse() => client_state.gc().await?,
         }
     }
@@ -267,3 +513,139 @@ pub async fn client_task(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assignment(strategy: AssignmentStrategy, weights: &[u32]) -> PartitionAssignment {
+        PartitionAssignment {
+            partition_range: PartitionRange {
+                offset: 100,
+                count: 1000,
+            },
+            policy: AssignmentPolicy {
+                strategy,
+                pinned: Default::default(),
+            },
+            clients: weights
+                .iter()
+                .enumerate()
+                .map(|(i, &weight)| AssignedClient {
+                    id: Uuid::from_u128(0x1234_5678_9abc_def0 * (i as u128 + 1)),
+                    name: format!("client-{}", i),
+                    weight,
+                })
+                .collect(),
+            client_index: 0,
+        }
+    }
+
+    fn owners(assignment: &PartitionAssignment) -> Vec<Uuid> {
+        assignment
+            .all_partitions()
+            .map(|partition| assignment.clients[assignment.owner(partition).unwrap()].id)
+            .collect()
+    }
+
+    fn owned_counts(assignment: &PartitionAssignment) -> Vec<usize> {
+        let mut counts = vec![0; assignment.clients.len()];
+        for partition in assignment.all_partitions() {
+            counts[assignment.owner(partition).unwrap()] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn legacy_client_value_has_unit_weight() {
+        #[derive(Serialize)]
+        struct Legacy {
+            last_active_ts: Timestamp,
+            name: String,
+        }
+        let ts = Timestamp::from_millis(1_600_000_000_000);
+        let bytes = postcard::to_stdvec(&Legacy {
+            last_active_ts: ts,
+            name: "old".into(),
+        })
+        .unwrap();
+        let value = ClientValue::decode(&bytes).unwrap();
+        assert_eq!(value.last_active_ts, ts);
+        assert_eq!(value.name, "old");
+        assert_eq!(value.weight, 1);
+
+        let bytes = postcard::to_stdvec(&ClientValue {
+            last_active_ts: ts,
+            name: "new".into(),
+            weight: 3,
+        })
+        .unwrap();
+        assert_eq!(ClientValue::decode(&bytes).unwrap().weight, 3);
+        assert!(ClientValue::decode(&[]).is_none());
+    }
+
+    #[test]
+    fn contiguous_shares_follow_weights() {
+        let assignment = assignment(AssignmentStrategy::Contiguous, &[1, 2, 1]);
+        assert_eq!(owned_counts(&assignment), vec![250, 500, 250]);
+    }
+
+    #[test]
+    fn rendezvous_shares_follow_weights() {
+        let assignment = assignment(AssignmentStrategy::Rendezvous, &[1, 3]);
+        let counts = owned_counts(&assignment);
+        assert!(counts[1] > counts[0] * 2, "{:?}", counts);
+    }
+
+    #[test]
+    fn pinned_partitions_prefer_named_client() {
+        let mut assignment = assignment(AssignmentStrategy::Rendezvous, &[1, 1, 1]);
+        assignment.policy.pinned.insert(100, "client-2".into());
+        assignment.policy.pinned.insert(101, "missing".into());
+        assert_eq!(assignment.owner(100), Some(2));
+        assert_eq!(
+            assignment.owner(101),
+            assignment.rendezvous_owner(101),
+            "Pins to absent clients fall back to the strategy"
+        );
+    }
+
+    #[test]
+    fn rendezvous_assigns_each_partition_once() {
+        let mut assignment = assignment(AssignmentStrategy::Rendezvous, &[1, 1, 1]);
+        let mut all = Vec::new();
+        for client_index in 0..assignment.clients.len() {
+            assignment.client_index = client_index;
+            let partitions = assignment.partitions();
+            assert!(!partitions.is_empty());
+            all.extend(partitions);
+        }
+        all.sort_unstable();
+        assert_eq!(all, assignment.all_partitions().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn rendezvous_only_moves_partitions_of_departed_client() {
+        let before = assignment(AssignmentStrategy::Rendezvous, &[1, 1, 1, 1]);
+        let mut after = before.clone();
+        let departed = after.clients.remove(2).id;
+        for (old, new) in owners(&before).into_iter().zip(owners(&after)) {
+            if old != departed {
+                assert_eq!(old, new);
+            }
+        }
+    }
+
+    #[test]
+    fn contiguous_reassigns_on_membership_change() {
+        let before = assignment(AssignmentStrategy::Contiguous, &[1, 1, 1, 1]);
+        let mut after = before.clone();
+        after.clients.remove(2);
+        let moved = owners(&before)
+            .into_iter()
+            .zip(owners(&after))
+            .filter(|(old, new)| old != new)
+            .count();
+        assert!(moved > 250);
+    }
+}
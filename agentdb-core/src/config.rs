@@ -0,0 +1,164 @@
+use std::{ops::Range, time::Duration};
+
+use crate::{client::PartitionRange, DEFAULT_PARTITION_RANGE};
+
+/// Runtime configuration for an AgentDB client. The default configuration is
+/// suitable for most deployments, and individual settings can be overridden
+/// using the builder methods.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub(crate) heartbeat_interval: Duration,
+    pub(crate) gc_interval: Duration,
+    pub(crate) gc_age: Duration,
+    pub(crate) max_batch_size: usize,
+    pub(crate) max_concurrent_batches: usize,
+    pub(crate) rollup_chunk_size: usize,
+    pub(crate) max_poll_interval: Duration,
+    pub(crate) initial_partition_range: PartitionRange,
+    pub(crate) initial_retry_backoff: Duration,
+    pub(crate) weight: u32,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval: Duration::from_secs(5),
+            gc_interval: Duration::from_secs(10),
+            gc_age: Duration::from_secs(60 * 5),
+            max_batch_size: 100,
+            max_concurrent_batches: 4,
+            rollup_chunk_size: 1000,
+            max_poll_interval: Duration::from_secs(120),
+            initial_partition_range: DEFAULT_PARTITION_RANGE,
+            initial_retry_backoff: Duration::from_secs(1),
+            weight: 1,
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Configure how often the client sends a heartbeat. A client is considered
+    /// to have disconnected if it misses two heartbeats, so all clients connected
+    /// to the same root should use the same interval. Defaults to 5 seconds.
+    pub fn with_heartbeat_interval(mut self, heartbeat_interval: Duration) -> Self {
+        self.heartbeat_interval = heartbeat_interval;
+        self
+    }
+    /// Configure how often the client garbage collects old operations.
+    /// Defaults to 10 seconds.
+    pub fn with_gc_interval(mut self, gc_interval: Duration) -> Self {
+        self.gc_interval = gc_interval;
+        self
+    }
+    /// Configure how long an operation must have been idle before it is
+    /// garbage collected. Defaults to 5 minutes.
+    pub fn with_gc_age(mut self, gc_age: Duration) -> Self {
+        self.gc_age = gc_age;
+        self
+    }
+    /// Configure the maximum number of messages delivered to an agent in a
+    /// single call to the state function. Defaults to 100.
+    pub fn with_max_batch_size(mut self, max_batch_size: usize) -> Self {
+        self.max_batch_size = max_batch_size.max(1);
+        self
+    }
+    /// Configure the maximum number of batches processed concurrently within a
+    /// single partition. Each batch is delivered to a different agent in its own
+    /// transaction, so messages to the same agent are still processed in order.
+    /// Defaults to 4.
+    pub fn with_max_concurrent_batches(mut self, max_concurrent_batches: usize) -> Self {
+        self.max_concurrent_batches = max_concurrent_batches.max(1);
+        self
+    }
+    /// Configure the maximum number of messages moved within a partition in a
+    /// single transaction, when rolling up messages into batches or migrating
+    /// them during a re-partition. Larger backlogs are moved over several
+    /// transactions. Must be at most 65535. Defaults to 1000.
+    pub fn with_rollup_chunk_size(mut self, rollup_chunk_size: usize) -> Self {
+        self.rollup_chunk_size = rollup_chunk_size.clamp(1, u16::MAX.into());
+        self
+    }
+    /// Configure the maximum amount of time a partition will wait before checking
+    /// for new messages, in case a change notification was missed. Defaults to
+    /// 2 minutes.
+    pub fn with_max_poll_interval(mut self, max_poll_interval: Duration) -> Self {
+        self.max_poll_interval = max_poll_interval;
+        self
+    }
+    /// Configure the range of partitions used by a root which has never been
+    /// used before. This has no effect on existing roots: use
+    /// [crate::admin::change_partitions] to re-partition those. Defaults to
+    /// partitions `0..100`. Panics if the range is empty.
+    pub fn with_initial_partition_range(mut self, initial_partition_range: Range<u32>) -> Self {
+        assert!(
+            !initial_partition_range.is_empty(),
+            "Partition range must not be empty"
+        );
+        self.initial_partition_range = PartitionRange {
+            offset: initial_partition_range.start,
+            count: initial_partition_range.end - initial_partition_range.start,
+        };
+        self
+    }
+    /// Configure how long to wait before retrying an agent whose state function
+    /// returned an error. The delay doubles with each subsequent failure.
+    /// Defaults to 1 second.
+    pub fn with_initial_retry_backoff(mut self, initial_retry_backoff: Duration) -> Self {
+        self.initial_retry_backoff = initial_retry_backoff;
+        self
+    }
+    /// Configure the capacity of this client relative to the other clients
+    /// connected to the same root. Each client receives a share of the
+    /// partitions proportional to its weight. Defaults to 1.
+    pub fn with_weight(mut self, weight: u32) -> Self {
+        self.weight = weight.max(1);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_overrides_defaults() {
+        let config = ClientConfig::default()
+            .with_max_batch_size(10)
+            .with_initial_partition_range(5..15)
+            .with_initial_retry_backoff(Duration::from_millis(250));
+        assert_eq!(config.max_batch_size, 10);
+        assert_eq!(
+            config.initial_partition_range,
+            PartitionRange {
+                offset: 5,
+                count: 10
+            }
+        );
+        assert_eq!(config.initial_retry_backoff, Duration::from_millis(250));
+        assert_eq!(
+            config.heartbeat_interval,
+            ClientConfig::default().heartbeat_interval
+        );
+    }
+
+    #[test]
+    fn builder_clamps_limits() {
+        let config = ClientConfig::default()
+            .with_max_batch_size(0)
+            .with_max_concurrent_batches(0)
+            .with_rollup_chunk_size(0)
+            .with_weight(0);
+        assert_eq!(config.max_batch_size, 1);
+        assert_eq!(config.max_concurrent_batches, 1);
+        assert_eq!(config.rollup_chunk_size, 1);
+        assert_eq!(config.weight, 1);
+        let config = ClientConfig::default().with_rollup_chunk_size(usize::MAX);
+        assert_eq!(config.rollup_chunk_size, usize::from(u16::MAX));
+    }
+
+    #[test]
+    #[should_panic(expected = "Partition range must not be empty")]
+    fn empty_initial_partition_range_panics() {
+        let _ = ClientConfig::default().with_initial_partition_range(3..3);
+    }
+}
@@ -0,0 +1,89 @@
+use foundationdb::{KeySelector, RangeOption, Transaction};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    directories::{PartitionSpace, RootSpace},
+    error::Error,
+    partition::is_expired,
+    utils::save_value,
+    MessageHeader, Timestamp,
+};
+
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct DeadLetterFailure {
+    pub error: String,
+    pub attempts: u32,
+    pub first_attempt_ts: Timestamp,
+    pub dead_lettered_ts: Timestamp,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct DeadLetterValue {
+    #[serde(with = "encoded_header")]
+    pub header: MessageHeader,
+    pub failure: DeadLetterFailure,
+}
+
+// Stores the header using its versioned encoding, so that dead letters remain readable
+// when the header changes.
+mod encoded_header {
+    use serde::{de::Error as _, Deserialize, Deserializer, Serializer};
+
+    use crate::MessageHeader;
+
+    pub fn serialize<S: Serializer>(
+        header: &MessageHeader,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&header.encode())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<MessageHeader, D::Error> {
+        let bytes = <Vec<u8>>::deserialize(deserializer)?;
+        MessageHeader::decode(&bytes).map_err(|e| D::Error::custom(e.0))
+    }
+}
+
+// Move the recipient's batched messages in the given lane, up to and including `last_key`,
+// into the dead-letter queue. Messages which have expired are left to be dropped instead.
+// The message blobs are left in place so that the messages can be replayed.
+pub(crate) async fn move_to_dead_letter(
+    tx: &Transaction,
+    root: &RootSpace,
+    partition: &PartitionSpace,
+    lane: u8,
+    recipient_id: Uuid,
+    last_key: &[u8],
+    failure: &DeadLetterFailure,
+) -> Result<usize, Error> {
+    let mut recipient_range: RangeOption =
+        partition.batch.nested_range(&(lane, recipient_id)).into();
+    recipient_range.end = KeySelector::first_greater_than(last_key.to_vec());
+
+    let mut count = 0;
+    let mut msg_stream = tx.get_ranges(recipient_range, false);
+    while let Some(msgs) = msg_stream.try_next().await? {
+        for msg in msgs {
+            let header = MessageHeader::decode(msg.value())?;
+            if is_expired(&header, failure.dead_lettered_ts) {
+                continue;
+            }
+            let dead_letter_key = root.dead_letter.pack(&header.message_id);
+            save_value(
+                tx,
+                &dead_letter_key,
+                &DeadLetterValue {
+                    header,
+                    failure: failure.clone(),
+                },
+            );
+            tx.clear(msg.key());
+            count += 1;
+        }
+    }
+    Ok(count)
+}
@@ -11,7 +11,7 @@ use uuid::Uuid;
 
 pub const AGENTDB_LAYER: &[u8] = b"agentdb";
 
-use crate::{Error, Timestamp, TypedSubspace};
+use crate::{encryption::KeyProvider, Error, Timestamp, TypedSubspace};
 
 /// AgentDB must be initialized with a connection to FoundationDB, and
 /// a FoundationDB directory layer for storing AgentDB roots.
@@ -19,6 +19,7 @@ pub struct Global {
     pub(crate) db: Arc<Database>,
     pub(crate) dir: DirectoryLayer,
     pub(crate) roots: RwLock<HashMap<String, Arc<RootSpace>>>,
+    pub(crate) key_provider: RwLock<Option<Arc<dyn KeyProvider>>>,
 }
 
 impl Debug for Global {
@@ -38,6 +39,7 @@ impl Global {
             db,
             dir,
             roots: Default::default(),
+            key_provider: Default::default(),
         })
     }
     /// Construct a global instance with a database connection and the default directory layer.
@@ -60,6 +62,15 @@ impl Global {
     pub fn dir(&self) -> &DirectoryLayer {
         &self.dir
     }
+    /// Install a key provider, so that blobs stored from now on are encrypted
+    /// at rest. Every client connected to the same roots must be able to obtain
+    /// the same keys.
+    pub fn set_key_provider(&self, key_provider: Arc<dyn KeyProvider>) {
+        *self.key_provider.write() = Some(key_provider);
+    }
+    pub(crate) fn key_provider(&self) -> Option<Arc<dyn KeyProvider>> {
+        read_rwlock(&self.key_provider, Clone::clone)
+    }
     pub(crate) async fn root(&self, root: &str) -> Result<Arc<RootSpace>, Error> {
         Ok(
             if let Some(root_space) = read_rwlock(&self.roots, |roots| roots.get(root).cloned()) {
@@ -77,15 +88,37 @@ pub(crate) struct RootSpace {
     pub(crate) root: String,
     pub(crate) user_dir: DirectoryOutput,
     pub(crate) clients: TypedSubspace<Uuid>,
+    pub(crate) clients_modified: Vec<u8>,
     pub(crate) agents: TypedSubspace<Uuid>,
     pub(crate) agent_counts: TypedSubspace<u32>,
+    pub(crate) expired_counts: TypedSubspace<u32>,
     pub(crate) blob_modified: TypedSubspace<Uuid>,
+    pub(crate) blob_refs: TypedSubspace<Uuid>,
+    pub(crate) blob_staging: TypedSubspace<Uuid>,
+    pub(crate) scheduled: TypedSubspace<Uuid>,
     pub(crate) blob_data: TypedSubspace<(Uuid, u32)>,
     pub(crate) partition_range_send: Vec<u8>,
     pub(crate) partition_range_recv: Vec<u8>,
+    pub(crate) budget_policy: Vec<u8>,
+    pub(crate) assignment_policy: Vec<u8>,
     pub(crate) partition_dir: DirectoryOutput,
     pub(crate) partitions: RwLock<HashMap<u32, Arc<PartitionSpace>>>,
     pub(crate) operation_ts: TypedSubspace<Uuid>,
+    pub(crate) dead_letter: TypedSubspace<Uuid>,
+    pub(crate) dead_letter_policy: Vec<u8>,
+    pub(crate) trace: TypedSubspace<(Uuid, Versionstamp)>,
+    pub(crate) trace_ts: TypedSubspace<Uuid>,
+    pub(crate) trace_policy: Vec<u8>,
+    pub(crate) history: TypedSubspace<(Uuid, Versionstamp, u32)>,
+    pub(crate) history_index: TypedSubspace<(Uuid, Versionstamp)>,
+    pub(crate) history_policy: Vec<u8>,
+    pub(crate) changes: TypedSubspace<Versionstamp>,
+    pub(crate) changes_modified: Vec<u8>,
+    pub(crate) change_feed_policy: Vec<u8>,
+    pub(crate) idempotency: TypedSubspace<String>,
+    pub(crate) idempotency_expiry: TypedSubspace<(Timestamp, String)>,
+    pub(crate) idempotency_policy: Vec<u8>,
+    pub(crate) compression_policy: Vec<u8>,
 }
 
 impl RootSpace {
@@ -106,34 +139,86 @@ impl RootSpace {
                             .await
                             .map_err(Error::from_dir)?;
                         let clients = TypedSubspace::open_or_create(tx, &dir, "clients").await?;
+                        let clients_modified = dir.pack(&"clients_modified".as_bytes());
                         let agents = TypedSubspace::open_or_create(tx, &dir, "agents").await?;
                         let agent_counts =
                             TypedSubspace::open_or_create(tx, &dir, "agent_counts").await?;
+                        let expired_counts =
+                            TypedSubspace::open_or_create(tx, &dir, "expired_counts").await?;
                         let blob_modified =
                             TypedSubspace::open_or_create(tx, &dir, "blob_modified").await?;
+                        let blob_refs =
+                            TypedSubspace::open_or_create(tx, &dir, "blob_refs").await?;
+                        let blob_staging =
+                            TypedSubspace::open_or_create(tx, &dir, "blob_staging").await?;
+                        let scheduled =
+                            TypedSubspace::open_or_create(tx, &dir, "scheduled").await?;
                         let blob_data =
                             TypedSubspace::open_or_create(tx, &dir, "blob_data").await?;
                         let partition_range_send = dir.pack(&"partition_range_send".as_bytes());
                         let partition_range_recv = dir.pack(&"partition_range_recv".as_bytes());
+                        let budget_policy = dir.pack(&"budget_policy".as_bytes());
+                        let assignment_policy = dir.pack(&"assignment_policy".as_bytes());
                         let partition_dir = dir
                             .create_or_open(tx, vec!["partition".into()], None, None)
                             .await
                             .map_err(Error::from_dir)?;
                         let operation_ts =
                             TypedSubspace::open_or_create(tx, &dir, "operation_ts").await?;
+                        let dead_letter =
+                            TypedSubspace::open_or_create(tx, &dir, "dead_letter").await?;
+                        let dead_letter_policy = dir.pack(&"dead_letter_policy".as_bytes());
+                        let trace = TypedSubspace::open_or_create(tx, &dir, "trace").await?;
+                        let trace_ts = TypedSubspace::open_or_create(tx, &dir, "trace_ts").await?;
+                        let trace_policy = dir.pack(&"trace_policy".as_bytes());
+                        let history = TypedSubspace::open_or_create(tx, &dir, "history").await?;
+                        let history_index =
+                            TypedSubspace::open_or_create(tx, &dir, "history_index").await?;
+                        let history_policy = dir.pack(&"history_policy".as_bytes());
+                        let changes = TypedSubspace::open_or_create(tx, &dir, "changes").await?;
+                        let changes_modified = dir.pack(&"changes_modified".as_bytes());
+                        let change_feed_policy = dir.pack(&"change_feed_policy".as_bytes());
+                        let idempotency =
+                            TypedSubspace::open_or_create(tx, &dir, "idempotency").await?;
+                        let idempotency_expiry =
+                            TypedSubspace::open_or_create(tx, &dir, "idempotency_expiry").await?;
+                        let idempotency_policy = dir.pack(&"idempotency_policy".as_bytes());
+                        let compression_policy = dir.pack(&"compression_policy".as_bytes());
                         Ok(Self {
                             root: root.into(),
                             user_dir,
                             clients,
+                            clients_modified,
                             agents,
                             agent_counts,
+                            expired_counts,
                             blob_modified,
+                            blob_refs,
+                            blob_staging,
+                            scheduled,
                             blob_data,
                             partition_range_send,
                             partition_range_recv,
+                            budget_policy,
+                            assignment_policy,
                             partition_dir,
                             partitions: Default::default(),
                             operation_ts,
+                            dead_letter,
+                            dead_letter_policy,
+                            trace,
+                            trace_ts,
+                            trace_policy,
+                            history,
+                            history_index,
+                            history_policy,
+                            changes,
+                            changes_modified,
+                            change_feed_policy,
+                            idempotency,
+                            idempotency_expiry,
+                            idempotency_policy,
+                            compression_policy,
                         })
                     }
                     .boxed()
@@ -167,9 +252,11 @@ impl RootSpace {
 pub(crate) struct PartitionSpace {
     pub(crate) partition: u32,
     pub(crate) modified: Vec<u8>,
-    pub(crate) message: TypedSubspace<(Timestamp, Versionstamp, u32)>,
-    pub(crate) batch: TypedSubspace<(Uuid, Versionstamp)>,
+    pub(crate) owner: Vec<u8>,
+    pub(crate) message: TypedSubspace<(u8, Timestamp, Versionstamp, u32)>,
+    pub(crate) batch: TypedSubspace<(u8, Uuid, Versionstamp)>,
     pub(crate) agent_retry: TypedSubspace<Uuid>,
+    pub(crate) agent_batch_size: TypedSubspace<Uuid>,
 }
 
 impl PartitionSpace {
@@ -186,16 +273,21 @@ impl PartitionSpace {
                             .await
                             .map_err(Error::from_dir)?;
                         let modified = dir.pack(&"modified".as_bytes());
+                        let owner = dir.pack(&"owner".as_bytes());
                         let message = TypedSubspace::open_or_create(tx, &dir, "message").await?;
                         let batch = TypedSubspace::open_or_create(tx, &dir, "batch").await?;
                         let agent_retry =
                             TypedSubspace::open_or_create(tx, &dir, "agent_retry").await?;
+                        let agent_batch_size =
+                            TypedSubspace::open_or_create(tx, &dir, "agent_batch_size").await?;
                         Ok(Self {
                             partition,
                             modified,
+                            owner,
                             message,
                             batch,
                             agent_retry,
+                            agent_batch_size,
                         })
                     }
                     .boxed()
@@ -0,0 +1,110 @@
+//! Encryption of blobs at rest.
+//!
+//! When a [KeyProvider] is installed on the [crate::Global] instance, every stripe
+//! of a newly stored blob is encrypted with ChaCha20-Poly1305 using the provider's
+//! current key for the root. The ID of that key is recorded alongside the blob, so
+//! that the blob can still be decrypted after the current key is rotated. Use
+//! [crate::admin::reencrypt_blobs] to re-encrypt existing blobs with the current key.
+
+use anyhow::anyhow;
+use chacha20poly1305::{
+    aead::{Aead, NewAead, Payload},
+    ChaCha20Poly1305, Key as CipherKey, Nonce,
+};
+use uuid::Uuid;
+
+use crate::error::Error;
+
+const NONCE_SIZE: usize = 12;
+
+/// Identifies a key which can be obtained from a [KeyProvider].
+pub type KeyId = u32;
+
+/// A 256-bit encryption key.
+pub type Key = [u8; 32];
+
+/// Provides the keys used to encrypt blobs at rest. Implementations are
+/// expected to cache keys, as they may be requested for every blob which
+/// is loaded or stored.
+pub trait KeyProvider: Send + Sync + 'static {
+    /// The ID of the key which should be used to encrypt new blobs stored
+    /// within the given root, or `None` if new blobs should not be encrypted.
+    fn current_key_id(&self, root: &str) -> Option<KeyId>;
+    /// Obtain the key with the given ID. Keys must remain available for as
+    /// long as any blob encrypted with them may exist.
+    fn key(&self, key_id: KeyId) -> Result<Key, Error>;
+}
+
+// Binds each stripe to its position within a blob, so that stripes cannot be
+// swapped between blobs or re-ordered without detection.
+fn associated_data(blob_id: Uuid, index: u32) -> [u8; 20] {
+    let mut aad = [0; 20];
+    aad[..16].copy_from_slice(blob_id.as_bytes());
+    aad[16..].copy_from_slice(&index.to_le_bytes());
+    aad
+}
+
+pub(crate) fn encrypt_stripe(key: &Key, blob_id: Uuid, index: u32, data: &[u8]) -> Vec<u8> {
+    let cipher = ChaCha20Poly1305::new(CipherKey::from_slice(key));
+    let nonce: [u8; NONCE_SIZE] = rand::random();
+    let aad = associated_data(blob_id, index);
+    let ciphertext = cipher
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: data,
+                aad: &aad,
+            },
+        )
+        .expect("Infallible encryption");
+    let mut res = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+    res.extend_from_slice(&nonce);
+    res.extend_from_slice(&ciphertext);
+    res
+}
+
+pub(crate) fn decrypt_stripe(
+    key: &Key,
+    blob_id: Uuid,
+    index: u32,
+    data: &[u8],
+) -> Result<Vec<u8>, Error> {
+    if data.len() < NONCE_SIZE {
+        return Err(Error(anyhow!("Corrupt stripe in blob {}", blob_id)));
+    }
+    let cipher = ChaCha20Poly1305::new(CipherKey::from_slice(key));
+    let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+    let aad = associated_data(blob_id, index);
+    cipher
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: &aad,
+            },
+        )
+        .map_err(|_| Error(anyhow!("Failed to decrypt blob {}", blob_id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stripes_are_bound_to_their_position() {
+        let key = [1; 32];
+        let blob_id = Uuid::new_v4();
+        let data = b"agent state";
+        let stripe = encrypt_stripe(&key, blob_id, 3, data);
+        assert_ne!(&stripe[NONCE_SIZE..], &data[..]);
+        assert_eq!(decrypt_stripe(&key, blob_id, 3, &stripe).unwrap(), data);
+
+        // Fresh nonces mean the same data never encrypts the same way twice
+        assert_ne!(encrypt_stripe(&key, blob_id, 3, data), stripe);
+
+        assert!(decrypt_stripe(&key, blob_id, 4, &stripe).is_err());
+        assert!(decrypt_stripe(&key, Uuid::new_v4(), 3, &stripe).is_err());
+        assert!(decrypt_stripe(&[2; 32], blob_id, 3, &stripe).is_err());
+        assert!(decrypt_stripe(&key, blob_id, 3, &stripe[..NONCE_SIZE - 1]).is_err());
+    }
+}
@@ -0,0 +1,322 @@
+//! Contains functions for inspecting previous versions of an agent's state.
+//!
+//! Versions are only retained for roots with a [crate::policy::HistoryPolicy]
+//! other than `Disabled`. Each version is identified by the versionstamp of the
+//! transaction which wrote it, so versions are ordered by commit order.
+//!
+//! Retained versions are encrypted with the current key for the root, if a
+//! [crate::encryption::KeyProvider] is installed. They are not re-encrypted by
+//! [crate::admin::reencrypt_blobs], so keys must remain available until every
+//! version encrypted with them has been pruned or cleared.
+
+use std::borrow::Cow;
+
+use foundationdb::{
+    options::{MutationType, StreamingMode},
+    tuple::Versionstamp,
+    RangeOption, Transaction,
+};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    blob::{self, BLOB_STRIPE_SIZE},
+    directories::{Global, RootSpace},
+    encryption::{decrypt_stripe, encrypt_stripe, KeyId},
+    error::Error,
+    id,
+    policy::HistoryPolicy,
+    utils::{load_value, next_key},
+    Timestamp,
+};
+
+// Only prune this many expired versions at a time, so that an agent with a long
+// history does not make a single transaction too large.
+const PRUNE_COUNT_PER_VERSION: usize = 16;
+
+#[derive(Serialize, Deserialize)]
+struct HistoryValue {
+    ts: Timestamp,
+    exists: bool,
+    // Encrypted chunks are bound to this ID rather than to the version, since the
+    // versionstamp is not known until the transaction commits.
+    data_id: Uuid,
+    key_id: Option<KeyId>,
+}
+
+/// Information about a single retained version of an agent's state.
+#[derive(Debug, Copy, Clone)]
+pub struct StateVersion {
+    version: Versionstamp,
+    ts: Timestamp,
+    exists: bool,
+}
+
+impl StateVersion {
+    /// The version of the agent's state. Versions are ordered by the time
+    /// they were committed.
+    pub fn version(&self) -> Versionstamp {
+        self.version
+    }
+    /// The time when this version was written.
+    pub fn ts(&self) -> Timestamp {
+        self.ts
+    }
+    /// Whether the agent existed in this version. If `false`, this version
+    /// records the destruction of the agent.
+    pub fn exists(&self) -> bool {
+        self.exists
+    }
+}
+
+// Clears every retained version of an agent older than `version`, inclusive.
+fn clear_versions_until(tx: &Transaction, root: &RootSpace, agent_id: Uuid, version: Versionstamp) {
+    let index_begin = root.history_index.nested_range(&(agent_id,)).0;
+    let index_end = next_key(&root.history_index.pack(&(agent_id, version)));
+    tx.clear_range(&index_begin, &index_end);
+
+    let data_begin = root.history.nested_range(&(agent_id,)).0;
+    let data_end = root.history.nested_range(&(agent_id, version)).1;
+    tx.clear_range(&data_begin, &data_end);
+}
+
+async fn prune_versions(
+    tx: &Transaction,
+    root: &RootSpace,
+    agent_id: Uuid,
+    policy: HistoryPolicy,
+) -> Result<(), Error> {
+    let range = root.history_index.nested_range(&(agent_id,));
+    match policy {
+        HistoryPolicy::Disabled => {}
+        HistoryPolicy::KeepVersions(count) => {
+            // Find the newest version which no longer fits, accounting for the
+            // version being written.
+            let keep = count.max(1) as usize - 1;
+            let mut range: RangeOption = range.into();
+            range.reverse = true;
+            range.limit = Some(keep + 1);
+            range.mode = StreamingMode::WantAll;
+            let values = tx.get_range(&range, 0, true).await?;
+            if let Some(value) = values.iter().nth(keep) {
+                let (_, version) = root.history_index.unpack(value.key())?;
+                clear_versions_until(tx, root, agent_id, version);
+            }
+        }
+        HistoryPolicy::KeepFor(duration) => {
+            let expired_ts = Timestamp::now() - duration;
+            let mut range: RangeOption = range.into();
+            range.limit = Some(PRUNE_COUNT_PER_VERSION);
+            range.mode = StreamingMode::WantAll;
+            let values = tx.get_range(&range, 0, true).await?;
+            let mut newest_expired = None;
+            for value in values.iter() {
+                let history_value: HistoryValue = postcard::from_bytes(value.value())?;
+                if history_value.ts >= expired_ts {
+                    break;
+                }
+                newest_expired = Some(root.history_index.unpack(value.key())?.1);
+            }
+            if let Some(version) = newest_expired {
+                clear_versions_until(tx, root, agent_id, version);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Records a new version of an agent's state according to the root's history
+// policy, pruning any versions which should no longer be retained. `state` is
+// `None` if the agent was destroyed.
+pub(crate) async fn record_version(
+    tx: &Transaction,
+    global: &Global,
+    root: &RootSpace,
+    agent_id: Uuid,
+    state: Option<&[u8]>,
+) -> Result<(), Error> {
+    let policy = load_value::<HistoryPolicy>(tx, &root.history_policy, true)
+        .await?
+        .unwrap_or_default();
+    if policy == HistoryPolicy::Disabled {
+        return Ok(());
+    }
+    prune_versions(tx, root, agent_id, policy).await?;
+
+    let version = Versionstamp::incomplete(0);
+    let key = blob::current_key(global, root)?;
+    let value = HistoryValue {
+        ts: Timestamp::now(),
+        exists: state.is_some(),
+        data_id: id::new(),
+        key_id: key.map(|(key_id, _)| key_id),
+    };
+    tx.atomic_op(
+        &root.history_index.pack(&(agent_id, version)),
+        &postcard::to_stdvec(&value).expect("Infallible serialization"),
+        MutationType::SetVersionstampedKey,
+    );
+    for (index, chunk) in state
+        .unwrap_or_default()
+        .chunks(BLOB_STRIPE_SIZE)
+        .enumerate()
+    {
+        let index = index as u32;
+        let chunk = if let Some((_, key)) = &key {
+            Cow::Owned(encrypt_stripe(key, value.data_id, index, chunk))
+        } else {
+            Cow::Borrowed(chunk)
+        };
+        let chunk_key = root.history.pack(&(agent_id, version, index));
+        tx.atomic_op(&chunk_key, &chunk, MutationType::SetVersionstampedKey);
+    }
+    Ok(())
+}
+
+pub(crate) async fn list_internal(
+    tx: &Transaction,
+    root: &RootSpace,
+    agent_id: Uuid,
+    snapshot: bool,
+) -> Result<Vec<StateVersion>, Error> {
+    let mut range: RangeOption = root.history_index.nested_range(&(agent_id,)).into();
+    range.mode = StreamingMode::WantAll;
+    let mut stream = tx.get_ranges(range, snapshot);
+    let mut res = Vec::new();
+    while let Some(values) = stream.try_next().await? {
+        for value in values {
+            let (_, version) = root.history_index.unpack(value.key())?;
+            let history_value: HistoryValue = postcard::from_bytes(value.value())?;
+            res.push(StateVersion {
+                version,
+                ts: history_value.ts,
+                exists: history_value.exists,
+            });
+        }
+    }
+    Ok(res)
+}
+
+pub(crate) async fn load_at_internal(
+    tx: &Transaction,
+    global: &Global,
+    root: &RootSpace,
+    agent_id: Uuid,
+    version: Versionstamp,
+    snapshot: bool,
+) -> Result<Option<Vec<u8>>, Error> {
+    let index_key = root.history_index.pack(&(agent_id, version));
+    let history_value = match load_value::<HistoryValue>(tx, &index_key, snapshot).await? {
+        Some(history_value) if history_value.exists => history_value,
+        _ => return Ok(None),
+    };
+    let key = history_value
+        .key_id
+        .map(|key_id| blob::load_key(global, key_id))
+        .transpose()?;
+
+    let range = root.history.nested_range(&(agent_id, version)).into();
+    let mut stream = tx.get_ranges(range, snapshot);
+    let mut res = Vec::new();
+    let mut index = 0;
+    while let Some(values) = stream.try_next().await? {
+        for value in values {
+            if let Some(key) = &key {
+                res.extend(decrypt_stripe(
+                    key,
+                    history_value.data_id,
+                    index,
+                    value.value(),
+                )?);
+            } else {
+                res.extend_from_slice(value.value());
+            }
+            index += 1;
+        }
+    }
+    Ok(Some(res))
+}
+
+/// List the retained versions of an agent's state, from oldest to newest.
+pub async fn list(
+    tx: &Transaction,
+    global: &Global,
+    root: &str,
+    agent_id: Uuid,
+    snapshot: bool,
+) -> Result<Vec<StateVersion>, Error> {
+    let root = global.root(root).await?;
+    list_internal(tx, &root, agent_id, snapshot).await
+}
+
+/// Load a previous version of an agent's state. Returns `None` if the version
+/// is not retained, or if the agent did not exist in that version.
+pub async fn load_at(
+    tx: &Transaction,
+    global: &Global,
+    root: &str,
+    agent_id: Uuid,
+    version: Versionstamp,
+    snapshot: bool,
+) -> Result<Option<Vec<u8>>, Error> {
+    let root = global.root(root).await?;
+    load_at_internal(tx, global, &root, agent_id, version, snapshot).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        admin,
+        test_utils::{commit, TestRoot},
+    };
+
+    #[tokio::test]
+    #[ignore]
+    async fn history_keeps_latest_versions() -> Result<(), Error> {
+        let root = TestRoot::new().await;
+        admin::set_history_policy(&root.global, &root.name, HistoryPolicy::KeepVersions(2)).await?;
+        let agent_id = Uuid::new_v4();
+        let large_state = vec![7; BLOB_STRIPE_SIZE + 1];
+        for state in &[Some(&b"first"[..]), Some(&large_state[..]), None] {
+            let tx = root.tx();
+            record_version(&tx, &root.global, &root.space, agent_id, *state).await?;
+            commit(tx).await?;
+        }
+
+        let tx = root.tx();
+        let versions = list_internal(&tx, &root.space, agent_id, true).await?;
+        assert_eq!(versions.len(), 2);
+        assert!(versions[0].exists());
+        assert!(!versions[1].exists());
+        assert!(versions[0].version() < versions[1].version());
+        assert_eq!(
+            load_at_internal(
+                &tx,
+                &root.global,
+                &root.space,
+                agent_id,
+                versions[0].version(),
+                true
+            )
+            .await?,
+            Some(large_state)
+        );
+        assert_eq!(
+            load_at_internal(
+                &tx,
+                &root.global,
+                &root.space,
+                agent_id,
+                versions[1].version(),
+                true
+            )
+            .await?,
+            None
+        );
+
+        root.cleanup().await;
+        Ok(())
+    }
+}
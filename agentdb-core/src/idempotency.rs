@@ -0,0 +1,226 @@
+//! Contains functions for deduplicating externally sent messages.
+//!
+//! A caller which may retry after an unknown commit result can attach an
+//! idempotency key to its transaction. The key is recorded in the root along
+//! with the versionstamp of the commit, and is retained according to the root's
+//! [crate::policy::IdempotencyPolicy]. A later transaction with the same key can
+//! then detect that the original transaction was committed and skip its work.
+
+use foundationdb::{
+    options::{MutationType, StreamingMode},
+    tuple::{pack_with_versionstamp, unpack, Versionstamp},
+    RangeOption, Transaction,
+};
+use uuid::Uuid;
+
+use crate::{
+    directories::{Global, RootSpace},
+    error::Error,
+    policy::IdempotencyPolicy,
+    utils::load_value,
+    Timestamp,
+};
+
+/// Information about the commit which first used an idempotency key.
+#[derive(Debug, Clone)]
+pub struct CommitInfo {
+    versionstamp: Versionstamp,
+    operation_id: Uuid,
+    committed_ts: Timestamp,
+}
+
+impl CommitInfo {
+    /// The versionstamp of the original commit.
+    pub fn versionstamp(&self) -> &Versionstamp {
+        &self.versionstamp
+    }
+    /// The ID of the operation committed by the original transaction.
+    pub fn operation_id(&self) -> Uuid {
+        self.operation_id
+    }
+    /// When the original transaction was committed.
+    pub fn committed_ts(&self) -> Timestamp {
+        self.committed_ts
+    }
+}
+
+/// Check whether an idempotency key has already been used within a root. Returns
+/// information about the original commit if so. Concurrent transactions using the
+/// same key will conflict.
+pub async fn check_key(
+    tx: &Transaction,
+    global: &Global,
+    root: &str,
+    key: &str,
+) -> Result<Option<CommitInfo>, Error> {
+    let root = global.root(root).await?;
+    let value = if let Some(value) = tx.get(&root.idempotency.pack(&key.into()), false).await? {
+        value
+    } else {
+        return Ok(None);
+    };
+    let (versionstamp, operation_id, committed_ts, expires_at) =
+        unpack::<(Versionstamp, Uuid, Timestamp, Timestamp)>(&value)?;
+
+    // The key may not have been cleaned up yet
+    if expires_at <= Timestamp::now() {
+        return Ok(None);
+    }
+    Ok(Some(CommitInfo {
+        versionstamp,
+        operation_id,
+        committed_ts,
+    }))
+}
+
+/// Record that an idempotency key was used by this transaction, on behalf of the
+/// given operation. The key will be retained according to the root's policy.
+pub async fn record_key(
+    tx: &Transaction,
+    global: &Global,
+    root: &str,
+    key: &str,
+    operation_id: Uuid,
+    user_version: u16,
+) -> Result<(), Error> {
+    let root = global.root(root).await?;
+    let policy = load_value::<IdempotencyPolicy>(tx, &root.idempotency_policy, true)
+        .await?
+        .unwrap_or_default();
+    let committed_ts = Timestamp::now();
+    let expires_at = committed_ts + policy.retention;
+    tx.atomic_op(
+        &root.idempotency.pack(&key.into()),
+        &pack_with_versionstamp(&(
+            Versionstamp::incomplete(user_version),
+            operation_id,
+            committed_ts,
+            expires_at,
+        )),
+        MutationType::SetVersionstampedValue,
+    );
+    tx.set(
+        &root.idempotency_expiry.pack(&(expires_at, key.into())),
+        &[],
+    );
+    Ok(())
+}
+
+// Removes idempotency keys which expired before `gc_ts`.
+pub(crate) async fn gc_keys(
+    tx: &Transaction,
+    root: &RootSpace,
+    gc_ts: Timestamp,
+    limit: usize,
+) -> Result<(), Error> {
+    let mut range: RangeOption = root.idempotency_expiry.nested_range2(&(), &(gc_ts,)).into();
+    range.limit = Some(limit);
+    range.mode = StreamingMode::WantAll;
+    let values = tx.get_range(&range, 0, true).await?;
+    for value in values.iter() {
+        let (expires_at, key) = root.idempotency_expiry.unpack(value.key())?;
+        tx.clear(value.key());
+
+        // The key may have been used again since this entry was written
+        let key = root.idempotency.pack(&key);
+        if let Some(value) = tx.get(&key, false).await? {
+            let (_, _, _, current_expires_at) =
+                unpack::<(Versionstamp, Uuid, Timestamp, Timestamp)>(&value)?;
+            if current_expires_at == expires_at {
+                tx.clear(&key);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        admin,
+        test_utils::{commit, TestRoot},
+    };
+
+    #[tokio::test]
+    #[ignore]
+    async fn repeated_key_reports_original_commit() -> Result<(), Error> {
+        let root = TestRoot::new().await;
+        let operation_id = Uuid::new_v4();
+
+        let tx = root.tx();
+        assert!(check_key(&tx, &root.global, &root.name, "command-1")
+            .await?
+            .is_none());
+        record_key(&tx, &root.global, &root.name, "command-1", operation_id, 0).await?;
+        commit(tx).await?;
+
+        let tx = root.tx();
+        let commit_info = check_key(&tx, &root.global, &root.name, "command-1")
+            .await?
+            .expect("Key was not recorded");
+        assert_eq!(commit_info.operation_id(), operation_id);
+        assert!(commit_info.versionstamp().is_complete());
+        assert!(check_key(&tx, &root.global, &root.name, "command-2")
+            .await?
+            .is_none());
+
+        root.cleanup().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn expired_keys_are_ignored_and_removed() -> Result<(), Error> {
+        let root = TestRoot::new().await;
+        admin::set_idempotency_policy(
+            &root.global,
+            &root.name,
+            IdempotencyPolicy {
+                retention: Duration::from_secs(0),
+            },
+        )
+        .await?;
+
+        let tx = root.tx();
+        record_key(&tx, &root.global, &root.name, "expired", Uuid::new_v4(), 0).await?;
+        record_key(&tx, &root.global, &root.name, "reused", Uuid::new_v4(), 0).await?;
+        commit(tx).await?;
+
+        // Using a key again after it expires records it with a new expiry
+        admin::set_idempotency_policy(&root.global, &root.name, IdempotencyPolicy::default())
+            .await?;
+        let tx = root.tx();
+        assert!(check_key(&tx, &root.global, &root.name, "reused")
+            .await?
+            .is_none());
+        let operation_id = Uuid::new_v4();
+        record_key(&tx, &root.global, &root.name, "reused", operation_id, 0).await?;
+        commit(tx).await?;
+
+        let tx = root.tx();
+        gc_keys(
+            &tx,
+            &root.space,
+            Timestamp::now() + Duration::from_secs(1),
+            10,
+        )
+        .await?;
+        commit(tx).await?;
+
+        let tx = root.tx();
+        assert!(tx
+            .get(&root.space.idempotency.pack(&"expired".into()), true)
+            .await?
+            .is_none());
+        let commit_info = check_key(&tx, &root.global, &root.name, "reused")
+            .await?
+            .expect("Reused key was removed");
+        assert_eq!(commit_info.operation_id(), operation_id);
+
+        root.cleanup().await;
+        Ok(())
+    }
+}
@@ -0,0 +1,158 @@
+use foundationdb::{FdbError, Transaction};
+use futures::{future::BoxFuture, FutureExt};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    client::ClientValue,
+    directories::{PartitionSpace, RootSpace},
+    error::Error,
+    utils::{load_value, save_value},
+    Timestamp,
+};
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct PartitionLease {
+    pub client_id: Uuid,
+    pub acquired_ts: Timestamp,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Client {client_id} no longer holds the lease on partition {partition}")]
+pub(crate) struct LeaseLost {
+    pub client_id: Uuid,
+    pub partition: u32,
+}
+
+// Fails with `LeaseLost` unless we hold the lease on the partition. The read is
+// not a snapshot read, so if another client takes over the lease before this
+// transaction commits, the transaction will conflict and be retried.
+pub(crate) async fn check_lease(
+    tx: &Transaction,
+    partition: &PartitionSpace,
+    client_id: Uuid,
+) -> Result<(), Error> {
+    match load_value::<PartitionLease>(tx, &partition.owner, false).await? {
+        Some(lease) if lease.client_id == client_id => Ok(()),
+        _ => Err(Error(
+            LeaseLost {
+                client_id,
+                partition: partition.partition,
+            }
+            .into(),
+        )),
+    }
+}
+
+// Attempts to acquire the lease on the partition. A lease can be taken over
+// once the client holding it has released it or stopped sending heartbeats.
+// If the lease is unavailable, returns a future which resolves when the lease
+// changes hands.
+pub(crate) async fn try_acquire_lease(
+    tx: &Transaction,
+    root: &RootSpace,
+    partition: &PartitionSpace,
+    client_id: Uuid,
+    expired_ts: Timestamp,
+) -> Result<Option<BoxFuture<'static, Result<(), FdbError>>>, Error> {
+    if let Some(lease) = load_value::<PartitionLease>(tx, &partition.owner, false).await? {
+        if lease.client_id == client_id {
+            return Ok(None);
+        }
+        let owner_key = root.clients.pack(&lease.client_id);
+        let owner = tx.get(&owner_key, false).await?;
+        if let Some(owner) = owner.as_deref().and_then(ClientValue::decode) {
+            if owner.last_active_ts >= expired_ts {
+                return Ok(Some(tx.watch(&partition.owner).boxed()));
+            }
+        }
+    }
+    save_value(
+        tx,
+        &partition.owner,
+        &PartitionLease {
+            client_id,
+            acquired_ts: Timestamp::now(),
+        },
+    );
+    Ok(None)
+}
+
+pub(crate) async fn release_lease(
+    tx: &Transaction,
+    partition: &PartitionSpace,
+    client_id: Uuid,
+) -> Result<(), FdbError> {
+    if let Some(lease) = load_value::<PartitionLease>(tx, &partition.owner, false).await? {
+        if lease.client_id == client_id {
+            tx.clear(&partition.owner);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{commit, TestRoot};
+
+    #[tokio::test]
+    #[ignore]
+    async fn lease_is_fenced_and_taken_over() -> Result<(), Error> {
+        let root = TestRoot::new().await;
+        let partition = root.partition(0).await;
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let tx = root.tx();
+        assert!(
+            try_acquire_lease(&tx, &root.space, &partition, a, Timestamp::now())
+                .await?
+                .is_none()
+        );
+        save_value(
+            &tx,
+            &root.space.clients.pack(&a),
+            &ClientValue {
+                last_active_ts: Timestamp::now(),
+                name: "a".into(),
+                weight: 1,
+            },
+        );
+        commit(tx).await?;
+
+        // Only the owner passes the check, and the lease can't be taken from an
+        // active client
+        let tx = root.tx();
+        check_lease(&tx, &partition, a).await?;
+        assert!(check_lease(&tx, &partition, b).await.is_err());
+        let expired_ts = Timestamp::now() - std::time::Duration::from_secs(60);
+        assert!(
+            try_acquire_lease(&tx, &root.space, &partition, b, expired_ts)
+                .await?
+                .is_some()
+        );
+
+        // Once the owner stops sending heartbeats, the lease changes hands and
+        // the old owner is fenced out
+        let tx = root.tx();
+        assert!(
+            try_acquire_lease(&tx, &root.space, &partition, b, Timestamp::now())
+                .await?
+                .is_none()
+        );
+        commit(tx).await?;
+        let tx = root.tx();
+        check_lease(&tx, &partition, b).await?;
+        let err = check_lease(&tx, &partition, a).await.unwrap_err();
+        assert!(err.0.downcast_ref::<LeaseLost>().is_some());
+
+        // Releasing a lease we don't hold has no effect
+        release_lease(&tx, &partition, a).await?;
+        check_lease(&tx, &partition, b).await?;
+        release_lease(&tx, &partition, b).await?;
+        assert!(check_lease(&tx, &partition, b).await.is_err());
+
+        root.cleanup().await;
+        Ok(())
+    }
+}
@@ -20,6 +20,10 @@
 //! also used internally for communication between agents. In the latter case,
 //! AgentDB guarantees exactly-once delivery of messages.
 //!
+//! Each message has a [Priority]. Agents with pending high-priority messages are
+//! processed before any other agents in the same partition, so that a large volume
+//! of bulk messages cannot delay more urgent ones.
+//!
 //! ## State function
 //!
 //! The state function governs the behaviour of the entire system: it is provided
@@ -104,36 +108,52 @@
 //! extended donwtime
 //!
 
-use std::{fmt::Debug, sync::Arc, time::Duration};
+use std::{fmt::Debug, sync::Arc};
 
+use anyhow::anyhow;
 use byteorder::{ByteOrder, LittleEndian};
 use foundationdb::{
     directory::{Directory, DirectoryOutput},
     Transaction,
 };
 use futures::future::BoxFuture;
-use message::{INITIAL_TS_OFFSET, MS_PER_MSG_PER_OP};
+use message::load_budget_policy;
+use policy::BudgetPolicy;
 use serde::{Deserialize, Serialize};
+use trace::TraceInfo;
 use uuid::Uuid;
 
 pub mod admin;
 pub mod blob;
 pub mod cancellation;
+pub mod changes;
 mod client;
+mod config;
+mod dead_letter;
 mod directories;
+pub mod encryption;
 mod error;
+pub mod history;
 pub mod id;
+pub mod idempotency;
+mod lease;
 mod message;
+pub mod metrics;
 mod partition;
+pub mod policy;
 mod prepacked;
+#[cfg(test)]
+mod test_utils;
+mod trace;
 mod typed_subspace;
 mod utils;
 
 use cancellation::{spawn_cancellable, CancellableHandle};
 use client::{client_task, PartitionRange};
+pub use config::ClientConfig;
 pub use directories::Global;
 pub use error::Error;
-pub use message::send_messages;
+pub use message::{change_scheduled_messages, send_messages};
 pub use prepacked::Prepacked;
 pub use typed_subspace::TypedSubspace;
 pub use utils::Timestamp;
@@ -143,17 +163,62 @@ const DEFAULT_PARTITION_RANGE: PartitionRange = PartitionRange {
     count: 100,
 };
 
-const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
-const GC_INTERVAL: Duration = Duration::from_secs(10);
-const MAX_BATCH_SIZE: usize = 100;
-
 #[derive(Serialize, Deserialize)]
 struct MessageHeader {
+    recipient_id: Uuid,
+    message_id: Uuid,
+    // The blob holding the message content, which may be shared with other messages.
+    // Equal to the message ID for messages sent before blob IDs were generated
+    // separately.
+    blob_id: Uuid,
+    operation_id: Uuid,
+    priority: Priority,
+    expires_at: Option<Timestamp>,
+    trace: Option<TraceInfo>,
+    // A streamed blob which the message holds a reference to
+    attachment: Option<Uuid>,
+}
+
+// Message headers are prefixed with the version of their encoding. Headers written
+// before the encoding was versioned start with the length of the recipient ID instead.
+const MESSAGE_HEADER_VERSION: u8 = 1;
+const LEGACY_MESSAGE_HEADER_TAG: u8 = 16;
+
+#[derive(Deserialize)]
+struct LegacyMessageHeader {
     recipient_id: Uuid,
     blob_id: Uuid,
     operation_id: Uuid,
 }
 
+impl MessageHeader {
+    fn encode(&self) -> Vec<u8> {
+        let mut res = vec![MESSAGE_HEADER_VERSION];
+        res.extend(postcard::to_stdvec(self).expect("Infallible serialization"));
+        res
+    }
+    fn decode(bytes: &[u8]) -> Result<Self, Error> {
+        match bytes.split_first() {
+            Some((&MESSAGE_HEADER_VERSION, rest)) => Ok(postcard::from_bytes(rest)?),
+            Some((&LEGACY_MESSAGE_HEADER_TAG, _)) => {
+                // Legacy messages always had their own blob
+                let legacy: LegacyMessageHeader = postcard::from_bytes(bytes)?;
+                Ok(Self {
+                    recipient_id: legacy.recipient_id,
+                    message_id: legacy.blob_id,
+                    blob_id: legacy.blob_id,
+                    operation_id: legacy.operation_id,
+                    priority: Priority::Normal,
+                    expires_at: None,
+                    trace: None,
+                    attachment: None,
+                })
+            }
+            _ => Err(Error(anyhow!("Unknown message header version"))),
+        }
+    }
+}
+
 /// A single message received by the agent.
 #[derive(Debug, Clone)]
 pub struct InboundMessage {
@@ -161,11 +226,16 @@ pub struct InboundMessage {
     pub operation_id: Uuid,
     /// The contents of the message.
     pub data: Vec<u8>,
+    /// The streamed blob attached to the message, if any. The message's reference
+    /// to the blob is released once the message has been handled, so the agent
+    /// must [blob::retain] the blob if it needs to keep it.
+    pub attachment: Option<Uuid>,
 }
 
 #[derive(Debug, Copy, Clone)]
 struct StateFnLiveMode<'a> {
     operation_ts: &'a TypedSubspace<Uuid>,
+    budget_policy: &'a [u8],
     user_dir: &'a DirectoryOutput,
     global: &'a Global,
     tx: &'a Transaction,
@@ -242,11 +312,12 @@ impl<'a> StateFnInput<'a> {
     /// Returns the clearance level of the given operation: how many messages
     /// can be sent as part of this operation before the system will return
     /// an error. If no messages are sent, the clearance level will gradually
-    /// increase over time.
+    /// increase over time, according to the root's [policy::BudgetPolicy].
     pub async fn clearance(&self, operation_id: Uuid) -> Result<i64, Error> {
         if let StateFnMode::Live(live) = self.mode {
+            let budget_policy = load_budget_policy(live.tx, live.budget_policy).await?;
             let current_ts = Timestamp::now().millis();
-            let initial_operation_ts = current_ts - INITIAL_TS_OFFSET;
+            let initial_operation_ts = current_ts - budget_policy.initial_ts_offset();
             let key = live.operation_ts.pack(&operation_id);
             let operation_ts = live
                 .tx
@@ -254,16 +325,50 @@ impl<'a> StateFnInput<'a> {
                 .await?
                 .map(|slice| LittleEndian::read_i64(&slice))
                 .unwrap_or(initial_operation_ts);
-            Ok((current_ts - operation_ts) / MS_PER_MSG_PER_OP)
+            Ok((current_ts - operation_ts) / budget_policy.ms_per_msg())
         } else {
-            Ok(INITIAL_TS_OFFSET / MS_PER_MSG_PER_OP)
+            Ok(i64::from(BudgetPolicy::default().max_burst))
         }
     }
 }
 
+/// The priority of a message. Messages are processed in separate lanes for each
+/// priority, and lower priority lanes are only processed once the higher priority
+/// lanes of the same partition are empty.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Priority {
+    /// Interactive traffic which should be delivered as soon as possible.
+    High,
+    /// The default priority.
+    Normal,
+    /// Bulk traffic, such as backfills, which can wait for other messages.
+    Low,
+}
+
+impl Priority {
+    // All priorities, from highest to lowest
+    const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+    // The lane used for this priority within the partition subspaces. Lower lanes
+    // are processed first.
+    fn lane(self) -> u8 {
+        self as u8
+    }
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Self::Normal
+    }
+}
+
 /// A message to be sent when the new agent state is saved.
 #[derive(Debug)]
 pub struct OutboundMessage {
+    /// The ID of the message, which can be used to cancel or reschedule the message
+    /// if it is scheduled for later delivery. Sending a scheduled message fails if
+    /// another pending scheduled message in the same root has the same ID.
+    pub message_id: Uuid,
     /// The root of the receiving agent.
     pub recipient_root: String,
     /// The ID of the receiving agent.
@@ -275,6 +380,32 @@ pub struct OutboundMessage {
     pub when: Timestamp,
     /// The contents of the message.
     pub content: Vec<u8>,
+    /// The priority with which the message is delivered.
+    pub priority: Priority,
+    /// The time after which the message is no longer useful. Messages which have
+    /// not been delivered by this time are dropped.
+    pub expires_at: Option<Timestamp>,
+    /// The name of the message type, if known. This is only used to
+    /// annotate the operation trace when tracing is enabled for the
+    /// recipient's root.
+    pub message_type: Option<String>,
+    /// A blob published by a [blob::BlobWriter] within the recipient's root, to be
+    /// attached to the message. The message holds its own reference to the blob
+    /// until it has been handled, so the sender may release its reference as soon
+    /// as the message is sent.
+    pub attachment: Option<Uuid>,
+}
+
+/// A change to a message which was previously sent with a `when` in the future,
+/// to be made when the new agent state is saved.
+#[derive(Debug, Clone)]
+pub struct ScheduledMessageChange {
+    /// The root of the receiving agent.
+    pub recipient_root: String,
+    /// The ID of the message to change.
+    pub message_id: Uuid,
+    /// When the message should now be sent, or `None` to cancel the message.
+    pub when: Option<Timestamp>,
 }
 
 /// A context accessible to post-commit hooks.
@@ -299,22 +430,33 @@ pub struct StateFnOutput {
     pub state: Option<Vec<u8>>,
     /// The messages to send.
     pub messages: Vec<OutboundMessage>,
+    /// Changes to previously scheduled messages.
+    pub message_changes: Vec<ScheduledMessageChange>,
     /// A post-commit hook to run.
     pub commit_hook: CommitHook,
 }
 
-/// The type of a state function.
-pub type StateFn =
-    Arc<dyn for<'a> Fn(StateFnInput<'a>) -> BoxFuture<'a, Result<StateFnOutput, ()>> + Send + Sync>;
+/// The type of a state function. If the state function returns an error, the
+/// messages will be retried with backoff, and may eventually be moved to the
+/// dead-letter queue according to the root's [policy::DeadLetterPolicy].
+pub type StateFn = Arc<
+    dyn for<'a> Fn(StateFnInput<'a>) -> BoxFuture<'a, Result<StateFnOutput, Error>> + Send + Sync,
+>;
 
 /// Start an AgentDB client, and obtain a cancellation handle.
+///
+/// Use [CancellableHandle::drain] to shut the client down gracefully: it will
+/// finish processing any in-flight batches before releasing its partitions to
+/// the remaining clients.
 pub fn start(
     client_name: String,
     global: Arc<Global>,
     root: String,
     state_fn: StateFn,
+    config: ClientConfig,
 ) -> CancellableHandle<Result<(), Error>> {
-    spawn_cancellable(|c| client_task(client_name, global, root, state_fn, c))
+    let config = Arc::new(config);
+    spawn_cancellable(|c| client_task(client_name, global, root, state_fn, config, c))
 }
 
 /// Run the AgentDB client forever, or until it returns an error.
@@ -323,8 +465,9 @@ pub async fn run(
     global: Arc<Global>,
     root: String,
     state_fn: StateFn,
+    config: ClientConfig,
 ) -> Result<(), Error> {
-    start(client_name, global, root, state_fn).await?
+    start(client_name, global, root, state_fn, config).await?
 }
 
 /// Construct the default client name. This is a combination of the hostname and process ID.
@@ -336,8 +479,34 @@ pub fn default_client_name() -> String {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn legacy_message_headers_are_decoded() {
+        let (recipient_id, blob_id, operation_id) =
+            (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let legacy = postcard::to_stdvec(&(recipient_id, blob_id, operation_id)).unwrap();
+        assert_eq!(legacy[0], LEGACY_MESSAGE_HEADER_TAG);
+
+        let header = MessageHeader::decode(&legacy).unwrap();
+        assert_eq!(header.recipient_id, recipient_id);
+        assert_eq!(header.message_id, blob_id);
+        assert_eq!(header.blob_id, blob_id);
+        assert_eq!(header.operation_id, operation_id);
+        assert_eq!(header.priority, Priority::Normal);
+        assert!(header.expires_at.is_none());
+        assert!(header.attachment.is_none());
+
+        let encoded = header.encode();
+        assert_eq!(encoded[0], MESSAGE_HEADER_VERSION);
+        assert_eq!(MessageHeader::decode(&encoded).unwrap().message_id, blob_id);
+
+        assert!(MessageHeader::decode(&[MESSAGE_HEADER_VERSION + 1]).is_err());
+        assert!(MessageHeader::decode(&[]).is_err());
+    }
 }
@@ -1,66 +1,258 @@
-use std::collections::{hash_map, HashMap, HashSet};
+use std::{
+    collections::{
+        hash_map::{self, DefaultHasher},
+        HashMap, HashSet,
+    },
+    hash::{Hash, Hasher},
+    sync::Arc,
+};
 
 use anyhow::anyhow;
 use byteorder::{ByteOrder, LittleEndian};
-use foundationdb::{options::MutationType, tuple::Versionstamp, Transaction};
+use foundationdb::{
+    options::MutationType,
+    tuple::{pack_with_versionstamp, unpack, Versionstamp},
+    FdbError, Transaction,
+};
+use uuid::Uuid;
 
 use crate::{
     blob,
-    directories::Global,
+    directories::{Global, PartitionSpace, RootSpace},
     error::Error,
     id,
+    metrics::{self, Counter, Labels},
     partition::mark_partition_modified,
-    utils::{load_partition_range, partition_for_recipient},
-    MessageHeader, OutboundMessage, Timestamp,
+    policy::{BudgetPolicy, TracePolicy},
+    trace::TraceInfo,
+    utils::{load_partition_range, load_value, partition_for_recipient},
+    MessageHeader, OutboundMessage, ScheduledMessageChange, Timestamp,
 };
 
-// Don't let any single operation exceed one message per second over long time scales.
-pub(crate) const MS_PER_MSG_PER_OP: i64 = 1000;
-// Cannot "burst" more than 1000 messages per operation.
-const MAX_MSG_BURST: i64 = 1000;
+// Messages at least this large with identical content share a single blob
+const SHARED_BLOB_MIN_SIZE: usize = 1024;
+
+// Rescheduled messages use the upper half of the index space, so that they cannot
+// collide with messages sent within the same transaction
+const RESCHEDULED_INDEX_BASE: u32 = 1 << 31;
+
+// The position of a message within the `message` subspace of a partition
+type MessageKey = (u8, Timestamp, Versionstamp, u32);
+
+// Identifies messages to the same root with identical content. The content is
+// hashed once up front, rather than every time the key is looked up.
+#[derive(PartialEq, Eq)]
+struct ContentKey<'a> {
+    hash: u64,
+    root: &'a str,
+    content: &'a [u8],
+}
+
+impl<'a> ContentKey<'a> {
+    fn new(msg: &'a OutboundMessage) -> Self {
+        let mut hasher = DefaultHasher::new();
+        msg.recipient_root.hash(&mut hasher);
+        msg.content.hash(&mut hasher);
+        Self {
+            hash: hasher.finish(),
+            root: &msg.recipient_root,
+            content: &msg.content,
+        }
+    }
+}
+
+impl Hash for ContentKey<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.hash.hash(state);
+    }
+}
+
+pub(crate) async fn load_budget_policy(
+    tx: &Transaction,
+    key: &[u8],
+) -> Result<BudgetPolicy, FdbError> {
+    Ok(load_value(tx, key, true).await?.unwrap_or_default())
+}
+
+// The agent sending messages from within the partition engine
+#[derive(Copy, Clone)]
+pub(crate) struct MessageSource<'a> {
+    pub sender_id: Uuid,
+    // The operation and message IDs of the messages being processed by the sender
+    pub delivered: &'a [(Uuid, Uuid)],
+}
 
-pub(crate) const INITIAL_TS_OFFSET: i64 = MS_PER_MSG_PER_OP * MAX_MSG_BURST;
+fn trace_info(source: Option<MessageSource<'_>>, msg: &OutboundMessage) -> TraceInfo {
+    TraceInfo {
+        sender_id: source.map(|source| source.sender_id),
+        message_type: msg.message_type.clone(),
+        caused_by: source
+            .iter()
+            .flat_map(|source| source.delivered)
+            .filter(|&&(operation_id, _)| operation_id == msg.operation_id)
+            .map(|&(_, message_id)| message_id)
+            .collect(),
+        sent_ts: Timestamp::now(),
+    }
+}
 
-/// Send messages into the AgentDB system.
+/// Send messages into the AgentDB system. Large messages with identical content
+/// sent to the same root within a single call share the storage for that content.
 pub async fn send_messages(
     tx: &Transaction,
     global: &Global,
     msgs: &[OutboundMessage],
     user_version: u16,
+) -> Result<(), Error> {
+    send_messages_from(tx, global, msgs, user_version, None).await
+}
+
+#[tracing::instrument(skip_all, fields(count = msgs.len()))]
+pub(crate) async fn send_messages_from(
+    tx: &Transaction,
+    global: &Global,
+    msgs: &[OutboundMessage],
+    user_version: u16,
+    source: Option<MessageSource<'_>>,
 ) -> Result<(), Error> {
     let mut partition_counts = HashMap::new();
     let mut partition_modified = HashSet::new();
     let mut operations = HashMap::<_, i64>::new();
 
+    // Count the recipients of each distinct message content, so that broadcasting
+    // the same content to many agents only stores it once. Only messages sent in
+    // the same call share a blob.
+    let content_keys: Vec<_> = msgs
+        .iter()
+        .map(|msg| {
+            if msg.content.len() >= SHARED_BLOB_MIN_SIZE {
+                Some(ContentKey::new(msg))
+            } else {
+                None
+            }
+        })
+        .collect();
+    let mut content_counts = HashMap::<_, i64>::new();
+    for content_key in content_keys.iter().flatten() {
+        *content_counts.entry(content_key).or_default() += 1;
+    }
+    let mut shared_blobs = HashMap::new();
+    let mut scheduled_ids = HashSet::new();
+
     for (idx, msg) in msgs.iter().enumerate() {
         let recipient_root = global.root(&msg.recipient_root).await?;
         let entry = partition_counts.entry(&msg.recipient_root);
-        let partition_range = match entry {
+        let (partition_range, trace_enabled, compression_policy) = match entry {
             hash_map::Entry::Occupied(occ) => *occ.get(),
-            hash_map::Entry::Vacant(vac) => *vac.insert(
-                load_partition_range(tx, &recipient_root.partition_range_send, false).await?,
-            ),
+            hash_map::Entry::Vacant(vac) => {
+                let partition_range =
+                    load_partition_range(tx, &recipient_root.partition_range_send, false).await?;
+                let trace_policy =
+                    load_value::<TracePolicy>(tx, &recipient_root.trace_policy, true)
+                        .await?
+                        .unwrap_or_default();
+                let compression_policy = blob::load_compression_policy(tx, &recipient_root).await?;
+                *vac.insert((partition_range, trace_policy.enabled, compression_policy))
+            }
         };
         *operations
             .entry((&msg.recipient_root, msg.operation_id))
             .or_default() += 1;
 
-        let msg_id = id::new();
-        blob::store_internal(tx, &recipient_root, msg_id, &msg.content);
-        let msg_hdr = postcard::to_stdvec(&MessageHeader {
+        let msg_id = msg.message_id;
+        tracing::debug!(
+            recipient_root = %msg.recipient_root,
+            recipient_id = %msg.recipient_id,
+            operation_id = %msg.operation_id,
+            message_id = %msg_id,
+            "Sending message"
+        );
+
+        // The message ID is the handle for cancelling or rescheduling a scheduled
+        // message, so it must not refer to another pending message
+        if msg.when != Timestamp::zero()
+            && (!scheduled_ids.insert((msg.recipient_root.as_str(), msg_id))
+                || tx
+                    .get(&recipient_root.scheduled.pack(&msg_id), false)
+                    .await?
+                    .is_some())
+        {
+            return Err(Error(anyhow!(
+                "Scheduled message ID already in use: {}",
+                msg_id
+            )));
+        }
+        let shared_content = content_keys[idx]
+            .as_ref()
+            .map(|content_key| (content_key, content_counts[content_key]))
+            .filter(|&(_, count)| count > 1);
+        let blob_id = if let Some((content_key, count)) = shared_content {
+            match shared_blobs.entry(content_key) {
+                hash_map::Entry::Occupied(occ) => *occ.get(),
+                hash_map::Entry::Vacant(vac) => {
+                    let blob_id = id::new();
+                    blob::store_internal(
+                        tx,
+                        global,
+                        &recipient_root,
+                        blob_id,
+                        &msg.content,
+                        &compression_policy,
+                    )?;
+                    blob::add_refs(tx, &recipient_root, blob_id, count);
+                    *vac.insert(blob_id)
+                }
+            }
+        } else {
+            let blob_id = id::new();
+            blob::store_internal(
+                tx,
+                global,
+                &recipient_root,
+                blob_id,
+                &msg.content,
+                &compression_policy,
+            )?;
+            blob_id
+        };
+        if let Some(attachment) = msg.attachment {
+            if tx
+                .get(&recipient_root.blob_modified.pack(&attachment), false)
+                .await?
+                .is_none()
+            {
+                return Err(Error(anyhow!("Attachment not found: {}", attachment)));
+            }
+            blob::add_refs(tx, &recipient_root, attachment, 1);
+        }
+        let msg_hdr = MessageHeader {
             recipient_id: msg.recipient_id,
+            message_id: msg_id,
             operation_id: msg.operation_id,
-            blob_id: msg_id,
-        })?;
+            blob_id,
+            priority: msg.priority,
+            expires_at: msg.expires_at,
+            trace: if trace_enabled {
+                Some(trace_info(source, msg))
+            } else {
+                None
+            },
+            attachment: msg.attachment,
+        }
+        .encode();
 
         let partition_idx = partition_for_recipient(msg.recipient_id, partition_range);
         let partition = recipient_root.partition(global, partition_idx).await?;
 
-        let key =
-            partition
-                .message
-                .pack(&(msg.when, Versionstamp::incomplete(user_version), idx as u32));
+        let key_parts = (
+            msg.priority.lane(),
+            msg.when,
+            Versionstamp::incomplete(user_version),
+            idx as u32,
+        );
+        let key = partition.message.pack(&key_parts);
         tx.atomic_op(&key, &msg_hdr, MutationType::SetVersionstampedKey);
+        index_scheduled_message(tx, &recipient_root, msg_id, msg.recipient_id, &key_parts);
 
         // Mark the partition as modified
         if partition_modified.insert((&msg.recipient_root, partition_idx)) {
@@ -70,17 +262,27 @@ pub async fn send_messages(
 
     // Make sure all the involved operations have sufficient budget to continue
     let current_ts = Timestamp::now().millis();
-    let initial_operation_ts = current_ts - INITIAL_TS_OFFSET;
     for ((recipient_root, operation_id), count) in operations {
         let root = global.root(recipient_root).await?;
+        let budget_policy = load_budget_policy(tx, &root.budget_policy).await?;
+        let ms_per_msg = budget_policy.ms_per_msg();
+        let initial_operation_ts = current_ts - budget_policy.initial_ts_offset();
         let key = root.operation_ts.pack(&operation_id);
         let operation_ts = tx
             .get(&key, true)
             .await?
             .map(|slice| LittleEndian::read_i64(&slice))
             .unwrap_or(initial_operation_ts);
-        let allowed_count = (current_ts - operation_ts) / MS_PER_MSG_PER_OP;
+        let allowed_count = (current_ts - operation_ts) / ms_per_msg;
         if allowed_count < count {
+            metrics::increment_counter(
+                Counter::BudgetRejections,
+                Labels {
+                    root: recipient_root,
+                    partition: None,
+                },
+                count as u64,
+            );
             return Err(Error(anyhow!(
                 "Budget exceeded for operation {}",
                 operation_id
@@ -88,12 +290,401 @@ pub async fn send_messages(
         }
 
         tx.atomic_op(&key, &initial_operation_ts.to_le_bytes(), MutationType::Max);
+        tx.atomic_op(&key, &(count * ms_per_msg).to_le_bytes(), MutationType::Add);
+    }
+
+    Ok(())
+}
+
+// Releases the blob containing the content of a message, and the message's reference
+// to its attachment, once they are no longer needed.
+pub(crate) async fn release_message_content(
+    tx: &Transaction,
+    root: &RootSpace,
+    header: &MessageHeader,
+) -> Result<(), Error> {
+    // Blobs which aren't shared have no recorded references, so releasing
+    // them deletes them straight away.
+    blob::release_ref(tx, root, header.blob_id).await?;
+    if let Some(attachment) = header.attachment {
+        blob::release_ref(tx, root, attachment).await?;
+    }
+    Ok(())
+}
+
+// Scheduled messages are indexed by message ID so that they can be found again
+// if they need to be cancelled or rescheduled.
+fn index_scheduled_message(
+    tx: &Transaction,
+    root: &RootSpace,
+    message_id: Uuid,
+    recipient_id: Uuid,
+    key_parts: &MessageKey,
+) {
+    let index_key = root.scheduled.pack(&message_id);
+    let (lane, when, version, idx) = key_parts;
+    if *when == Timestamp::zero() {
+        tx.clear(&index_key);
+    } else {
+        tx.atomic_op(
+            &index_key,
+            &pack_with_versionstamp(&(recipient_id, *lane, *when, version.clone(), *idx)),
+            MutationType::SetVersionstampedValue,
+        );
+    }
+}
+
+// Removes a message from the scheduled message index once it is ready to be delivered.
+pub(crate) fn unindex_scheduled_message(
+    tx: &Transaction,
+    root: &RootSpace,
+    header: &MessageHeader,
+    when: Timestamp,
+) {
+    if when != Timestamp::zero() {
+        tx.clear(&root.scheduled.pack(&header.message_id));
+    }
+}
+
+struct ScheduledMessage {
+    recipient_id: Uuid,
+    partition: Arc<PartitionSpace>,
+    key_parts: MessageKey,
+    header: Vec<u8>,
+}
+
+async fn find_scheduled_message(
+    tx: &Transaction,
+    global: &Global,
+    root: &RootSpace,
+    message_id: Uuid,
+) -> Result<Option<ScheduledMessage>, Error> {
+    let index_key = root.scheduled.pack(&message_id);
+    let (recipient_id, lane, when, version, idx) =
+        if let Some(value) = tx.get(&index_key, false).await? {
+            unpack::<(Uuid, u8, Timestamp, Versionstamp, u32)>(&value)?
+        } else {
+            return Ok(None);
+        };
+    let key_parts = (lane, when, version, idx);
+
+    // The message may not have been migrated yet if the root is being re-partitioned
+    for partition_range_key in [&root.partition_range_send, &root.partition_range_recv] {
+        let partition_range = load_partition_range(tx, partition_range_key, false).await?;
+        let partition_idx = partition_for_recipient(recipient_id, partition_range);
+        let partition = root.partition(global, partition_idx).await?;
+        if let Some(header) = tx.get(&partition.message.pack(&key_parts), false).await? {
+            return Ok(Some(ScheduledMessage {
+                recipient_id,
+                partition,
+                key_parts,
+                header: header.to_vec(),
+            }));
+        }
+    }
+    Ok(None)
+}
+
+// Cancels or reschedules a message which has not been delivered yet. Returns `false` if
+// the message could not be found.
+pub(crate) async fn change_scheduled_message(
+    tx: &Transaction,
+    global: &Global,
+    root: &RootSpace,
+    message_id: Uuid,
+    when: Option<Timestamp>,
+    user_version: u16,
+    idx: u32,
+) -> Result<bool, Error> {
+    let scheduled =
+        if let Some(scheduled) = find_scheduled_message(tx, global, root, message_id).await? {
+            scheduled
+        } else {
+            return Ok(false);
+        };
+    tx.clear(&scheduled.partition.message.pack(&scheduled.key_parts));
+
+    if let Some(when) = when {
+        tracing::debug!(message_id = %message_id, when = %when, "Rescheduling message");
+        let partition_range = load_partition_range(tx, &root.partition_range_send, false).await?;
+        let partition_idx = partition_for_recipient(scheduled.recipient_id, partition_range);
+        let partition = root.partition(global, partition_idx).await?;
+        let key_parts = (
+            scheduled.key_parts.0,
+            when,
+            Versionstamp::incomplete(user_version),
+            RESCHEDULED_INDEX_BASE + idx,
+        );
         tx.atomic_op(
-            &key,
-            &(count * MS_PER_MSG_PER_OP).to_le_bytes(),
-            MutationType::Add,
+            &partition.message.pack(&key_parts),
+            &scheduled.header,
+            MutationType::SetVersionstampedKey,
         );
+        index_scheduled_message(tx, root, message_id, scheduled.recipient_id, &key_parts);
+        mark_partition_modified(tx, &partition);
+    } else {
+        tracing::debug!(message_id = %message_id, "Cancelling message");
+        let header = MessageHeader::decode(&scheduled.header)?;
+        tx.clear(&root.scheduled.pack(&message_id));
+        release_message_content(tx, root, &header).await?;
     }
+    Ok(true)
+}
 
+/// Cancel or reschedule messages which were previously sent with a `when` in the
+/// future. Changes to messages which have already been delivered are ignored.
+pub async fn change_scheduled_messages(
+    tx: &Transaction,
+    global: &Global,
+    changes: &[ScheduledMessageChange],
+    user_version: u16,
+) -> Result<(), Error> {
+    for (idx, change) in changes.iter().enumerate() {
+        let root = global.root(&change.recipient_root).await?;
+        change_scheduled_message(
+            tx,
+            global,
+            &root,
+            change.message_id,
+            change.when,
+            user_version,
+            idx as u32,
+        )
+        .await?;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::AsyncWriteExt;
+
+    use super::*;
+    use crate::{
+        admin,
+        test_utils::{commit, test_message, TestRoot},
+        DEFAULT_PARTITION_RANGE,
+    };
+
+    #[test]
+    fn content_keys_match_identical_content_for_the_same_root() {
+        let content = vec![7; SHARED_BLOB_MIN_SIZE];
+        let msg = test_message("root", Uuid::new_v4(), &content);
+        let same = test_message("root", Uuid::new_v4(), &content);
+        let other_root = test_message("other", Uuid::new_v4(), &content);
+        let other_content = test_message("root", Uuid::new_v4(), &content[1..]);
+
+        let key = ContentKey::new(&msg);
+        assert!(ContentKey::new(&same) == key);
+        assert!(ContentKey::new(&other_root) != key);
+        assert!(ContentKey::new(&other_content) != key);
+
+        let mut counts = HashMap::new();
+        for msg in [&msg, &same, &other_root] {
+            *counts.entry(ContentKey::new(msg)).or_insert(0) += 1;
+        }
+        assert_eq!(counts[&key], 2);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn operation_budget_limits_burst() -> Result<(), Error> {
+        let root = TestRoot::new().await;
+        admin::set_budget_policy(
+            &root.global,
+            &root.name,
+            BudgetPolicy {
+                message_interval: Duration::from_secs(60),
+                max_burst: 2,
+            },
+        )
+        .await?;
+
+        let mut msgs = vec![
+            test_message(&root.name, Uuid::new_v4(), b"a"),
+            test_message(&root.name, Uuid::new_v4(), b"b"),
+        ];
+        let operation_id = msgs[0].operation_id;
+        msgs[1].operation_id = operation_id;
+        let tx = root.tx();
+        send_messages(&tx, &root.global, &msgs, 0).await?;
+        commit(tx).await?;
+
+        // The burst has been used up, so one more message is rejected
+        let mut msg = test_message(&root.name, Uuid::new_v4(), b"c");
+        msg.operation_id = operation_id;
+        let tx = root.tx();
+        assert!(send_messages(&tx, &root.global, &[msg], 0).await.is_err());
+
+        // Other operations are unaffected
+        let msg = test_message(&root.name, Uuid::new_v4(), b"d");
+        send_messages(&tx, &root.global, &[msg], 0).await?;
+
+        root.cleanup().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn attachment_is_released_with_message() -> Result<(), Error> {
+        let root = TestRoot::new().await;
+        let mut writer = blob::BlobWriter::new(root.global.clone(), &root.name).await?;
+        writer.write_all(b"attached").await?;
+        let attachment = writer.publish().await?;
+
+        // The sender can release its reference as soon as the message is sent
+        let recipient_id = Uuid::new_v4();
+        let mut msg = test_message(&root.name, recipient_id, b"");
+        msg.attachment = Some(attachment);
+        let tx = root.tx();
+        send_messages(&tx, &root.global, &[msg], 0).await?;
+        blob::release_ref(&tx, &root.space, attachment).await?;
+        commit(tx).await?;
+
+        let partition = root
+            .partition(partition_for_recipient(
+                recipient_id,
+                DEFAULT_PARTITION_RANGE,
+            ))
+            .await;
+        let tx = root.tx();
+        let values = tx
+            .get_range(&partition.message.range().into(), 0, true)
+            .await?;
+        let header = MessageHeader::decode(values[0].value())?;
+        assert_eq!(header.attachment, Some(attachment));
+        assert!(
+            blob::load_internal(&tx, &root.global, &root.space, attachment, true)
+                .await?
+                .is_some()
+        );
+        release_message_content(&tx, &root.space, &header).await?;
+        commit(tx).await?;
+
+        let tx = root.tx();
+        assert!(
+            blob::load_internal(&tx, &root.global, &root.space, attachment, true)
+                .await?
+                .is_none()
+        );
+
+        root.cleanup().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn missing_attachment_is_rejected() -> Result<(), Error> {
+        let root = TestRoot::new().await;
+        let mut msg = test_message(&root.name, Uuid::new_v4(), b"");
+        msg.attachment = Some(Uuid::new_v4());
+        let tx = root.tx();
+        assert!(send_messages(&tx, &root.global, &[msg], 0).await.is_err());
+
+        root.cleanup().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn reused_scheduled_message_id_is_rejected() -> Result<(), Error> {
+        let root = TestRoot::new().await;
+        let (recipient_id, message_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let msg = || {
+            let mut msg = test_message(&root.name, recipient_id, b"later");
+            msg.message_id = message_id;
+            msg.when = Timestamp::from_millis(Timestamp::now().millis() + 3_600_000);
+            msg
+        };
+
+        // Within a single call
+        let tx = root.tx();
+        assert!(send_messages(&tx, &root.global, &[msg(), msg()], 0)
+            .await
+            .is_err());
+        drop(tx);
+
+        // And against a message which is already scheduled
+        let tx = root.tx();
+        send_messages(&tx, &root.global, &[msg()], 0).await?;
+        commit(tx).await?;
+        let tx = root.tx();
+        assert!(send_messages(&tx, &root.global, &[msg()], 0).await.is_err());
+
+        root.cleanup().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn scheduled_message_can_be_rescheduled_and_cancelled() -> Result<(), Error> {
+        let root = TestRoot::new().await;
+        let recipient_id = Uuid::new_v4();
+        let partition = root
+            .partition(partition_for_recipient(
+                recipient_id,
+                DEFAULT_PARTITION_RANGE,
+            ))
+            .await;
+        let mut msg = test_message(&root.name, recipient_id, b"later");
+        msg.when = Timestamp::from_millis(Timestamp::now().millis() + 3_600_000);
+        let message_id = msg.message_id;
+        let tx = root.tx();
+        send_messages(&tx, &root.global, &[msg], 0).await?;
+        commit(tx).await?;
+
+        let change = |when| ScheduledMessageChange {
+            recipient_root: root.name.clone(),
+            message_id,
+            when,
+        };
+        let new_when = Timestamp::from_millis(Timestamp::now().millis() + 7_200_000);
+        let tx = root.tx();
+        change_scheduled_messages(&tx, &root.global, &[change(Some(new_when))], 0).await?;
+        commit(tx).await?;
+
+        // The message is moved to its new time, and can still be found
+        let tx = root.tx();
+        let values = tx
+            .get_range(&partition.message.range().into(), 0, true)
+            .await?;
+        assert_eq!(values.len(), 1);
+        let (_, when, _, _) = partition.message.unpack(values[0].key())?;
+        assert_eq!(when, new_when);
+        let blob_id = MessageHeader::decode(values[0].value())?.blob_id;
+        assert_ne!(blob_id, message_id);
+        assert!(
+            find_scheduled_message(&tx, &root.global, &root.space, message_id)
+                .await?
+                .is_some()
+        );
+
+        change_scheduled_messages(&tx, &root.global, &[change(None)], 0).await?;
+        commit(tx).await?;
+
+        // Cancelling removes the message, its index entry and its content
+        let tx = root.tx();
+        assert!(tx
+            .get_range(&partition.message.range().into(), 0, true)
+            .await?
+            .is_empty());
+        assert!(tx
+            .get(&root.space.scheduled.pack(&message_id), true)
+            .await?
+            .is_none());
+        assert!(
+            blob::load_internal(&tx, &root.global, &root.space, blob_id, true)
+                .await?
+                .is_none()
+        );
+        assert!(
+            !change_scheduled_message(&tx, &root.global, &root.space, message_id, None, 0, 0)
+                .await?
+        );
+
+        root.cleanup().await;
+        Ok(())
+    }
+}
@@ -0,0 +1,333 @@
+//! A lightweight metrics facade for the partition engine. Metrics are discarded
+//! unless a [Recorder] has been installed using [set_recorder]. A recorder which
+//! exposes metrics in the Prometheus text format is provided as
+//! [PrometheusRecorder].
+
+use std::{collections::BTreeMap, fmt::Write, sync::Arc};
+
+use lazy_static::lazy_static;
+use parking_lot::{Mutex, RwLock};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, ToSocketAddrs},
+};
+
+use crate::Error;
+
+/// A metric which counts events.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Counter {
+    /// Messages moved from the pending queue into per-agent batches.
+    MessagesRolledUp,
+    /// Batches of messages successfully processed by the state function.
+    BatchesProcessed,
+    /// Transactions retried due to conflicts or other retryable errors.
+    TransactionRetries,
+    /// Attempts to send messages which were rejected because the operation
+    /// had exceeded its budget.
+    BudgetRejections,
+    /// Commit hooks run after a batch was processed.
+    CommitHooks,
+    /// Messages dropped because they expired before they could be delivered.
+    MessagesExpired,
+}
+
+impl Counter {
+    /// The name of the metric.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::MessagesRolledUp => "agentdb_messages_rolled_up_total",
+            Self::BatchesProcessed => "agentdb_batches_processed_total",
+            Self::TransactionRetries => "agentdb_transaction_retries_total",
+            Self::BudgetRejections => "agentdb_budget_rejections_total",
+            Self::CommitHooks => "agentdb_commit_hooks_total",
+            Self::MessagesExpired => "agentdb_messages_expired_total",
+        }
+    }
+    /// A description of the metric.
+    pub fn help(self) -> &'static str {
+        match self {
+            Self::MessagesRolledUp => "Messages moved from the pending queue into agent batches.",
+            Self::BatchesProcessed => "Batches of messages processed by the state function.",
+            Self::TransactionRetries => "Partition engine transactions which were retried.",
+            Self::BudgetRejections => "Messages rejected due to an exhausted operation budget.",
+            Self::CommitHooks => "Commit hooks run after processing a batch.",
+            Self::MessagesExpired => "Messages dropped because they expired before delivery.",
+        }
+    }
+}
+
+/// A metric which records a distribution of values.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Histogram {
+    /// The number of messages in each batch passed to the state function.
+    BatchSize,
+    /// The time taken by the state function, in seconds.
+    StateFnLatency,
+}
+
+impl Histogram {
+    /// The name of the metric.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::BatchSize => "agentdb_batch_size",
+            Self::StateFnLatency => "agentdb_state_fn_latency_seconds",
+        }
+    }
+    /// A description of the metric.
+    pub fn help(self) -> &'static str {
+        match self {
+            Self::BatchSize => "Number of messages in each batch passed to the state function.",
+            Self::StateFnLatency => "Time taken by the state function to process a batch.",
+        }
+    }
+    /// The upper bounds of the buckets used when exporting this histogram.
+    pub fn buckets(self) -> &'static [f64] {
+        match self {
+            Self::BatchSize => &[1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0, 1000.0],
+            Self::StateFnLatency => &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0],
+        }
+    }
+}
+
+/// Identifies the part of the system a metric was recorded for.
+#[derive(Debug, Copy, Clone)]
+pub struct Labels<'a> {
+    /// The root the metric was recorded for.
+    pub root: &'a str,
+    /// The partition the metric was recorded for, if any.
+    pub partition: Option<u32>,
+}
+
+/// Receives metrics from the partition engine.
+pub trait Recorder: Send + Sync {
+    /// Increment a counter by the given value.
+    fn increment_counter(&self, counter: Counter, labels: Labels<'_>, value: u64);
+    /// Record a single value in a histogram.
+    fn record_histogram(&self, histogram: Histogram, labels: Labels<'_>, value: f64);
+}
+
+lazy_static! {
+    static ref RECORDER: RwLock<Option<Arc<dyn Recorder>>> = RwLock::new(None);
+}
+
+/// Install a recorder to receive metrics from every client in this process.
+/// Replaces any previously installed recorder.
+pub fn set_recorder(recorder: Arc<dyn Recorder>) {
+    *RECORDER.write() = Some(recorder);
+}
+
+/// Remove the installed recorder, so that metrics are discarded.
+pub fn clear_recorder() {
+    *RECORDER.write() = None;
+}
+
+pub(crate) fn increment_counter(counter: Counter, labels: Labels<'_>, value: u64) {
+    if let Some(recorder) = &*RECORDER.read() {
+        recorder.increment_counter(counter, labels, value);
+    }
+}
+
+pub(crate) fn record_histogram(histogram: Histogram, labels: Labels<'_>, value: f64) {
+    if let Some(recorder) = &*RECORDER.read() {
+        recorder.record_histogram(histogram, labels, value);
+    }
+}
+
+type LabelKey = (String, Option<u32>);
+
+struct HistogramData {
+    bucket_counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+/// A recorder which aggregates metrics in memory, and renders them in the
+/// Prometheus text exposition format.
+#[derive(Default)]
+pub struct PrometheusRecorder {
+    counters: Mutex<BTreeMap<(Counter, LabelKey), u64>>,
+    histograms: Mutex<BTreeMap<(Histogram, LabelKey), HistogramData>>,
+}
+
+fn label_key(labels: Labels<'_>) -> LabelKey {
+    (labels.root.into(), labels.partition)
+}
+
+fn format_labels(out: &mut String, (root, partition): &LabelKey, extra: Option<(&str, f64)>) {
+    out.push_str("{root=\"");
+    for c in root.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    if let Some(partition) = partition {
+        let _ = write!(out, ",partition=\"{}\"", partition);
+    }
+    if let Some((name, value)) = extra {
+        if value.is_infinite() {
+            let _ = write!(out, ",{}=\"+Inf\"", name);
+        } else {
+            let _ = write!(out, ",{}=\"{}\"", name, value);
+        }
+    }
+    out.push('}');
+}
+
+impl PrometheusRecorder {
+    /// Construct a new recorder with no recorded metrics.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Render all recorded metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        let counters = self.counters.lock();
+        let mut prev_counter = None;
+        for ((counter, labels), value) in counters.iter() {
+            if prev_counter != Some(*counter) {
+                let _ = writeln!(out, "# HELP {} {}", counter.name(), counter.help());
+                let _ = writeln!(out, "# TYPE {} counter", counter.name());
+                prev_counter = Some(*counter);
+            }
+            out.push_str(counter.name());
+            format_labels(&mut out, labels, None);
+            let _ = writeln!(out, " {}", value);
+        }
+        drop(counters);
+
+        let histograms = self.histograms.lock();
+        let mut prev_histogram = None;
+        for ((histogram, labels), data) in histograms.iter() {
+            let name = histogram.name();
+            if prev_histogram != Some(*histogram) {
+                let _ = writeln!(out, "# HELP {} {}", name, histogram.help());
+                let _ = writeln!(out, "# TYPE {} histogram", name);
+                prev_histogram = Some(*histogram);
+            }
+            let mut cumulative = 0;
+            for (bound, count) in histogram.buckets().iter().zip(&data.bucket_counts) {
+                cumulative += count;
+                let _ = write!(out, "{}_bucket", name);
+                format_labels(&mut out, labels, Some(("le", *bound)));
+                let _ = writeln!(out, " {}", cumulative);
+            }
+            let _ = write!(out, "{}_bucket", name);
+            format_labels(&mut out, labels, Some(("le", f64::INFINITY)));
+            let _ = writeln!(out, " {}", data.count);
+            let _ = write!(out, "{}_sum", name);
+            format_labels(&mut out, labels, None);
+            let _ = writeln!(out, " {}", data.sum);
+            let _ = write!(out, "{}_count", name);
+            format_labels(&mut out, labels, None);
+            let _ = writeln!(out, " {}", data.count);
+        }
+
+        out
+    }
+    /// Serve the recorded metrics over HTTP on the given address, so that they
+    /// can be scraped by Prometheus. Every request receives the rendered metrics,
+    /// regardless of the path requested. Runs until an error occurs.
+    pub async fn serve(self: Arc<Self>, addr: impl ToSocketAddrs) -> Result<(), Error> {
+        let listener = TcpListener::bind(addr).await?;
+        loop {
+            let (mut socket, _) = listener.accept().await?;
+            let this = self.clone();
+            tokio::spawn(async move {
+                // There's only one document to serve, so the request can be ignored
+                let mut buf = [0; 1024];
+                let _ = socket.read(&mut buf).await;
+                let body = this.render();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+            });
+        }
+    }
+}
+
+impl Recorder for PrometheusRecorder {
+    fn increment_counter(&self, counter: Counter, labels: Labels<'_>, value: u64) {
+        *self
+            .counters
+            .lock()
+            .entry((counter, label_key(labels)))
+            .or_default() += value;
+    }
+    fn record_histogram(&self, histogram: Histogram, labels: Labels<'_>, value: f64) {
+        let mut histograms = self.histograms.lock();
+        let data = histograms
+            .entry((histogram, label_key(labels)))
+            .or_insert_with(|| HistogramData {
+                bucket_counts: vec![0; histogram.buckets().len()],
+                sum: 0.0,
+                count: 0,
+            });
+        if let Some(index) = histogram.buckets().iter().position(|&bound| value <= bound) {
+            data.bucket_counts[index] += 1;
+        }
+        data.sum += value;
+        data.count += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_are_aggregated_per_label() {
+        let recorder = PrometheusRecorder::new();
+        let labels = Labels {
+            root: "root",
+            partition: Some(3),
+        };
+        recorder.increment_counter(Counter::BatchesProcessed, labels, 2);
+        recorder.increment_counter(Counter::BatchesProcessed, labels, 5);
+        recorder.increment_counter(
+            Counter::BudgetRejections,
+            Labels {
+                root: "a \"quoted\" root",
+                partition: None,
+            },
+            1,
+        );
+        assert_eq!(
+            recorder.render(),
+            "# HELP agentdb_batches_processed_total Batches of messages processed by the state function.\n\
+             # TYPE agentdb_batches_processed_total counter\n\
+             agentdb_batches_processed_total{root=\"root\",partition=\"3\"} 7\n\
+             # HELP agentdb_budget_rejections_total Messages rejected due to an exhausted operation budget.\n\
+             # TYPE agentdb_budget_rejections_total counter\n\
+             agentdb_budget_rejections_total{root=\"a \\\"quoted\\\" root\"} 1\n"
+        );
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let recorder = PrometheusRecorder::new();
+        let labels = Labels {
+            root: "root",
+            partition: None,
+        };
+        for value in &[1.0, 3.0, 3.0, 5000.0] {
+            recorder.record_histogram(Histogram::BatchSize, labels, *value);
+        }
+        let rendered = recorder.render();
+        assert!(rendered.contains("agentdb_batch_size_bucket{root=\"root\",le=\"1\"} 1\n"));
+        assert!(rendered.contains("agentdb_batch_size_bucket{root=\"root\",le=\"2\"} 1\n"));
+        assert!(rendered.contains("agentdb_batch_size_bucket{root=\"root\",le=\"5\"} 3\n"));
+        assert!(rendered.contains("agentdb_batch_size_bucket{root=\"root\",le=\"1000\"} 3\n"));
+        assert!(rendered.contains("agentdb_batch_size_bucket{root=\"root\",le=\"+Inf\"} 4\n"));
+        assert!(rendered.contains("agentdb_batch_size_sum{root=\"root\"} 5007\n"));
+        assert!(rendered.contains("agentdb_batch_size_count{root=\"root\"} 4\n"));
+    }
+}
@@ -1,54 +1,170 @@
-use std::{future::Future, sync::Arc, time::Duration};
+use std::{
+    collections::HashSet,
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::anyhow;
 use foundationdb::{
     directory::Directory, options::MutationType, tuple::Versionstamp, KeySelector, RangeOption,
     TransactOption, Transaction,
 };
-use futures::{future::FusedFuture, select, FutureExt, TryStreamExt};
+use futures::{
+    future::FusedFuture, select, stream::FuturesUnordered, FutureExt, StreamExt, TryStreamExt,
+};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use crate::{
     blob,
     cancellation::Cancellation,
+    changes::{record_change, ChangeKind},
     client::PartitionRange,
+    dead_letter::{move_to_dead_letter, DeadLetterFailure},
     directories::{Global, PartitionSpace, RootSpace},
     error::Error,
-    message::send_messages,
+    history::record_version,
+    lease::{check_lease, release_lease, try_acquire_lease},
+    message::{
+        change_scheduled_messages, release_message_content, send_messages_from,
+        unindex_scheduled_message, MessageSource,
+    },
+    metrics::{self, Counter, Histogram, Labels},
+    policy::DeadLetterPolicy,
+    trace::record_delivery,
     utils::{
         get_first_in_range, load_partition_range, load_value, move_entries,
-        partition_for_recipient, save_value, Timestamp,
+        partition_for_recipient, range_is_empty, save_value, Timestamp,
     },
-    HookContext, InboundMessage, MessageHeader, StateFn, StateFnInput, StateFnLiveMode,
-    StateFnMode, MAX_BATCH_SIZE,
+    ClientConfig, HookContext, InboundMessage, MessageHeader, Priority, StateFn, StateFnInput,
+    StateFnLiveMode, StateFnMode,
 };
 
-const MAX_POLL_INTERVAL_SECS: u64 = 120;
 const MAX_AGENT_COUNTS: u32 = 256;
+const MAX_EXPIRED_COUNTS: u32 = 256;
+const BATCH_SIZE_INCREMENT: usize = 10;
 
 #[derive(Debug, thiserror::Error)]
-#[error("State function returned an error")]
-struct StateFnError;
+#[error("State function returned an error: {message}")]
+struct StateFnError {
+    message: String,
+    // The key of the last message in the failed batch, if it had any messages
+    last_key: Option<Vec<u8>>,
+}
 
 struct PartitionState {
+    client_id: Uuid,
     global: Arc<Global>,
     root: Arc<RootSpace>,
     partition: Arc<PartitionSpace>,
     cancellation: Cancellation,
     state_fn: StateFn,
+    config: Arc<ClientConfig>,
 }
 
 #[derive(Debug, Copy, Clone)]
 struct FoundRecipient {
     id: Uuid,
+    lane: u8,
     retry_at: Option<Timestamp>,
+    batch_size: usize,
+    in_flight: bool,
+    preempted: bool,
 }
 
 #[derive(Serialize, Deserialize)]
-struct RetryAtState {
-    retry_at: Timestamp,
-    backoff: Duration,
+pub(crate) struct RetryAtState {
+    pub(crate) retry_at: Timestamp,
+    pub(crate) backoff: Duration,
+    pub(crate) attempts: u32,
+    pub(crate) first_attempt_ts: Timestamp,
+    pub(crate) last_error: Option<String>,
+}
+
+impl RetryAtState {
+    // The state of an agent which is making its first attempt to process a batch
+    fn first_attempt(ts: Timestamp, initial_backoff: Duration) -> Self {
+        Self {
+            retry_at: ts,
+            backoff: initial_backoff,
+            attempts: 1,
+            first_attempt_ts: ts,
+            last_error: None,
+        }
+    }
+    // Moves on to the next attempt once the agent is due to retry, doubling the time
+    // to wait before the attempt after. The last error is cleared to mark the attempt
+    // as in progress.
+    fn next_attempt(&mut self) {
+        self.retry_at += self.backoff;
+        self.backoff += self.backoff;
+        self.attempts += 1;
+        self.last_error = None;
+    }
+}
+
+fn labels<'a>(root: &'a RootSpace, partition: &PartitionSpace) -> Labels<'a> {
+    Labels {
+        root: &root.root,
+        partition: Some(partition.partition),
+    }
+}
+
+// Counts every attempt after the first as a retry
+fn count_attempt(attempt: &mut u32, labels: Labels<'_>) {
+    if *attempt > 0 {
+        metrics::increment_counter(Counter::TransactionRetries, labels, 1);
+    }
+    *attempt += 1;
+}
+
+// Records that a recipient's batch is no longer in flight, keeping hold of the first
+// error encountered.
+fn finish_batch(
+    in_flight_recipients: &mut HashSet<Uuid>,
+    first_error: &mut Option<Error>,
+    (recipient_id, res): (Uuid, Result<(), Error>),
+) {
+    in_flight_recipients.remove(&recipient_id);
+    if let Err(e) = res {
+        first_error.get_or_insert(e);
+    }
+}
+
+pub(crate) fn is_expired(msg_hdr: &MessageHeader, ts: Timestamp) -> bool {
+    msg_hdr
+        .expires_at
+        .map_or(false, |expires_at| expires_at <= ts)
+}
+
+// Drops a message which expired before it could be delivered
+async fn drop_expired(
+    tx: &Transaction,
+    root: &RootSpace,
+    key: &[u8],
+    msg_hdr: &MessageHeader,
+) -> Result<(), Error> {
+    tracing::debug!(
+        operation_id = %msg_hdr.operation_id,
+        message_id = %msg_hdr.message_id,
+        "Dropping expired message"
+    );
+    tx.clear(key);
+    release_message_content(tx, root, msg_hdr).await
+}
+
+fn count_expired(tx: &Transaction, root: &RootSpace, partition: &PartitionSpace, count: u64) {
+    if count > 0 {
+        let expired_count_key = root
+            .expired_counts
+            .pack(&(partition.partition % MAX_EXPIRED_COUNTS));
+        tx.atomic_op(
+            &expired_count_key,
+            &(count as i64).to_le_bytes(),
+            MutationType::Add,
+        );
+    }
 }
 
 pub(crate) fn mark_partition_modified(tx: &Transaction, partition: &PartitionSpace) {
@@ -62,102 +178,202 @@ pub(crate) fn mark_partition_modified(tx: &Transaction, partition: &PartitionSpa
 
 impl PartitionState {
     pub fn new(
+        client_id: Uuid,
         global: Arc<Global>,
         root: Arc<RootSpace>,
         partition: Arc<PartitionSpace>,
         state_fn: StateFn,
+        config: Arc<ClientConfig>,
         cancellation: Cancellation,
     ) -> Self {
         Self {
+            client_id,
             global,
             root,
             partition,
             cancellation,
             state_fn,
+            config,
         }
     }
-    async fn rollup_messages(&mut self) -> Result<impl Future + FusedFuture, Error> {
-        // Roll up all the messages in the partition into batches, and get back a future that
-        // will resolve when either a new message is added, or a scheduled message becomes ready.
-        self.global
+    // Waits until we hold the lease on this partition. Returns `false` if we
+    // were cancelled or asked to drain before the lease became available.
+    async fn acquire_lease(&mut self) -> Result<bool, Error> {
+        loop {
+            let expired_ts = Timestamp::now() - self.config.heartbeat_interval * 2;
+            let maybe_watch_fut = self
+                .global
+                .db()
+                .transact_boxed(
+                    (&self.root, &self.partition, self.client_id),
+                    |tx, &mut (root, partition, client_id)| {
+                        try_acquire_lease(tx, root, partition, client_id, expired_ts).boxed()
+                    },
+                    TransactOption::idempotent(),
+                )
+                .await?;
+
+            let watch_fut = if let Some(watch_fut) = maybe_watch_fut {
+                watch_fut
+            } else {
+                return Ok(true);
+            };
+
+            log::info!(
+                "Waiting for partition {} to be released",
+                self.partition.partition
+            );
+            let mut draining = self.cancellation.draining().boxed().fuse();
+            select! {
+                _ = tokio::time::timeout(self.config.heartbeat_interval, watch_fut).fuse() => {},
+                _ = &mut self.cancellation => return Ok(false),
+                _ = draining => return Ok(false),
+            }
+        }
+    }
+    // Rolls up the ready messages in the partition into batches, and gets back a future that
+    // will resolve when either a new message is added, or a scheduled message becomes ready.
+    // At most `rollup_chunk_size` messages are rolled up at once to stay within transaction
+    // limits, so also returns `true` if there may be more messages ready to roll up.
+    async fn rollup_messages(&mut self) -> Result<(impl Future + FusedFuture, bool), Error> {
+        let max_poll_interval = self.config.max_poll_interval;
+        let rollup_chunk_size = self.config.rollup_chunk_size;
+        let (msg_count, expired_count, more, watch_fut) = self
+            .global
             .db()
             .transact_boxed(
-                &self.partition,
-                |tx, &mut partition| {
+                (&self.root, &self.partition, self.client_id, 0),
+                |tx, &mut (root, partition, client_id, ref mut attempt)| {
+                    count_attempt(attempt, labels(root, partition));
                     async move {
+                        check_lease(tx, partition, client_id).await?;
+
                         let ts = Timestamp::now();
-                        let mut past_message_range: RangeOption =
-                            partition.message.nested_range2(&(), &(ts,)).into();
-                        past_message_range.limit = Some(u16::MAX as usize);
-                        let future_message_range: RangeOption =
-                            partition.message.nested_range2(&(ts,), &()).into();
+                        let mut msg_index: u16 = 0;
+                        let mut expired_count = 0;
+                        let mut delay = max_poll_interval;
 
-                        // Find all messages which are ready to be received
-                        let mut msg_stream = tx.get_ranges(past_message_range.clone(), true);
+                        // Expired messages count towards the chunk size too, since dropping
+                        // them also adds to the size of the transaction
+                        let mut remaining = rollup_chunk_size;
 
-                        // Group the messages by recipient
-                        let mut msg_index = 0;
-                        while let Some(msgs) = msg_stream.try_next().await? {
-                            for msg in msgs {
-                                // Decode the message header
-                                let msg_hdr: MessageHeader = postcard::from_bytes(msg.value())?;
-
-                                // Figure out where the message should be batched.
-                                let batch_key = partition.batch.pack(&(
-                                    msg_hdr.recipient_id,
-                                    Versionstamp::incomplete(msg_index),
-                                ));
-                                msg_index += 1;
-                                tx.atomic_op(
-                                    &batch_key,
-                                    msg.value(),
-                                    MutationType::SetVersionstampedKey,
-                                );
-                                tx.clear(msg.key());
+                        // Roll up the highest priority lanes first, so that a backlog of
+                        // bulk messages cannot delay more urgent messages
+                        for priority in Priority::ALL {
+                            let lane = priority.lane();
+                            if remaining > 0 {
+                                // Find all messages which are ready to be received
+                                let mut past_message_range: RangeOption = partition
+                                    .message
+                                    .nested_range2(&(lane,), &(lane, ts))
+                                    .into();
+                                past_message_range.limit = Some(remaining);
+                                let mut msg_stream = tx.get_ranges(past_message_range, true);
+
+                                // Group the messages by recipient
+                                while let Some(msgs) = msg_stream.try_next().await? {
+                                    for msg in msgs {
+                                        // Decode the message header
+                                        let msg_hdr = MessageHeader::decode(msg.value())?;
+                                        let (_, when, _, _) =
+                                            partition.message.unpack(msg.key())?;
+                                        remaining -= 1;
+                                        unindex_scheduled_message(tx, root, &msg_hdr, when);
+                                        if is_expired(&msg_hdr, ts) {
+                                            drop_expired(tx, root, msg.key(), &msg_hdr).await?;
+                                            expired_count += 1;
+                                            continue;
+                                        }
+
+                                        // Figure out where the message should be batched.
+                                        let batch_key = partition.batch.pack(&(
+                                            lane,
+                                            msg_hdr.recipient_id,
+                                            Versionstamp::incomplete(msg_index),
+                                        ));
+                                        msg_index += 1;
+                                        tx.atomic_op(
+                                            &batch_key,
+                                            msg.value(),
+                                            MutationType::SetVersionstampedKey,
+                                        );
+                                        tx.clear(msg.key());
+                                    }
+                                }
                             }
-                        }
-                        log::info!(
-                            "Rolled up {} message(s) in partition {}",
-                            msg_index,
-                            partition.partition
-                        );
 
-                        // Find out how long to wait for the next scheduled message.
-                        // Or just wait two minutes if there's no scheduled message.
-                        let mut delay = Duration::from_secs(MAX_POLL_INTERVAL_SECS);
-                        if let Some(msg) =
-                            get_first_in_range(tx, future_message_range.clone(), true).await?
-                        {
-                            if let Ok((next_ts, _, _)) = partition.message.unpack(msg.key()) {
-                                delay = next_ts - ts;
+                            // Find out how long to wait for the next scheduled message.
+                            // Or just wait for the poll interval if there's no scheduled message.
+                            let future_message_range: RangeOption = partition
+                                .message
+                                .nested_range2(&(lane, ts), &(lane,))
+                                .into();
+                            if let Some(msg) =
+                                get_first_in_range(tx, future_message_range, true).await?
+                            {
+                                if let Ok((_, next_ts, _, _)) = partition.message.unpack(msg.key())
+                                {
+                                    delay = delay.min(next_ts - ts);
+                                }
                             }
                         }
+                        let more = remaining == 0;
+                        if more {
+                            log::info!(
+                                "Rolled up {} message(s) in partition {}, more remaining",
+                                msg_index,
+                                partition.partition
+                            );
+                        } else {
+                            log::info!(
+                                "Rolled up {} message(s) in partition {}",
+                                msg_index,
+                                partition.partition
+                            );
+                        }
+                        count_expired(tx, root, partition, expired_count);
 
-                        Ok::<_, Error>(
+                        Ok::<_, Error>((
+                            msg_index,
+                            expired_count,
+                            more,
                             tokio::time::timeout(delay, tx.watch(&partition.modified)).fuse(),
-                        )
+                        ))
                     }
                     .boxed()
                 },
                 TransactOption::idempotent(),
             )
-            .await
+            .await?;
+
+        let labels = labels(&self.root, &self.partition);
+        metrics::increment_counter(Counter::MessagesRolledUp, labels, u64::from(msg_count));
+        if expired_count > 0 {
+            metrics::increment_counter(Counter::MessagesExpired, labels, expired_count);
+        }
+        Ok((watch_fut, more))
     }
 
     // Finds the next recipient in the range with pending batched messages. Returns
-    // the recipient even if it's not ready to retry. The caller should skip those
-    // recipients.
+    // the recipient even if it's not ready to retry, or if it already has a batch in
+    // flight. The caller should skip those recipients. The retry state of a recipient
+    // with a batch in flight is left alone, as that batch is still being attempted.
+    // Also checks whether messages are ready to be rolled up in a higher priority lane
+    // than the recipient's, in which case the caller should go back and roll them up.
     async fn find_next_recipient(
-        &mut self,
+        &self,
         batch_range: RangeOption<'static>,
+        in_flight_recipients: &HashSet<Uuid>,
     ) -> Result<Option<FoundRecipient>, Error> {
+        let initial_retry_backoff = self.config.initial_retry_backoff;
+        let max_batch_size = self.config.max_batch_size;
         self.global
             .db()
             .transact_boxed(
-                (&self.partition, batch_range),
-                |tx, &mut (partition, ref batch_range)| {
+                (&self.partition, batch_range, in_flight_recipients),
+                |tx, &mut (partition, ref batch_range, in_flight_recipients)| {
                     async move {
-                        let (recipient_id, _) = if let Some(msg) =
+                        let (lane, recipient_id, _) = if let Some(msg) =
                             get_first_in_range(tx, batch_range.clone(), false).await?
                         {
                             partition.batch.unpack(msg.key())?
@@ -165,6 +381,38 @@ impl PartitionState {
                             return Ok(None);
                         };
 
+                        // Snapshot reads, so that new messages don't conflict with the search
+                        let ts = Timestamp::now();
+                        let mut preempted = false;
+                        for higher_lane in 0..lane {
+                            let range = partition
+                                .message
+                                .nested_range2(&(higher_lane,), &(higher_lane, ts));
+                            if !range_is_empty(tx, range.into(), true).await? {
+                                preempted = true;
+                                break;
+                            }
+                        }
+
+                        if in_flight_recipients.contains(&recipient_id) {
+                            return Ok(Some(FoundRecipient {
+                                id: recipient_id,
+                                lane,
+                                retry_at: None,
+                                batch_size: max_batch_size,
+                                in_flight: true,
+                                preempted,
+                            }));
+                        }
+
+                        // Start from the batch size which last worked for this agent
+                        let batch_size_key = partition.agent_batch_size.pack(&recipient_id);
+                        let batch_size = load_value::<u64>(tx, &batch_size_key, true)
+                            .await?
+                            .map_or(max_batch_size, |batch_size| {
+                                (batch_size as usize).clamp(1, max_batch_size)
+                            });
+
                         let retry_at_key = partition.agent_retry.pack(&recipient_id);
                         let retry_at_state = if let Some(mut retry_at_state) =
                             load_value::<RetryAtState>(tx, &retry_at_key, false).await?
@@ -172,25 +420,29 @@ impl PartitionState {
                             if retry_at_state.retry_at > Timestamp::now() {
                                 return Ok(Some(FoundRecipient {
                                     id: recipient_id,
+                                    lane,
                                     retry_at: Some(retry_at_state.retry_at),
+                                    batch_size,
+                                    in_flight: false,
+                                    preempted,
                                 }));
                             } else {
-                                retry_at_state.retry_at += retry_at_state.backoff;
-                                retry_at_state.backoff += retry_at_state.backoff;
+                                retry_at_state.next_attempt();
                             }
                             retry_at_state
                         } else {
-                            RetryAtState {
-                                retry_at: Timestamp::now(),
-                                backoff: Duration::from_secs(1),
-                            }
+                            RetryAtState::first_attempt(Timestamp::now(), initial_retry_backoff)
                         };
 
                         save_value(tx, &retry_at_key, &retry_at_state);
 
                         Ok::<_, Error>(Some(FoundRecipient {
                             id: recipient_id,
+                            lane,
                             retry_at: None,
+                            batch_size,
+                            in_flight: false,
+                            preempted,
                         }))
                     }
                     .boxed()
@@ -200,22 +452,16 @@ impl PartitionState {
             .await
     }
 
-    async fn process_batch(
-        &mut self,
-        batch_range: RangeOption<'static>,
-    ) -> Result<Option<FoundRecipient>, Error> {
-        let recipient = if let Some(recipient) = self.find_next_recipient(batch_range).await? {
-            recipient
-        } else {
-            return Ok(None);
-        };
-
-        // Skip the recipient if it's not ready to retry from an error
-        if recipient.retry_at.is_some() {
-            return Ok(Some(recipient));
-        }
-
-        let max_batch_size = MAX_BATCH_SIZE;
+    #[tracing::instrument(
+        skip_all,
+        fields(
+            root = %self.root.root,
+            partition = self.partition.partition,
+            agent_id = %recipient.id,
+            operation_id = tracing::field::Empty
+        )
+    )]
+    async fn process_batch(&self, recipient: FoundRecipient) -> Result<(), Error> {
         match self
             .global
             .db()
@@ -225,49 +471,108 @@ impl PartitionState {
                     &self.root,
                     &self.partition,
                     &self.state_fn,
-                    max_batch_size,
+                    self.client_id,
+                    self.config.max_batch_size,
+                    recipient.batch_size,
+                    0,
                 ),
-                |tx, &mut (global, root, partition, state_fn, ref mut max_batch_size)| {
+                |tx,
+                 &mut (
+                    global,
+                    root,
+                    partition,
+                    state_fn,
+                    client_id,
+                    configured_batch_size,
+                    ref mut max_batch_size,
+                    ref mut attempt,
+                )| {
+                    count_attempt(attempt, labels(root, partition));
                     async move {
+                        check_lease(tx, partition, client_id).await?;
+
                         // Automatically reduce batch size on failure
+                        let starting_batch_size = *max_batch_size;
                         if *max_batch_size > 1 {
                             *max_batch_size >>= 1;
                         }
 
                         // Load the initial agent state
                         let recipient_state =
-                            blob::load_internal(tx, root, recipient.id, false).await?;
+                            blob::load_internal(tx, global, root, recipient.id, false).await?;
 
                         // Determine the range of keys where messages are batched
-                        let mut recipient_range: RangeOption =
-                            partition.batch.nested_range(&(recipient.id,)).into();
+                        let mut recipient_range: RangeOption = partition
+                            .batch
+                            .nested_range(&(recipient.lane, recipient.id))
+                            .into();
                         recipient_range.limit = Some(*max_batch_size);
 
-                        // Load and clear all the message IDs
+                        // Load and clear all the message IDs, dropping any messages which
+                        // expired while waiting in the batch
+                        let ts = Timestamp::now();
                         let mut all_msg_hdrs = Vec::new();
+                        let mut last_key = None;
+                        let mut expired_count = 0;
                         let mut msg_stream = tx.get_ranges(recipient_range, false);
                         while let Some(msgs) = msg_stream.try_next().await? {
                             for msg in msgs {
                                 // Decode the message header
-                                let msg_hdr: MessageHeader = postcard::from_bytes(msg.value())?;
+                                let msg_hdr = MessageHeader::decode(msg.value())?;
+                                if is_expired(&msg_hdr, ts) {
+                                    drop_expired(tx, root, msg.key(), &msg_hdr).await?;
+                                    expired_count += 1;
+                                    continue;
+                                }
+                                tracing::debug!(
+                                    operation_id = %msg_hdr.operation_id,
+                                    message_id = %msg_hdr.message_id,
+                                    "Delivering message"
+                                );
                                 tx.clear(msg.key());
+                                last_key = Some(msg.key().to_vec());
                                 all_msg_hdrs.push(msg_hdr);
                             }
                         }
+                        count_expired(tx, root, partition, expired_count);
+
+                        // There's nothing to deliver if every message expired
+                        if all_msg_hdrs.is_empty() && expired_count > 0 {
+                            tx.clear(&partition.agent_retry.pack(&recipient.id));
+                            return Ok((None, 0, expired_count));
+                        }
+
+                        // A batch may contain messages from several operations
+                        let mut operation_ids: Vec<_> =
+                            all_msg_hdrs.iter().map(|hdr| hdr.operation_id).collect();
+                        operation_ids.sort_unstable();
+                        operation_ids.dedup();
+                        let operation_ids: Vec<_> =
+                            operation_ids.iter().map(Uuid::to_string).collect();
+                        tracing::Span::current().record(
+                            "operation_id",
+                            &tracing::field::display(operation_ids.join(",")),
+                        );
 
                         // Load all the message contents
                         let mut all_msgs = Vec::with_capacity(all_msg_hdrs.len());
-                        for msg_hdr in all_msg_hdrs {
+                        let mut delivered = Vec::with_capacity(all_msg_hdrs.len());
+                        for (index, msg_hdr) in all_msg_hdrs.into_iter().enumerate() {
+                            if let Some(info) = &msg_hdr.trace {
+                                record_delivery(tx, root, &msg_hdr, info, index as u16);
+                            }
+                            delivered.push((msg_hdr.operation_id, msg_hdr.message_id));
                             let inbound_msg = InboundMessage {
                                 operation_id: msg_hdr.operation_id,
-                                data: blob::load_internal(tx, root, msg_hdr.blob_id, true)
+                                data: blob::load_internal(tx, global, root, msg_hdr.blob_id, true)
                                     .await?
                                     .ok_or_else(|| {
                                         Error(anyhow!("Blob not found: {}", msg_hdr.blob_id))
                                     })?,
+                                attachment: msg_hdr.attachment,
                             };
                             all_msgs.push(inbound_msg);
-                            blob::delete_internal(tx, root, msg_hdr.blob_id);
+                            release_message_content(tx, root, &msg_hdr).await?;
                         }
 
                         log::info!(
@@ -280,6 +585,7 @@ impl PartitionState {
                             mode: StateFnMode::Live(StateFnLiveMode {
                                 user_dir: &root.user_dir,
                                 operation_ts: &root.operation_ts,
+                                budget_policy: &root.budget_policy,
                                 global,
                                 tx,
                             }),
@@ -288,13 +594,43 @@ impl PartitionState {
                             state: recipient_state,
                             messages: all_msgs,
                         };
+                        let batch_size = state_fn_input.messages.len();
                         let exist_before = state_fn_input.state.is_some();
-                        let state_fn_output =
-                            state_fn(state_fn_input).await.map_err(|_| StateFnError)?;
+                        let started = Instant::now();
+                        let state_fn_result = state_fn(state_fn_input).await;
+                        metrics::record_histogram(
+                            Histogram::StateFnLatency,
+                            labels(root, partition),
+                            started.elapsed().as_secs_f64(),
+                        );
+                        let state_fn_output = state_fn_result.map_err(|e| StateFnError {
+                            message: format!("{:#}", e.0),
+                            last_key,
+                        })?;
                         let exist_after = state_fn_output.state.is_some();
 
+                        // Retain the new state in the agent's history, unless the agent
+                        // never existed at all
+                        let state = state_fn_output.state.as_deref();
+                        if exist_before || exist_after {
+                            record_version(tx, global, root, recipient.id, state).await?;
+                        }
+
+                        // Record the change in the root's change feed
+                        let change_kind = match (exist_before, exist_after) {
+                            (false, false) => None,
+                            (false, true) => Some(ChangeKind::Created),
+                            (true, true) => Some(ChangeKind::Updated),
+                            (true, false) => Some(ChangeKind::Destroyed),
+                        };
+                        if let Some(change_kind) = change_kind {
+                            record_change(tx, global, root, recipient.id, change_kind, state)
+                                .await?;
+                        }
+
                         if let Some(state) = state_fn_output.state {
-                            blob::store_internal(tx, root, recipient.id, &state);
+                            let policy = blob::load_compression_policy(tx, root).await?;
+                            blob::store_internal(tx, global, root, recipient.id, &state, &policy)?;
                         } else {
                             blob::delete_internal(tx, root, recipient.id);
 
@@ -305,11 +641,40 @@ impl PartitionState {
                                 .map_err(Error::from_dir)?;
                         }
 
-                        send_messages(tx, global, &state_fn_output.messages, 0).await?;
+                        send_messages_from(
+                            tx,
+                            global,
+                            &state_fn_output.messages,
+                            0,
+                            Some(MessageSource {
+                                sender_id: recipient.id,
+                                delivered: &delivered,
+                            }),
+                        )
+                        .await?;
+                        change_scheduled_messages(tx, global, &state_fn_output.message_changes, 0)
+                            .await?;
 
                         // Clear the "retry_at" flag from this agent
                         tx.clear(&partition.agent_retry.pack(&recipient.id));
 
+                        // Remember the batch size which worked for this agent, growing it
+                        // back towards the configured maximum after each batch which was
+                        // filled with delivered messages, not counting expired ones. This
+                        // is the size before it was reduced, so it's on the same scale as
+                        // the configured maximum.
+                        let batch_size_key = partition.agent_batch_size.pack(&recipient.id);
+                        let learned_batch_size = if batch_size >= *max_batch_size {
+                            (starting_batch_size + BATCH_SIZE_INCREMENT).min(configured_batch_size)
+                        } else {
+                            starting_batch_size
+                        };
+                        if !exist_after || learned_batch_size >= configured_batch_size {
+                            tx.clear(&batch_size_key);
+                        } else if learned_batch_size != recipient.batch_size {
+                            save_value(tx, &batch_size_key, &(learned_batch_size as u64));
+                        }
+
                         // If agent was created or destroyed
                         if exist_before != exist_after {
                             let agent_key = root.agents.pack(&recipient.id);
@@ -331,7 +696,11 @@ impl PartitionState {
                             );
                         }
 
-                        Ok::<_, Error>(state_fn_output.commit_hook)
+                        Ok::<_, Error>((
+                            Some(state_fn_output.commit_hook),
+                            batch_size,
+                            expired_count,
+                        ))
                     }
                     .boxed()
                 },
@@ -343,60 +712,348 @@ impl PartitionState {
             )
             .await
         {
-            Ok(commit_hook) => {
-                // Call the commit hook
-                commit_hook(HookContext {
-                    global: self.global.clone(),
-                });
+            Ok((maybe_commit_hook, batch_size, expired_count)) => {
+                let labels = labels(&self.root, &self.partition);
+                if expired_count > 0 {
+                    metrics::increment_counter(Counter::MessagesExpired, labels, expired_count);
+                }
+                if let Some(commit_hook) = maybe_commit_hook {
+                    metrics::increment_counter(Counter::BatchesProcessed, labels, 1);
+                    metrics::record_histogram(Histogram::BatchSize, labels, batch_size as f64);
+
+                    // Call the commit hook
+                    commit_hook(HookContext {
+                        global: self.global.clone(),
+                    });
+                    metrics::increment_counter(Counter::CommitHooks, labels, 1);
+                }
             }
-            Err(e) if e.0.is::<StateFnError>() => {
-                // If the error was returned by the state function,
-                // there's nothing we can do to progress the agent,
-                // so just move on.
-                log::error!("{}", e);
+            Err(e) => {
+                if let Some(state_fn_error) = e.0.downcast_ref::<StateFnError>() {
+                    // If the error was returned by the state function,
+                    // there's nothing we can do to progress the agent,
+                    // so record the failure and move on.
+                    log::error!("{}", e);
+                    self.record_state_fn_failure(recipient, state_fn_error)
+                        .await?;
+                } else {
+                    return Err(e);
+                }
             }
-            Err(e) => return Err(e),
         }
 
-        Ok(Some(recipient))
+        Ok(())
+    }
+
+    // Records the error against the agent's retry state, or moves the failing
+    // messages to the dead-letter queue if the root's policy says we should
+    // give up on them.
+    async fn record_state_fn_failure(
+        &self,
+        recipient: FoundRecipient,
+        error: &StateFnError,
+    ) -> Result<(), Error> {
+        self.global
+            .db()
+            .transact_boxed(
+                (&self.root, &self.partition, self.client_id, recipient, error),
+                |tx, &mut (root, partition, client_id, recipient, error)| {
+                    async move {
+                        check_lease(tx, partition, client_id).await?;
+
+                        let recipient_id = recipient.id;
+                        let retry_at_key = partition.agent_retry.pack(&recipient_id);
+                        let mut retry_at_state = if let Some(retry_at_state) =
+                            load_value::<RetryAtState>(tx, &retry_at_key, false).await?
+                        {
+                            retry_at_state
+                        } else {
+                            return Ok(());
+                        };
+
+                        let policy =
+                            load_value::<DeadLetterPolicy>(tx, &root.dead_letter_policy, true)
+                                .await?
+                                .unwrap_or_default();
+
+                        let attempts_exhausted = policy
+                            .max_attempts
+                            .map_or(false, |max_attempts| retry_at_state.attempts >= max_attempts);
+                        if attempts_exhausted {
+                            let failure = DeadLetterFailure {
+                                error: error.message.clone(),
+                                attempts: retry_at_state.attempts,
+                                first_attempt_ts: retry_at_state.first_attempt_ts,
+                                dead_lettered_ts: Timestamp::now(),
+                            };
+                            let count = if let Some(last_key) = &error.last_key {
+                                move_to_dead_letter(
+                                    tx,
+                                    root,
+                                    partition,
+                                    recipient.lane,
+                                    recipient_id,
+                                    last_key,
+                                    &failure,
+                                )
+                                .await?
+                            } else {
+                                0
+                            };
+                            log::warn!(
+                                "Moved {} message(s) for agent {} to the dead-letter queue after {} attempt(s)",
+                                count,
+                                recipient_id,
+                                failure.attempts
+                            );
+
+                            // Give any remaining messages a fresh start
+                            tx.clear(&retry_at_key);
+                        } else {
+                            retry_at_state.last_error = Some(error.message.clone());
+                            save_value(tx, &retry_at_key, &retry_at_state);
+                        }
+
+                        Ok::<_, Error>(())
+                    }
+                    .boxed()
+                },
+                TransactOption::idempotent(),
+            )
+            .await
     }
-    async fn process_batches(&mut self) -> Result<Option<Timestamp>, Error> {
+    // Processes batches for up to `max_concurrent_batches` recipients at once. An agent
+    // may have batches in several lanes, so we skip any recipient which already has a
+    // batch in flight: there is never more than one batch in flight for the same agent.
+    async fn process_batches(&self) -> Result<Option<Timestamp>, Error> {
         // Begin with the entire partition range
         let mut batch_range: RangeOption = self.partition.batch.range().into();
 
         // If no messages found, retry after the maximum interval
-        let mut overall_retry_at =
-            Some(Timestamp::now() + Duration::from_secs(MAX_POLL_INTERVAL_SECS));
+        let mut overall_retry_at = Some(Timestamp::now() + self.config.max_poll_interval);
 
-        while let Some(recipient) = self.process_batch(batch_range.clone()).await? {
-            // If we found and processed a batch, advance our range to exclude that agent
-            batch_range.begin = KeySelector::first_greater_or_equal(
-                self.partition.batch.nested_range(&(recipient.id,)).1,
-            );
+        let mut in_flight = FuturesUnordered::new();
+        let mut in_flight_recipients = HashSet::new();
+        let mut first_error = None;
+        let mut preempted = false;
+
+        let res = async {
+            // When draining, finish the batches in progress but don't start any more
+            while !preempted && first_error.is_none() && !self.cancellation.is_draining() {
+                // Wait for a slot to become available
+                while in_flight.len() >= self.config.max_concurrent_batches {
+                    if let Some(done) = in_flight.next().await {
+                        finish_batch(&mut in_flight_recipients, &mut first_error, done);
+                    }
+                }
+
+                // Keep the batches in flight progressing while we look for the next recipient.
+                // A batch which finishes meanwhile is still treated as in flight by this
+                // search, so that its agent's retry state isn't touched.
+                let maybe_recipient = {
+                    let in_flight_snapshot = in_flight_recipients.clone();
+                    let mut find_fut = self
+                        .find_next_recipient(batch_range.clone(), &in_flight_snapshot)
+                        .boxed()
+                        .fuse();
+                    loop {
+                        select! {
+                            res = find_fut => break res?,
+                            done = in_flight.select_next_some() => {
+                                finish_batch(&mut in_flight_recipients, &mut first_error, done);
+                            }
+                        }
+                    }
+                };
+                // Stop once there are no more recipients, or once any batch has failed
+                let recipient = match maybe_recipient {
+                    Some(recipient) if first_error.is_none() => recipient,
+                    _ => break,
+                };
+
+                if recipient.in_flight {
+                    // Come back for this batch as soon as the agent's current batch is done
+                    overall_retry_at = None;
+                } else if recipient.retry_at.is_none() {
+                    // Skip the recipient if it's not ready to retry from an error
+                    in_flight_recipients.insert(recipient.id);
+                    in_flight.push(
+                        self.process_batch(recipient)
+                            .map(move |res| (recipient.id, res)),
+                    );
+                }
+
+                // If we found a batch, advance our range to exclude that agent
+                batch_range.begin = KeySelector::first_greater_or_equal(
+                    self.partition
+                        .batch
+                        .nested_range(&(recipient.lane, recipient.id))
+                        .1,
+                );
 
-            // Use the smallest retry interval of all batches we process, or `None` if any batch can
-            // be retried immediately.
-            overall_retry_at = overall_retry_at.min(recipient.retry_at);
+                // Use the smallest retry interval of all batches we process, or `None` if any
+                // batch can be retried immediately.
+                overall_retry_at = overall_retry_at.min(recipient.retry_at);
+
+                // Go back and roll up any higher priority messages which arrived while we were
+                // processing a lower priority lane, rather than waiting for the lane to empty
+                preempted = recipient.preempted;
+            }
+            Ok::<_, Error>(())
+        }
+        .await;
+
+        // Never abandon a batch part way through, as its commit hook must still run. Keep
+        // going even if one of them fails, and report the first error afterwards.
+        while let Some(done) = in_flight.next().await {
+            finish_batch(&mut in_flight_recipients, &mut first_error, done);
+        }
+        res?;
+        if let Some(e) = first_error {
+            return Err(e);
+        }
+
+        if preempted {
+            Ok(None)
+        } else {
+            Ok(overall_retry_at)
+        }
+    }
+    // Moves messages which were sent before messages had a priority into the lane for
+    // normal priority messages. Legacy message keys start with a timestamp rather than a
+    // lane: those of immediate messages sort within the first lane after its current
+    // keys, and those of scheduled messages sort after the last lane. Legacy batch keys
+    // start with the recipient ID, so also sort after the last lane.
+    async fn migrate_legacy_messages(&self) -> Result<(), Error> {
+        let legacy_message = self
+            .partition
+            .message
+            .cast::<(Timestamp, Versionstamp, u32)>();
+        let legacy_batch = self.partition.batch.cast::<(Uuid, Versionstamp)>();
+        let end_lane = Priority::ALL.len() as u8;
+        let message_ranges = [
+            legacy_message.subrange(
+                (Timestamp::zero(), Versionstamp::complete([0; 10], 0), 0)
+                    ..=(
+                        Timestamp::zero(),
+                        Versionstamp::complete([0xff; 10], u16::MAX),
+                        u32::MAX,
+                    ),
+            ),
+            (
+                self.partition.message.nested_range(&(end_lane,)).0,
+                self.partition.message.range().1,
+            ),
+        ];
+        let batch_range = (
+            self.partition.batch.nested_range(&(end_lane,)).0,
+            self.partition.batch.range().1,
+        );
+        let rollup_chunk_size = self.config.rollup_chunk_size;
+
+        loop {
+            let count = self
+                .global
+                .db()
+                .transact_boxed(
+                    (
+                        &self.partition,
+                        self.client_id,
+                        (&legacy_message, &legacy_batch),
+                        (&message_ranges, &batch_range),
+                    ),
+                    |tx,
+                     &mut (
+                        partition,
+                        client_id,
+                        (legacy_message, legacy_batch),
+                        (message_ranges, batch_range),
+                    )| {
+                        async move {
+                            check_lease(tx, partition, client_id).await?;
+                            let lane = Priority::Normal.lane();
+                            let mut remaining = rollup_chunk_size;
+
+                            for range in message_ranges {
+                                if remaining == 0 {
+                                    break;
+                                }
+                                let mut range: RangeOption = range.clone().into();
+                                range.limit = Some(remaining);
+                                let mut msg_stream = tx.get_ranges(range, false);
+                                while let Some(msgs) = msg_stream.try_next().await? {
+                                    for msg in msgs {
+                                        let (when, version, idx) =
+                                            legacy_message.unpack(msg.key())?;
+                                        let msg_hdr = MessageHeader::decode(msg.value())?;
+                                        tx.set(
+                                            &partition.message.pack(&(lane, when, version, idx)),
+                                            &msg_hdr.encode(),
+                                        );
+                                        tx.clear(msg.key());
+                                        remaining -= 1;
+                                    }
+                                }
+                            }
+
+                            if remaining > 0 {
+                                let mut range: RangeOption = batch_range.clone().into();
+                                range.limit = Some(remaining);
+                                let mut msg_stream = tx.get_ranges(range, false);
+                                while let Some(msgs) = msg_stream.try_next().await? {
+                                    for msg in msgs {
+                                        let (recipient_id, version) =
+                                            legacy_batch.unpack(msg.key())?;
+                                        let msg_hdr = MessageHeader::decode(msg.value())?;
+                                        tx.set(
+                                            &partition.batch.pack(&(lane, recipient_id, version)),
+                                            &msg_hdr.encode(),
+                                        );
+                                        tx.clear(msg.key());
+                                        remaining -= 1;
+                                    }
+                                }
+                            }
+
+                            if remaining < rollup_chunk_size {
+                                mark_partition_modified(tx, partition);
+                            }
+                            Ok::<_, Error>(rollup_chunk_size - remaining)
+                        }
+                        .boxed()
+                    },
+                    TransactOption::idempotent(),
+                )
+                .await?;
+            if count == 0 {
+                return Ok(());
+            }
+            log::info!(
+                "Migrated {} legacy message(s) in partition {}",
+                count,
+                self.partition.partition
+            );
         }
-        Ok(overall_retry_at)
     }
     async fn migrate_messages(&self, partition_range_send: PartitionRange) -> Result<(), Error> {
         log::info!(
             "Migrating messages from partition {} to new partitions",
             self.partition.partition
         );
-        // Migrate unbatched messages
+        // Migrate unbatched messages, a chunk at a time to stay within transaction limits
         let mut partition_message_range: RangeOption = self.partition.message.range().into();
-        partition_message_range.limit = Some(u16::MAX as usize);
+        partition_message_range.limit = Some(self.config.rollup_chunk_size);
 
         while move_entries(
             self.global.db(),
             self,
             partition_message_range.clone(),
+            &self.root.partition_range_send,
+            partition_range_send,
             |item, this| {
                 async move {
                     let key_parts = this.partition.message.unpack(item.key())?;
-                    let msg_hdr = postcard::from_bytes::<MessageHeader>(item.value())?;
+                    let msg_hdr = MessageHeader::decode(item.value())?;
                     let new_partition_idx =
                         partition_for_recipient(msg_hdr.recipient_id, partition_range_send);
                     let new_partition =
@@ -412,17 +1069,19 @@ impl PartitionState {
 
         // Migrate batched messages
         let mut partition_batch_range: RangeOption = self.partition.batch.range().into();
-        partition_batch_range.limit = Some(u16::MAX as usize);
+        partition_batch_range.limit = Some(self.config.rollup_chunk_size);
 
         while move_entries(
             self.global.db(),
             self,
             partition_batch_range.clone(),
+            &self.root.partition_range_send,
+            partition_range_send,
             |item, this| {
                 async move {
                     let key_parts = this.partition.batch.unpack(item.key())?;
                     let new_partition_idx =
-                        partition_for_recipient(key_parts.0, partition_range_send);
+                        partition_for_recipient(key_parts.1, partition_range_send);
                     let new_partition =
                         this.root.partition(&this.global, new_partition_idx).await?;
                     let new_key = new_partition.batch.pack(&key_parts);
@@ -463,23 +1122,34 @@ impl PartitionState {
 
         Ok(())
     }
+    #[tracing::instrument(
+        skip_all,
+        fields(root = %self.root.root, partition = self.partition.partition)
+    )]
     async fn step(&mut self) -> Result<(), Error> {
         self.maybe_migrate_messages().await?;
-        let watch_fut = self.rollup_messages().await?;
+        let (watch_fut, rollup_incomplete) = self.rollup_messages().await?;
         let maybe_retry_at = self.process_batches().await?;
 
+        // Deliver what we have so far before rolling up the rest of a large backlog
+        if rollup_incomplete {
+            return Ok(());
+        }
+
         // If there was nothing to process, sleep until there is a new message
         if let Some(retry_at) = maybe_retry_at {
             let duration = retry_at - Timestamp::now();
+            let mut draining = self.cancellation.draining().boxed().fuse();
             select! {
                 _ = tokio::time::timeout(duration, watch_fut).fuse() => {},
                 _ = &mut self.cancellation => {},
+                _ = draining => {},
             }
         }
         Ok(())
     }
-    pub async fn run(mut self) -> Result<(), Error> {
-        while !self.cancellation.is_cancelled() {
+    pub async fn run(&mut self) -> Result<(), Error> {
+        while !self.cancellation.is_cancelled() && !self.cancellation.is_draining() {
             self.step().await?;
         }
         Ok(())
@@ -487,37 +1157,69 @@ impl PartitionState {
 }
 
 pub(crate) async fn partition_task_inner(
+    client_id: Uuid,
     global: Arc<Global>,
     root: Arc<RootSpace>,
     partition: u32,
     state_fn: StateFn,
+    config: Arc<ClientConfig>,
     cancellation: Cancellation,
 ) -> Result<(), Error> {
     let partition = root.partition(&global, partition).await?;
-    let partition_state = PartitionState::new(
+    let mut partition_state = PartitionState::new(
+        client_id,
         global,
         root,
         partition,
         state_fn.clone(),
+        config,
         cancellation.clone(),
     );
-    partition_state.run().await
+    if partition_state.acquire_lease().await? {
+        partition_state.migrate_legacy_messages().await?;
+        partition_state.run().await?;
+    }
+    Ok(())
+}
+
+// Releases our lease on the partition so that another client can take it over
+// without waiting for our heartbeat to expire.
+async fn release_partition(
+    client_id: Uuid,
+    global: &Global,
+    root: &RootSpace,
+    partition: u32,
+) -> Result<(), Error> {
+    let partition = root.partition(global, partition).await?;
+    global
+        .db()
+        .transact_boxed(
+            (&partition, client_id),
+            |tx, &mut (partition, client_id)| release_lease(tx, partition, client_id).boxed(),
+            TransactOption::idempotent(),
+        )
+        .await?;
+    Ok(())
 }
 
 pub(crate) async fn partition_task(
+    client_id: Uuid,
     global: Arc<Global>,
     root: Arc<RootSpace>,
     partition: u32,
     state_fn: StateFn,
+    config: Arc<ClientConfig>,
     cancellation: Cancellation,
 ) {
     log::info!("Starting partition {}", partition);
-    while !cancellation.is_cancelled() {
+    while !cancellation.is_cancelled() && !cancellation.is_draining() {
         if let Err(e) = partition_task_inner(
+            client_id,
             global.clone(),
             root.clone(),
             partition,
             state_fn.clone(),
+            config.clone(),
             cancellation.clone(),
         )
         .await
@@ -526,5 +1228,572 @@ pub(crate) async fn partition_task(
             tokio::time::sleep(Duration::from_secs(5)).await;
         }
     }
+    if let Err(e) = release_partition(client_id, &global, &root, partition).await {
+        log::error!("Failed to release partition {}: {:?}", partition, e);
+    }
     log::info!("Stopping partition {}", partition);
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc,
+        },
+    };
+
+    use futures::future::BoxFuture;
+
+    use super::*;
+    use crate::{
+        admin,
+        cancellation::{spawn_cancellable, CancellableHandle},
+        send_messages,
+        test_utils::{commit, test_header, test_message, TestRoot},
+        StateFnOutput, DEFAULT_PARTITION_RANGE,
+    };
+
+    fn unused_state_fn(_input: StateFnInput<'_>) -> BoxFuture<'_, Result<StateFnOutput, Error>> {
+        unreachable!("The state function is not called")
+    }
+
+    // Creates the agent if it doesn't exist, and otherwise ignores its messages.
+    fn create_state_fn(_input: StateFnInput<'_>) -> BoxFuture<'_, Result<StateFnOutput, Error>> {
+        futures::future::ok(StateFnOutput {
+            state: Some(b"state".to_vec()),
+            messages: Vec::new(),
+            message_changes: Vec::new(),
+            commit_hook: Box::new(|_ctx| {}),
+        })
+        .boxed()
+    }
+
+    fn failing_state_fn(_input: StateFnInput<'_>) -> BoxFuture<'_, Result<StateFnOutput, Error>> {
+        futures::future::err(Error(anyhow!("Failed"))).boxed()
+    }
+
+    static ACTIVE_BATCHES: AtomicUsize = AtomicUsize::new(0);
+    static MAX_ACTIVE_BATCHES: AtomicUsize = AtomicUsize::new(0);
+
+    // Takes a while to handle each batch, keeping track of how many it is handling at once.
+    fn slow_state_fn(input: StateFnInput<'_>) -> BoxFuture<'_, Result<StateFnOutput, Error>> {
+        async move {
+            let active = ACTIVE_BATCHES.fetch_add(1, Ordering::SeqCst) + 1;
+            MAX_ACTIVE_BATCHES.fetch_max(active, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            ACTIVE_BATCHES.fetch_sub(1, Ordering::SeqCst);
+            Ok(StateFnOutput {
+                state: input.state,
+                messages: Vec::new(),
+                message_changes: Vec::new(),
+                commit_hook: Box::new(|_ctx| {}),
+            })
+        }
+        .boxed()
+    }
+
+    // Takes the lease on the partition, and returns a state for processing it. The
+    // state is cancelled when the returned handle is dropped.
+    async fn test_partition_state(
+        root: &TestRoot,
+        partition: &Arc<PartitionSpace>,
+        state_fn: StateFn,
+    ) -> Result<(PartitionState, CancellableHandle<()>), Error> {
+        let client_id = Uuid::new_v4();
+        let tx = root.tx();
+        assert!(
+            try_acquire_lease(&tx, &root.space, partition, client_id, Timestamp::now())
+                .await?
+                .is_none()
+        );
+        commit(tx).await?;
+
+        let (cancellation_tx, cancellation_rx) = mpsc::channel();
+        let handle = spawn_cancellable(move |cancellation| {
+            cancellation_tx.send(cancellation).unwrap();
+            futures::future::ready(())
+        });
+        let partition_state = PartitionState::new(
+            client_id,
+            root.global.clone(),
+            root.space.clone(),
+            partition.clone(),
+            state_fn,
+            Arc::new(ClientConfig::default()),
+            cancellation_rx.recv().unwrap(),
+        );
+        Ok((partition_state, handle))
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn legacy_messages_are_moved_to_normal_lane() -> Result<(), Error> {
+        let root = TestRoot::new().await;
+        let partition = root.partition(0).await;
+        let (partition_state, _handle) =
+            test_partition_state(&root, &partition, Arc::new(unused_state_fn)).await?;
+        let tx = root.tx();
+
+        // Write messages in the layout used before messages had a priority
+        let legacy_header = |header: &MessageHeader| {
+            postcard::to_stdvec(&(header.recipient_id, header.blob_id, header.operation_id))
+                .unwrap()
+        };
+        let (immediate, scheduled, batched) = (
+            test_header(Uuid::new_v4()),
+            test_header(Uuid::new_v4()),
+            test_header(Uuid::new_v4()),
+        );
+        let version = Versionstamp::complete([1; 10], 0);
+        let scheduled_ts = Timestamp::from_millis(Timestamp::now().millis() + 60_000);
+        let legacy_message = partition.message.cast::<(Timestamp, Versionstamp, u32)>();
+        tx.set(
+            &legacy_message.pack(&(Timestamp::zero(), version.clone(), 0)),
+            &legacy_header(&immediate),
+        );
+        tx.set(
+            &legacy_message.pack(&(scheduled_ts, version.clone(), 1)),
+            &legacy_header(&scheduled),
+        );
+        tx.set(
+            &partition
+                .batch
+                .cast::<(Uuid, Versionstamp)>()
+                .pack(&(batched.recipient_id, version.clone())),
+            &legacy_header(&batched),
+        );
+
+        // A message in the current layout shares a prefix with legacy immediate messages
+        let mut high = test_header(Uuid::new_v4());
+        high.priority = Priority::High;
+        let high_lane = Priority::High.lane();
+        tx.set(
+            &partition
+                .message
+                .pack(&(high_lane, Timestamp::zero(), version.clone(), 0)),
+            &high.encode(),
+        );
+        commit(tx).await?;
+        partition_state.migrate_legacy_messages().await?;
+
+        let tx = root.tx();
+        let lane = Priority::Normal.lane();
+        let values = tx
+            .get_range(&partition.message.range().into(), 0, true)
+            .await?;
+        let keys = values
+            .iter()
+            .map(|value| partition.message.unpack(value.key()))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(
+            keys,
+            vec![
+                (high_lane, Timestamp::zero(), version.clone(), 0),
+                (lane, Timestamp::zero(), version.clone(), 0),
+                (lane, scheduled_ts, version.clone(), 1),
+            ]
+        );
+        let message_ids = values
+            .iter()
+            .map(|value| Ok(MessageHeader::decode(value.value())?.message_id))
+            .collect::<Result<Vec<_>, Error>>()?;
+        assert_eq!(
+            message_ids,
+            vec![high.message_id, immediate.message_id, scheduled.message_id]
+        );
+
+        let values = tx
+            .get_range(&partition.batch.range().into(), 0, true)
+            .await?;
+        assert_eq!(values.len(), 1);
+        assert_eq!(
+            partition.batch.unpack(values[0].key())?,
+            (lane, batched.recipient_id, version)
+        );
+        let header = MessageHeader::decode(values[0].value())?;
+        assert_eq!(header.message_id, batched.message_id);
+        assert_eq!(header.priority, Priority::Normal);
+
+        root.cleanup().await;
+        Ok(())
+    }
+
+    #[test]
+    fn retry_backoff_doubles_with_each_attempt() {
+        let ts = Timestamp::now();
+        let backoff = Duration::from_secs(1);
+        let mut state = RetryAtState::first_attempt(ts, backoff);
+        assert_eq!(state.attempts, 1);
+        assert_eq!(state.retry_at, ts);
+
+        state.last_error = Some("failed".into());
+        state.next_attempt();
+        assert_eq!(state.attempts, 2);
+        assert_eq!(state.retry_at, ts + backoff);
+        assert_eq!(state.backoff, backoff * 2);
+        assert_eq!(state.first_attempt_ts, ts);
+        assert!(state.last_error.is_none());
+
+        state.next_attempt();
+        assert_eq!(state.attempts, 3);
+        assert_eq!(state.retry_at, ts + backoff * 3);
+        assert_eq!(state.backoff, backoff * 4);
+    }
+
+    #[test]
+    fn messages_expire_at_their_deadline() {
+        let mut header = test_header(Uuid::new_v4());
+        let ts = Timestamp::now();
+        assert!(!is_expired(&header, ts));
+        header.expires_at = Some(ts);
+        assert!(is_expired(&header, ts));
+        assert!(!is_expired(&header, ts - Duration::from_secs(1)));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn expired_messages_are_dropped_on_rollup() -> Result<(), Error> {
+        let root = TestRoot::new().await;
+        let recipient_id = Uuid::new_v4();
+        let partition = root
+            .partition(partition_for_recipient(
+                recipient_id,
+                DEFAULT_PARTITION_RANGE,
+            ))
+            .await;
+        let (mut partition_state, _handle) =
+            test_partition_state(&root, &partition, Arc::new(unused_state_fn)).await?;
+
+        let mut expired = test_message(&root.name, recipient_id, b"expired");
+        expired.expires_at = Some(Timestamp::now() - Duration::from_secs(60));
+        let mut live = test_message(&root.name, recipient_id, b"live");
+        live.expires_at = Some(Timestamp::now() + Duration::from_secs(60));
+        let (expired_id, live_id) = (expired.message_id, live.message_id);
+        let tx = root.tx();
+        send_messages(&tx, &root.global, &[expired, live], 0).await?;
+        commit(tx).await?;
+
+        let tx = root.tx();
+        let mut blob_ids = HashMap::new();
+        for value in tx
+            .get_range(&partition.message.range().into(), 0, true)
+            .await?
+            .iter()
+        {
+            let msg_hdr = MessageHeader::decode(value.value())?;
+            blob_ids.insert(msg_hdr.message_id, msg_hdr.blob_id);
+        }
+        drop(tx);
+
+        let (_, more) = partition_state.rollup_messages().await?;
+        assert!(!more);
+
+        // Only the live message is batched, and the expired message's blob is deleted
+        let tx = root.tx();
+        let values = tx
+            .get_range(&partition.batch.range().into(), 0, true)
+            .await?;
+        assert_eq!(values.len(), 1);
+        assert_eq!(
+            MessageHeader::decode(values[0].value())?.message_id,
+            live_id
+        );
+        assert!(
+            blob::load_internal(&tx, &root.global, &root.space, blob_ids[&expired_id], true)
+                .await?
+                .is_none()
+        );
+        assert!(
+            blob::load_internal(&tx, &root.global, &root.space, blob_ids[&live_id], true)
+                .await?
+                .is_some()
+        );
+        assert_eq!(
+            admin::describe_root(&root.global, &root.name)
+                .await?
+                .expired_message_count(),
+            1
+        );
+
+        root.cleanup().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn only_the_failed_batch_is_dead_lettered() -> Result<(), Error> {
+        let root = TestRoot::new().await;
+        let recipient_id = Uuid::new_v4();
+        let partition = root
+            .partition(partition_for_recipient(
+                recipient_id,
+                DEFAULT_PARTITION_RANGE,
+            ))
+            .await;
+        let (mut partition_state, _handle) =
+            test_partition_state(&root, &partition, Arc::new(failing_state_fn)).await?;
+        admin::set_dead_letter_policy(
+            &root.global,
+            &root.name,
+            DeadLetterPolicy {
+                max_attempts: Some(1),
+            },
+        )
+        .await?;
+
+        // The first message expires after it is batched, but before it is delivered
+        let mut expiring = test_message(&root.name, recipient_id, b"expiring");
+        expiring.expires_at = Some(Timestamp::now() + Duration::from_millis(500));
+        let messages = [
+            expiring,
+            test_message(&root.name, recipient_id, b"first"),
+            test_message(&root.name, recipient_id, b"second"),
+        ];
+        let message_ids: Vec<_> = messages.iter().map(|msg| msg.message_id).collect();
+        let tx = root.tx();
+        send_messages(&tx, &root.global, &messages, 0).await?;
+        commit(tx).await?;
+        let (_, more) = partition_state.rollup_messages().await?;
+        assert!(!more);
+        tokio::time::sleep(Duration::from_millis(600)).await;
+
+        // Both delivered messages are dead-lettered, and the expired one is left to be dropped
+        partition_state.process_batches().await?;
+        let tx = root.tx();
+        for (i, message_id) in message_ids.into_iter().enumerate() {
+            assert_eq!(
+                tx.get(&root.space.dead_letter.pack(&message_id), true)
+                    .await?
+                    .is_some(),
+                i > 0
+            );
+        }
+        let values = tx
+            .get_range(&partition.batch.range().into(), 0, true)
+            .await?;
+        assert_eq!(values.len(), 1);
+
+        root.cleanup().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn batches_for_one_agent_are_not_processed_concurrently() -> Result<(), Error> {
+        let root = TestRoot::new().await;
+        let recipient_id = Uuid::new_v4();
+        let partition = root
+            .partition(partition_for_recipient(
+                recipient_id,
+                DEFAULT_PARTITION_RANGE,
+            ))
+            .await;
+        let (mut partition_state, _handle) =
+            test_partition_state(&root, &partition, Arc::new(slow_state_fn)).await?;
+
+        // Leave the agent with a batch in each of two lanes
+        let mut high = test_message(&root.name, recipient_id, b"high");
+        high.priority = Priority::High;
+        let normal = test_message(&root.name, recipient_id, b"normal");
+        let tx = root.tx();
+        send_messages(&tx, &root.global, &[high, normal], 0).await?;
+        commit(tx).await?;
+        let (_, more) = partition_state.rollup_messages().await?;
+        assert!(!more);
+
+        // Only the high priority batch is processed, and the other is picked up straight away
+        // on the next pass
+        assert_eq!(partition_state.process_batches().await?, None);
+        assert_eq!(MAX_ACTIVE_BATCHES.load(Ordering::SeqCst), 1);
+        let tx = root.tx();
+        let values = tx
+            .get_range(&partition.batch.range().into(), 0, true)
+            .await?;
+        assert_eq!(values.len(), 1);
+        assert_eq!(
+            partition.batch.unpack(values[0].key())?.0,
+            Priority::Normal.lane()
+        );
+
+        // Finding the agent again while its batch was in flight didn't count as a retry
+        assert!(tx
+            .get(&partition.agent_retry.pack(&recipient_id), true)
+            .await?
+            .is_none());
+
+        partition_state.process_batches().await?;
+        assert_eq!(MAX_ACTIVE_BATCHES.load(Ordering::SeqCst), 1);
+        let tx = root.tx();
+        assert!(tx
+            .get_range(&partition.batch.range().into(), 0, true)
+            .await?
+            .is_empty());
+
+        root.cleanup().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn learned_batch_size_is_used_and_grows_back() -> Result<(), Error> {
+        let root = TestRoot::new().await;
+        let recipient_id = Uuid::new_v4();
+        let partition_id = partition_for_recipient(recipient_id, DEFAULT_PARTITION_RANGE);
+        let partition = root.partition(partition_id).await;
+        let (mut partition_state, _handle) =
+            test_partition_state(&root, &partition, Arc::new(create_state_fn)).await?;
+
+        // Pretend an earlier batch for this agent was too large
+        let batch_size_key = partition.agent_batch_size.pack(&recipient_id);
+        let messages = (0..7)
+            .map(|_| test_message(&root.name, recipient_id, b"content"))
+            .collect::<Vec<_>>();
+        let tx = root.tx();
+        save_value(&tx, &batch_size_key, &5u64);
+        send_messages(&tx, &root.global, &messages, 0).await?;
+        commit(tx).await?;
+        let (_, more) = partition_state.rollup_messages().await?;
+        assert!(!more);
+
+        // Like the configured maximum, the learned size is halved on the first attempt,
+        // after which a full batch grows it
+        partition_state.process_batches().await?;
+        let tx = root.tx();
+        let values = tx
+            .get_range(&partition.batch.range().into(), 0, true)
+            .await?;
+        assert_eq!(values.len(), 5);
+        assert_eq!(
+            load_value::<u64>(&tx, &batch_size_key, true).await?,
+            Some(5 + BATCH_SIZE_INCREMENT as u64)
+        );
+        let root_desc = admin::describe_root(&root.global, &root.name).await?;
+        assert_eq!(
+            root_desc.partitions()[&partition_id].learned_batch_sizes(),
+            &[(recipient_id, 5 + BATCH_SIZE_INCREMENT)]
+        );
+
+        // A batch smaller than the learned size doesn't grow it
+        partition_state.process_batches().await?;
+        let tx = root.tx();
+        assert!(tx
+            .get_range(&partition.batch.range().into(), 0, true)
+            .await?
+            .is_empty());
+        assert_eq!(
+            load_value::<u64>(&tx, &batch_size_key, true).await?,
+            Some(5 + BATCH_SIZE_INCREMENT as u64)
+        );
+
+        // Once the size grows back to the configured maximum, it is forgotten
+        let max_batch_size = ClientConfig::default().max_batch_size;
+        let learned_batch_size = max_batch_size - 1;
+        let messages = (0..learned_batch_size / 2)
+            .map(|_| test_message(&root.name, recipient_id, b"content"))
+            .collect::<Vec<_>>();
+        save_value(&tx, &batch_size_key, &(learned_batch_size as u64));
+        send_messages(&tx, &root.global, &messages, 0).await?;
+        commit(tx).await?;
+        let (_, more) = partition_state.rollup_messages().await?;
+        assert!(!more);
+        partition_state.process_batches().await?;
+        let tx = root.tx();
+        assert!(tx
+            .get_range(&partition.batch.range().into(), 0, true)
+            .await?
+            .is_empty());
+        assert_eq!(load_value::<u64>(&tx, &batch_size_key, true).await?, None);
+
+        root.cleanup().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn large_backlog_is_rolled_up_in_chunks() -> Result<(), Error> {
+        let root = TestRoot::new().await;
+        let recipient_id = Uuid::new_v4();
+        let partition = root
+            .partition(partition_for_recipient(
+                recipient_id,
+                DEFAULT_PARTITION_RANGE,
+            ))
+            .await;
+        let (mut partition_state, _handle) =
+            test_partition_state(&root, &partition, Arc::new(unused_state_fn)).await?;
+        partition_state.config = Arc::new(ClientConfig::default().with_rollup_chunk_size(2));
+
+        let messages = (0..5)
+            .map(|_| test_message(&root.name, recipient_id, b"content"))
+            .collect::<Vec<_>>();
+        let tx = root.tx();
+        send_messages(&tx, &root.global, &messages, 0).await?;
+        commit(tx).await?;
+
+        for (expected_batched, expected_more) in [(2, true), (4, true), (5, false)] {
+            let (_, more) = partition_state.rollup_messages().await?;
+            assert_eq!(more, expected_more);
+            let tx = root.tx();
+            let batched = tx
+                .get_range(&partition.batch.range().into(), 0, true)
+                .await?;
+            assert_eq!(batched.len(), expected_batched);
+        }
+
+        root.cleanup().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn messages_are_migrated_in_chunks() -> Result<(), Error> {
+        let root = TestRoot::new().await;
+        let partition = root.partition(DEFAULT_PARTITION_RANGE.offset).await;
+        let (mut partition_state, _handle) =
+            test_partition_state(&root, &partition, Arc::new(unused_state_fn)).await?;
+        partition_state.config = Arc::new(ClientConfig::default().with_rollup_chunk_size(2));
+
+        // Move everything to a single new partition
+        let partition_range_send = PartitionRange {
+            offset: DEFAULT_PARTITION_RANGE.offset + DEFAULT_PARTITION_RANGE.count,
+            count: 1,
+        };
+        let new_partition = root.partition(partition_range_send.offset).await;
+        let lane = Priority::Normal.lane();
+        let tx = root.tx();
+        save_value(&tx, &root.space.partition_range_send, &partition_range_send);
+        for i in 0..5u8 {
+            let header = test_header(Uuid::new_v4());
+            let version = Versionstamp::complete([i; 10], 0);
+            tx.set(
+                &partition
+                    .message
+                    .pack(&(lane, Timestamp::zero(), version.clone(), 0)),
+                &header.encode(),
+            );
+            tx.set(
+                &partition.batch.pack(&(lane, header.recipient_id, version)),
+                &header.encode(),
+            );
+        }
+        commit(tx).await?;
+
+        partition_state
+            .migrate_messages(partition_range_send)
+            .await?;
+
+        let tx = root.tx();
+        for (from, to) in [
+            (partition.message.range(), new_partition.message.range()),
+            (partition.batch.range(), new_partition.batch.range()),
+        ] {
+            assert!(tx.get_range(&from.into(), 0, true).await?.is_empty());
+            assert_eq!(tx.get_range(&to.into(), 0, true).await?.len(), 5);
+        }
+
+        root.cleanup().await;
+        Ok(())
+    }
+}
@@ -0,0 +1,217 @@
+//! Policies which are stored per-root within FoundationDB, so that every
+//! client connected to a root behaves consistently. Policies can be
+//! inspected and changed at runtime using the [crate::admin] module.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+/// Controls when messages are moved to a root's dead-letter queue.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct DeadLetterPolicy {
+    /// The number of consecutive times the state function may fail for an
+    /// agent before the offending messages are moved to the dead-letter queue.
+    /// If `None`, the messages will be retried forever.
+    pub max_attempts: Option<u32>,
+}
+
+/// Controls the feedback-loop protection applied to operations sending messages
+/// to agents within a root.
+///
+/// Each operation accrues budget over time, up to a maximum burst size, and
+/// each message sent as part of the operation consumes one unit of budget.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BudgetPolicy {
+    /// The time taken for an operation to accrue enough budget to send one
+    /// more message. This limits the rate at which an operation can send
+    /// messages over long time scales.
+    pub message_interval: Duration,
+    /// The maximum number of messages an operation can send in a single burst.
+    pub max_burst: u32,
+}
+
+impl Default for BudgetPolicy {
+    fn default() -> Self {
+        Self {
+            message_interval: Duration::from_secs(1),
+            max_burst: 1000,
+        }
+    }
+}
+
+impl BudgetPolicy {
+    pub(crate) fn ms_per_msg(&self) -> i64 {
+        (self.message_interval.as_millis() as i64).max(1)
+    }
+    pub(crate) fn initial_ts_offset(&self) -> i64 {
+        self.ms_per_msg() * i64::from(self.max_burst)
+    }
+}
+
+/// Controls how partitions are divided between the clients connected to a root.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct AssignmentPolicy {
+    /// The strategy used to decide which client owns each partition.
+    pub strategy: AssignmentStrategy,
+    /// Partitions which should be owned by the client with the given name,
+    /// regardless of the strategy. If no client with that name is connected,
+    /// the partition is assigned according to the strategy as normal.
+    pub pinned: BTreeMap<u32, String>,
+}
+
+/// A strategy for assigning partitions to clients.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AssignmentStrategy {
+    /// Each client owns a contiguous slice of the partition range, in order
+    /// of client ID, sized according to its weight. When a client joins or
+    /// leaves, most partitions change owner.
+    Contiguous,
+    /// Each partition is owned by the client which scores highest for that
+    /// partition under weighted rendezvous hashing. When a client joins or
+    /// leaves, only the partitions belonging to that client change owner.
+    Rendezvous,
+}
+
+impl Default for AssignmentStrategy {
+    fn default() -> Self {
+        Self::Contiguous
+    }
+}
+
+/// Controls whether the delivery of messages to agents within a root is
+/// recorded, so that the causal graph of an operation can be inspected using
+/// [crate::admin::trace_operation].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TracePolicy {
+    /// Whether messages sent to agents within this root are traced. Only
+    /// messages sent while tracing is enabled will appear in the trace.
+    pub enabled: bool,
+    /// How long to keep the trace of an operation after the last message in
+    /// that operation was delivered.
+    pub retention: Duration,
+}
+
+impl Default for TracePolicy {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            retention: Duration::from_secs(60 * 60 * 24),
+        }
+    }
+}
+
+/// Controls how many previous versions of each agent's state are retained
+/// within a root, so that they can be inspected after the fact.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HistoryPolicy {
+    /// No history is retained.
+    Disabled,
+    /// Retain the given number of most recent versions of each agent's state.
+    KeepVersions(u32),
+    /// Retain the versions of each agent's state written within the given
+    /// duration.
+    KeepFor(Duration),
+}
+
+impl Default for HistoryPolicy {
+    fn default() -> Self {
+        Self::Disabled
+    }
+}
+
+/// Controls whether changes to the state of agents within a root are recorded
+/// in the root's change feed, which can be consumed using
+/// [crate::changes::changes_since].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeFeedPolicy {
+    /// Whether changes to agent state are recorded.
+    pub enabled: bool,
+    /// How long changes are kept in the feed. Consumers which fall further
+    /// behind than this will miss changes.
+    pub retention: Duration,
+}
+
+impl Default for ChangeFeedPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            retention: Duration::from_secs(60 * 60 * 24 * 7),
+        }
+    }
+}
+
+/// Controls how long idempotency keys recorded using [crate::idempotency::record_key]
+/// are retained within a root. A transaction which is retried after this period
+/// will not be recognised as a duplicate.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdempotencyPolicy {
+    /// How long idempotency keys are retained.
+    pub retention: Duration,
+}
+
+impl Default for IdempotencyPolicy {
+    fn default() -> Self {
+        Self {
+            retention: Duration::from_secs(60 * 60 * 24),
+        }
+    }
+}
+
+/// Controls how blobs, such as agent states and messages, are compressed when
+/// they are stored within a root. Blobs are always readable regardless of the
+/// policy they were stored with.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompressionPolicy {
+    /// The compression algorithm to use.
+    pub algorithm: CompressionAlgorithm,
+    /// Blobs smaller than this many bytes are stored uncompressed.
+    pub min_size: usize,
+}
+
+impl Default for CompressionPolicy {
+    fn default() -> Self {
+        Self {
+            algorithm: CompressionAlgorithm::None,
+            min_size: 1024,
+        }
+    }
+}
+
+/// A compression algorithm for blobs.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CompressionAlgorithm {
+    /// Blobs are stored uncompressed.
+    None,
+    /// Zstandard compression, at the given compression level.
+    Zstd {
+        /// The compression level, from 1 to 22. Use 0 for the default level.
+        level: i32,
+    },
+    /// LZ4 compression, which is faster but compresses less than Zstandard.
+    Lz4,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn budget_accrues_one_message_per_interval() {
+        let policy = BudgetPolicy {
+            message_interval: Duration::from_millis(250),
+            max_burst: 8,
+        };
+        assert_eq!(policy.ms_per_msg(), 250);
+        assert_eq!(policy.initial_ts_offset(), 2000);
+    }
+
+    #[test]
+    fn zero_message_interval_is_clamped() {
+        let policy = BudgetPolicy {
+            message_interval: Duration::from_micros(10),
+            max_burst: 5,
+        };
+        assert_eq!(policy.ms_per_msg(), 1);
+        assert_eq!(policy.initial_ts_offset(), 5);
+    }
+}
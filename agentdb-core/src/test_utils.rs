@@ -0,0 +1,102 @@
+//! Helpers for tests which need a running FoundationDB cluster. Those tests are
+//! marked `#[ignore]`, and can be run with `cargo test -- --ignored` once the
+//! default cluster file points at a test cluster.
+
+use std::sync::{Arc, Once};
+
+use foundationdb::{directory::Directory, FdbError, Transaction};
+use uuid::Uuid;
+
+use crate::{
+    directories::{Global, PartitionSpace, RootSpace},
+    Error, MessageHeader, OutboundMessage, Priority, Timestamp,
+};
+
+static BOOT: Once = Once::new();
+
+/// Connect to the default FoundationDB cluster, starting the network thread
+/// the first time this is called. The network is left running until the test
+/// process exits.
+pub(crate) fn global() -> Arc<Global> {
+    BOOT.call_once(|| std::mem::forget(unsafe { foundationdb::boot() }));
+    Global::connect(None).expect("Failed to connect to FoundationDB")
+}
+
+/// A root with a unique name, so that tests can run concurrently against the
+/// same cluster.
+pub(crate) struct TestRoot {
+    pub global: Arc<Global>,
+    pub name: String,
+    pub space: Arc<RootSpace>,
+}
+
+impl TestRoot {
+    pub async fn new() -> Self {
+        let global = global();
+        let name = format!("test-{}", Uuid::new_v4());
+        let space = global.root(&name).await.expect("Failed to create root");
+        Self {
+            global,
+            name,
+            space,
+        }
+    }
+    pub async fn partition(&self, partition: u32) -> Arc<PartitionSpace> {
+        self.space
+            .partition(&self.global, partition)
+            .await
+            .expect("Failed to open partition")
+    }
+    pub fn tx(&self) -> Transaction {
+        self.global
+            .db()
+            .create_trx()
+            .expect("Failed to create transaction")
+    }
+    /// Remove everything stored under the root.
+    pub async fn cleanup(self) {
+        let tx = self.tx();
+        self.global
+            .dir()
+            .remove_if_exists(&tx, vec![self.name.clone()])
+            .await
+            .expect("Failed to remove root");
+        commit(tx).await.expect("Failed to commit");
+    }
+}
+
+pub(crate) async fn commit(tx: Transaction) -> Result<(), Error> {
+    tx.commit().await.map_err(FdbError::from)?;
+    Ok(())
+}
+
+/// A message header for a message to the given recipient, with no content.
+pub(crate) fn test_header(recipient_id: Uuid) -> MessageHeader {
+    let message_id = Uuid::new_v4();
+    MessageHeader {
+        recipient_id,
+        message_id,
+        blob_id: message_id,
+        operation_id: Uuid::new_v4(),
+        priority: Priority::Normal,
+        expires_at: None,
+        trace: None,
+        attachment: None,
+    }
+}
+
+/// A message with the given content, to be delivered immediately.
+pub(crate) fn test_message(root: &str, recipient_id: Uuid, content: &[u8]) -> OutboundMessage {
+    OutboundMessage {
+        message_id: Uuid::new_v4(),
+        recipient_root: root.into(),
+        recipient_id,
+        operation_id: Uuid::new_v4(),
+        when: Timestamp::zero(),
+        content: content.into(),
+        priority: Priority::Normal,
+        expires_at: None,
+        message_type: None,
+        attachment: None,
+    }
+}
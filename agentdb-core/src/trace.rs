@@ -0,0 +1,154 @@
+use byteorder::{ByteOrder, LittleEndian};
+use foundationdb::{options::MutationType, tuple::Versionstamp, RangeOption, Transaction};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{directories::RootSpace, error::Error, MessageHeader, Timestamp};
+
+// Carried in the header of messages sent to roots with tracing enabled.
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct TraceInfo {
+    pub sender_id: Option<Uuid>,
+    pub message_type: Option<String>,
+    // The messages being processed by the sender which belong to the same operation
+    pub caused_by: Vec<Uuid>,
+    pub sent_ts: Timestamp,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct TraceEntry {
+    pub message_id: Uuid,
+    pub recipient_id: Uuid,
+    pub info: TraceInfo,
+    pub delivered_ts: Timestamp,
+}
+
+// Records the delivery of a traced message against its operation. The `index`
+// must be unique within the transaction.
+pub(crate) fn record_delivery(
+    tx: &Transaction,
+    root: &RootSpace,
+    header: &MessageHeader,
+    info: &TraceInfo,
+    index: u16,
+) {
+    let delivered_ts = Timestamp::now();
+    let entry = TraceEntry {
+        message_id: header.message_id,
+        recipient_id: header.recipient_id,
+        info: info.clone(),
+        delivered_ts,
+    };
+    let key = root
+        .trace
+        .pack(&(header.operation_id, Versionstamp::incomplete(index)));
+    tx.atomic_op(
+        &key,
+        &postcard::to_stdvec(&entry).expect("Infallible serialization"),
+        MutationType::SetVersionstampedKey,
+    );
+
+    // Keep track of when the operation was last active, so that old traces can be removed
+    tx.atomic_op(
+        &root.trace_ts.pack(&header.operation_id),
+        &delivered_ts.millis().to_le_bytes(),
+        MutationType::Max,
+    );
+}
+
+// Removes the traces of operations which have not been active since `gc_ts`.
+pub(crate) async fn gc_traces(
+    tx: &Transaction,
+    root: &RootSpace,
+    gc_ts: i64,
+    range: impl Into<RangeOption<'_>>,
+    reverse: bool,
+    limit: usize,
+) -> Result<(), Error> {
+    let mut range = range.into();
+    range.limit = Some(limit);
+    range.reverse = reverse;
+    let mut stream = tx.get_ranges(range, true);
+    while let Some(values) = stream.try_next().await? {
+        for value in values {
+            if LittleEndian::read_i64(value.value()) < gc_ts {
+                let operation_id = root.trace_ts.unpack(value.key())?;
+                let (begin, end) = root.trace.nested_range(&(operation_id,));
+                tx.clear_range(&begin, &end);
+                tx.clear(value.key());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        admin,
+        test_utils::{commit, test_header, TestRoot},
+    };
+
+    #[tokio::test]
+    #[ignore]
+    async fn trace_records_causality_until_gc() -> Result<(), Error> {
+        let root = TestRoot::new().await;
+        let first = test_header(Uuid::new_v4());
+        let mut second = test_header(Uuid::new_v4());
+        second.operation_id = first.operation_id;
+        let info = |caused_by| TraceInfo {
+            sender_id: Some(first.recipient_id),
+            message_type: Some("test".into()),
+            caused_by,
+            sent_ts: Timestamp::now(),
+        };
+
+        let tx = root.tx();
+        record_delivery(&tx, &root.space, &first, &info(Vec::new()), 0);
+        record_delivery(&tx, &root.space, &second, &info(vec![first.message_id]), 1);
+        commit(tx).await?;
+
+        let trace = admin::trace_operation(&root.global, &root.name, first.operation_id).await?;
+        assert_eq!(trace.len(), 2);
+        assert_eq!(trace[0].message_id(), first.message_id);
+        assert_eq!(trace[1].message_id(), second.message_id);
+        assert_eq!(trace[1].caused_by(), &[first.message_id]);
+        assert_eq!(trace[1].message_type(), Some("test"));
+
+        // Traces of operations which are still active survive garbage collection
+        let tx = root.tx();
+        let old_ts = Timestamp::now().millis() - 60_000;
+        gc_traces(
+            &tx,
+            &root.space,
+            old_ts,
+            root.space.trace_ts.range(),
+            false,
+            10,
+        )
+        .await?;
+        commit(tx).await?;
+        let trace = admin::trace_operation(&root.global, &root.name, first.operation_id).await?;
+        assert_eq!(trace.len(), 2);
+
+        let tx = root.tx();
+        let future_ts = Timestamp::now().millis() + 60_000;
+        gc_traces(
+            &tx,
+            &root.space,
+            future_ts,
+            root.space.trace_ts.range(),
+            false,
+            10,
+        )
+        .await?;
+        commit(tx).await?;
+        let trace = admin::trace_operation(&root.global, &root.name, first.operation_id).await?;
+        assert!(trace.is_empty());
+
+        root.cleanup().await;
+        Ok(())
+    }
+}
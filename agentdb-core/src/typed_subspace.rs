@@ -97,6 +97,14 @@ impl<T> TypedSubspace<T> {
     pub fn range(&self) -> (Vec<u8>, Vec<u8>) {
         self.inner.range()
     }
+    /// Interpret the keys of this subspace as values of a different type. This is
+    /// only useful when migrating keys from an older layout.
+    pub fn cast<U>(&self) -> TypedSubspace<U> {
+        TypedSubspace {
+            inner: self.inner.clone(),
+            phantom: PhantomData,
+        }
+    }
 }
 
 fn advance_tuple_key(key: &mut [u8]) {
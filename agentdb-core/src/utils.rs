@@ -23,6 +23,18 @@ pub fn partition_for_recipient(recipient_id: Uuid, partition_range: PartitionRan
     (hash % partition_range.count) + partition_range.offset
 }
 
+// Scores a client for ownership of a partition. Each partition is owned by the
+// client with the highest score, so the scores must agree between clients.
+pub fn rendezvous_score(client_id: Uuid, partition: u32) -> u64 {
+    let hash = client_id.as_u128();
+    let hash = ((hash >> 64) ^ hash) as u64;
+    // Mix in the partition and apply the splitmix64 finalizer
+    let mut x = hash ^ u64::from(partition).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
 pub fn next_key(key: &[u8]) -> Vec<u8> {
     let mut v = Vec::with_capacity(key.len() + 1);
     v.extend_from_slice(key);
@@ -30,16 +42,25 @@ pub fn next_key(key: &[u8]) -> Vec<u8> {
     v
 }
 
+// Moves a batch of entries to new keys, as long as the partition range stored at
+// `partition_range_key` still matches the one the new keys were chosen for. Returns
+// `true` if there may be more entries to move.
 pub fn move_entries<'trx, D: 'trx + Send + Sync>(
     db: &'trx Database,
     data: D,
     range: RangeOption<'trx>,
+    partition_range_key: &'trx [u8],
+    partition_range: PartitionRange,
     conv: impl for<'a> FnMut(&'a FdbValue, &'a D) -> BoxFuture<'a, Result<Vec<u8>, Error>> + Send + 'trx,
 ) -> impl Future<Output = Result<bool, Error>> + Send + 'trx {
     db.transact_boxed(
-        (range, conv, data),
-        |tx, (range, conv, data)| {
+        (range, conv, data, partition_range_key, partition_range),
+        |tx, (range, conv, data, partition_range_key, partition_range)| {
             async move {
+                if load_partition_range(tx, partition_range_key, false).await? != *partition_range {
+                    return Ok(false);
+                }
+
                 let mut msg_stream = tx.get_ranges(range.clone(), false);
                 let mut more = false;
                 while let Some(batch) = msg_stream.try_next().await? {
@@ -2,9 +2,10 @@ use std::fmt::Debug;
 use std::marker::PhantomData;
 use std::sync::{Arc, Mutex};
 
+use agentdb_core::history::{self, StateVersion};
 use agentdb_core::{blob, Error, Global};
 use anyhow::anyhow;
-use foundationdb::{TransactOption, Transaction};
+use foundationdb::{tuple::Versionstamp, TransactOption, Transaction};
 use futures::{Future, FutureExt, Stream, TryStreamExt};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
@@ -63,6 +64,37 @@ impl DynAgentRef {
             )
             .await
     }
+    /// Load a previous version of this agent's state. Returns `None` if the
+    /// version is not retained, or if the agent did not exist in that version.
+    pub async fn load_at(
+        self,
+        global: &Global,
+        version: Versionstamp,
+    ) -> Result<Option<DynAgent>, Error> {
+        global
+            .db()
+            .transact_boxed(
+                global,
+                |tx, global| {
+                    history::load_at(tx, global, self.root.name(), self.id, version, true).boxed()
+                },
+                TransactOption::idempotent(),
+            )
+            .await
+            .map(|maybe_blob| maybe_blob.map(DynAgent))
+    }
+    /// List the retained versions of this agent's state, from oldest to newest.
+    /// Versions are only retained if enabled by the root's history policy.
+    pub async fn history(self, global: &Global) -> Result<Vec<StateVersion>, Error> {
+        global
+            .db()
+            .transact_boxed(
+                global,
+                |tx, global| history::list(tx, global, self.root.name(), self.id, true).boxed(),
+                TransactOption::idempotent(),
+            )
+            .await
+    }
     /// Watch for the first change to this agent's state.
     pub async fn watch(
         self,
@@ -172,6 +204,15 @@ impl<A: Agent> AgentRef<A> {
     pub async fn load(self, global: &Global) -> Result<Option<A>, Error> {
         Self::downcast_state(self.inner.load(global).await?)
     }
+    /// Load a previous version of this agent's state. Returns `None` if the
+    /// version is not retained, or if the agent did not exist in that version.
+    pub async fn load_at(self, global: &Global, version: Versionstamp) -> Result<Option<A>, Error> {
+        Self::downcast_state(self.inner.load_at(global, version).await?)
+    }
+    /// List the retained versions of this agent's state, from oldest to newest.
+    pub async fn history(self, global: &Global) -> Result<Vec<StateVersion>, Error> {
+        self.inner.history(global).await
+    }
     /// Watch for the first change to this agent's state.
     pub async fn watch(
         self,
@@ -1,5 +1,8 @@
 use agentdb_core::{
-    id, send_messages, Error, Global, HookContext, OutboundMessage, StateFnInput, Timestamp,
+    change_scheduled_messages, id,
+    idempotency::{self, CommitInfo},
+    send_messages, Error, Global, HookContext, OutboundMessage, Priority, ScheduledMessageChange,
+    StateFnInput, Timestamp,
 };
 use anyhow::anyhow;
 use foundationdb::directory::DirectoryOutput;
@@ -10,7 +13,7 @@ use uuid::Uuid;
 use crate::agent_ref::{AgentRef, DynAgentRef};
 use crate::constructor::{Construct, Constructor};
 use crate::handler::{Handle, Handler};
-use crate::message::{DynMessage, Message};
+use crate::message::{DynMessage, Message, MessageRef};
 use crate::root::Root;
 
 // Require the ability to burst 500 messages for safe clearance
@@ -19,12 +22,40 @@ const MIN_SAFE_CLEARANCE: i64 = 500;
 /// A function to run after an agent's new state has been committed.
 pub type CommitHook = Box<dyn FnOnce(HookContext) + Send + Sync + 'static>;
 
+fn change_scheduled(
+    messages: &mut Vec<OutboundMessage>,
+    message_changes: &mut Vec<ScheduledMessageChange>,
+    message: MessageRef,
+    when: Option<Timestamp>,
+) {
+    // Messages sent from this context have not been stored yet, so can be changed directly
+    if let Some(index) = messages
+        .iter()
+        .position(|msg| msg.message_id == message.id())
+    {
+        if let Some(when) = when {
+            messages[index].when = when;
+        } else {
+            messages.remove(index);
+        }
+    } else {
+        message_changes.push(ScheduledMessageChange {
+            recipient_root: message.root().to_string(),
+            message_id: message.id(),
+            when,
+        });
+    }
+}
+
 /// Context passed to an agent's state function
 pub struct Context<'a> {
     pub(crate) input: &'a StateFnInput<'a>,
     pub(crate) operation_id: Uuid,
     pub(crate) root: Root,
+    pub(crate) priority: Priority,
+    pub(crate) expires_at: Option<Timestamp>,
     pub(crate) messages: Vec<OutboundMessage>,
+    pub(crate) message_changes: Vec<ScheduledMessageChange>,
     pub(crate) commit_hooks: Vec<CommitHook>,
 }
 
@@ -34,14 +65,29 @@ impl<'a> ContextLike for Context<'a> {
         handle: DynAgentRef,
         message: DynMessage,
         when: Timestamp,
-    ) -> Result<(), Error> {
+    ) -> Result<MessageRef, Error> {
+        let message_id = id::new();
         self.messages.push(OutboundMessage {
+            message_id,
             recipient_root: handle.root().to_string(),
             recipient_id: handle.id(),
             operation_id: self.operation_id,
             when,
+            priority: self.priority,
+            expires_at: self.expires_at,
+            message_type: message.type_name(),
+            attachment: None,
             content: message.0,
         });
+        Ok(MessageRef::from_parts(handle.root(), message_id))
+    }
+
+    fn dyn_change_scheduled(
+        &mut self,
+        message: MessageRef,
+        when: Option<Timestamp>,
+    ) -> Result<(), Error> {
+        change_scheduled(&mut self.messages, &mut self.message_changes, message, when);
         Ok(())
     }
 
@@ -56,11 +102,23 @@ impl<'a> Context<'a> {
             input,
             root: Root::from_name(input.root),
             operation_id: Uuid::nil(),
+            priority: Priority::default(),
+            expires_at: None,
             messages: Vec::new(),
+            message_changes: Vec::new(),
             commit_hooks: Vec::new(),
         }
     }
 
+    /// Set the priority of messages subsequently sent from this context.
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+    }
+    /// Set the time after which messages subsequently sent from this context
+    /// should be dropped rather than delivered.
+    pub fn set_expires_at(&mut self, expires_at: Option<Timestamp>) {
+        self.expires_at = expires_at;
+    }
     /// Schedule `f` to be called after the agent's new state is committed.
     pub fn dyn_run_on_commit(&mut self, f: CommitHook) {
         self.commit_hooks.push(f);
@@ -105,7 +163,11 @@ impl<'a> Context<'a> {
 #[derive(Debug)]
 pub struct ExternalContext {
     operation_id: Uuid,
+    priority: Priority,
+    expires_at: Option<Timestamp>,
     messages: Vec<OutboundMessage>,
+    message_changes: Vec<ScheduledMessageChange>,
+    idempotency_key: Option<(Root, String)>,
 }
 
 impl ContextLike for ExternalContext {
@@ -114,14 +176,29 @@ impl ContextLike for ExternalContext {
         handle: DynAgentRef,
         message: DynMessage,
         when: Timestamp,
-    ) -> Result<(), Error> {
+    ) -> Result<MessageRef, Error> {
+        let message_id = id::new();
         self.messages.push(OutboundMessage {
+            message_id,
             recipient_root: handle.root().to_string(),
             recipient_id: handle.id(),
             operation_id: self.operation_id,
             when,
+            priority: self.priority,
+            expires_at: self.expires_at,
+            message_type: message.type_name(),
+            attachment: None,
             content: message.0,
         });
+        Ok(MessageRef::from_parts(handle.root(), message_id))
+    }
+
+    fn dyn_change_scheduled(
+        &mut self,
+        message: MessageRef,
+        when: Option<Timestamp>,
+    ) -> Result<(), Error> {
+        change_scheduled(&mut self.messages, &mut self.message_changes, message, when);
         Ok(())
     }
 
@@ -136,38 +213,85 @@ impl ExternalContext {
     pub fn new() -> Self {
         Self {
             operation_id: id::new(),
+            priority: Priority::default(),
+            expires_at: None,
             messages: Vec::new(),
+            message_changes: Vec::new(),
+            idempotency_key: None,
         }
     }
 
+    /// Set the priority of messages subsequently sent from this context.
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+    }
+    /// Set the time after which messages subsequently sent from this context
+    /// should be dropped rather than delivered.
+    pub fn set_expires_at(&mut self, expires_at: Option<Timestamp>) {
+        self.expires_at = expires_at;
+    }
+
+    /// Attach an idempotency key to this context. The key is recorded within the
+    /// given root when the context is run, and running another context with the
+    /// same key will have no effect for as long as the key is retained. This allows
+    /// callers to safely retry after an unknown commit result.
+    pub fn set_idempotency_key(&mut self, root: Root, key: impl Into<String>) {
+        self.idempotency_key = Some((root, key.into()));
+    }
+
     async fn run_internal(
         &self,
         global: &Global,
         tx: &Transaction,
         user_version: u16,
-    ) -> Result<(), Error> {
-        send_messages(tx, global, &self.messages, user_version).await
+    ) -> Result<Option<CommitInfo>, Error> {
+        if let Some((root, key)) = &self.idempotency_key {
+            if let Some(commit_info) = idempotency::check_key(tx, global, root.name(), key).await? {
+                return Ok(Some(commit_info));
+            }
+            idempotency::record_key(
+                tx,
+                global,
+                root.name(),
+                key,
+                self.operation_id,
+                user_version,
+            )
+            .await?;
+        }
+        send_messages(tx, global, &self.messages, user_version).await?;
+        change_scheduled_messages(tx, global, &self.message_changes, user_version).await?;
+        Ok(None)
     }
 
     /// Run all the side-effects accumulated within this context inside the
-    /// provided transaction.
+    /// provided transaction. If the context's idempotency key was already used,
+    /// nothing is done and information about the original commit is returned.
     pub async fn run_tx(
         self,
         global: &Global,
         tx: &Transaction,
         user_version: u16,
-    ) -> Result<(), Error> {
+    ) -> Result<Option<CommitInfo>, Error> {
         self.run_internal(global, tx, user_version).await
     }
 
-    /// Run all the side-effects accumulated within this context.
-    pub async fn run(self, global: &Global) -> Result<(), Error> {
+    /// Run all the side-effects accumulated within this context. If the context's
+    /// idempotency key was already used, nothing is done and information about the
+    /// original commit is returned.
+    pub async fn run(self, global: &Global) -> Result<Option<CommitInfo>, Error> {
+        // With an idempotency key, retrying after an unknown commit result is safe
+        let options = if self.idempotency_key.is_some() {
+            TransactOption::idempotent()
+        } else {
+            TransactOption::default()
+        };
         global
             .db()
             .transact_boxed(
                 (global, self),
                 |tx, &mut (global, ref this)| this.run_internal(global, tx, 0).boxed(),
-                TransactOption::default(),
+                options,
             )
             .await
     }
@@ -190,6 +314,15 @@ pub trait ContextLike {
         handle: DynAgentRef,
         message: DynMessage,
         when: Timestamp,
+    ) -> Result<MessageRef, Error>;
+
+    /// Reschedule a message which was previously sent with a future delivery time,
+    /// or cancel it if `when` is `None`. Has no effect if the message has already
+    /// been delivered.
+    fn dyn_change_scheduled(
+        &mut self,
+        message: MessageRef,
+        when: Option<Timestamp>,
     ) -> Result<(), Error>;
 
     /// Obtain the ID of the operation to which messages sent using this context belong.
@@ -198,7 +331,18 @@ pub trait ContextLike {
     // Provided
     /// Immediately send a message of any type to an agent of any type.
     fn dyn_send(&mut self, handle: DynAgentRef, message: DynMessage) -> Result<(), Error> {
-        self.dyn_send_at(handle, message, Timestamp::zero())
+        self.dyn_send_at(handle, message, Timestamp::zero())?;
+        Ok(())
+    }
+    /// Cancel a message which was previously sent with a future delivery time. Has
+    /// no effect if the message has already been delivered.
+    fn cancel(&mut self, message: MessageRef) -> Result<(), Error> {
+        self.dyn_change_scheduled(message, None)
+    }
+    /// Change when a message which was previously sent with a future delivery time
+    /// will be delivered. Has no effect if the message has already been delivered.
+    fn reschedule(&mut self, message: MessageRef, when: Timestamp) -> Result<(), Error> {
+        self.dyn_change_scheduled(message, Some(when))
     }
     /// Immediately construct a new agent of unknown type using a message of any type.
     fn dyn_construct(&mut self, root: Root, message: DynMessage) -> Result<DynAgentRef, Error> {
@@ -247,16 +391,18 @@ pub trait ContextLike {
     where
         Handler<M>: inventory::Collect,
     {
-        self.send_at(handle, message, Timestamp::zero())
+        self.send_at(handle, message, Timestamp::zero())?;
+        Ok(())
     }
 
-    /// Schedule a message to be sent to an agent.
+    /// Schedule a message to be sent to an agent. The returned handle can be used
+    /// to cancel or reschedule the message until it is delivered.
     fn send_at<M: Message, A: Handle<M>>(
         &mut self,
         handle: AgentRef<A>,
         message: M,
         when: Timestamp,
-    ) -> Result<(), Error>
+    ) -> Result<MessageRef, Error>
     where
         Handler<M>: inventory::Collect,
     {
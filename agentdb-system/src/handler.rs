@@ -4,6 +4,7 @@ use agentdb_core::Error;
 use async_trait::async_trait;
 use futures::future::BoxFuture;
 use futures::FutureExt;
+use tracing::Instrument;
 
 use crate::agent::Agent;
 use crate::agent_ref::{AgentRef, DynAgentRef};
@@ -71,14 +72,18 @@ where
     where
         Self: inventory::Collect,
     {
+        let span = tracing::debug_span!("handle", message_type = message.typetag_name());
         let type_id = state.type_id();
         for handler in inventory::iter::<Self> {
             if handler.type_id == type_id {
-                return (handler.handle_fn)(state, ref_, message, context).await;
+                return (handler.handle_fn)(state, ref_, message, context)
+                    .instrument(span)
+                    .await;
             }
         }
         state
             ._internal_handle_dyn(ref_, message.into(), context)
+            .instrument(span)
             .await
     }
 }
@@ -65,14 +65,15 @@ pub use agentdb_macros::*;
 pub use agent::{Agent, DynAgent};
 pub use agent_ref::{AgentRef, DynAgentRef};
 pub use agentdb_core::{
-    default_client_name, id, Error, Global, HookContext, Prepacked, Timestamp, TypedSubspace,
+    default_client_name, id, idempotency::CommitInfo, ClientConfig, Error, Global, HookContext,
+    Prepacked, Priority, Timestamp, TypedSubspace,
 };
 pub use constructor::{Construct, DynConstruct};
 pub use context::{CommitHook, Context, ContextLike, ExternalContext};
 pub use destructor::Destruct;
 pub use dynamic_handler::HandleDyn;
 pub use handler::Handle;
-pub use message::{DynMessage, Message};
+pub use message::{DynMessage, Message, MessageRef};
 pub use root::Root;
 pub use system::{run, start};
 
@@ -1,13 +1,17 @@
+use std::collections::BTreeMap;
+
 use agentdb_core::Error;
 use async_trait::async_trait;
 use downcast_rs::{impl_downcast, DowncastSync};
-use serde::{Deserialize, Serialize};
+use serde::{de::IgnoredAny, Deserialize, Serialize};
+use uuid::Uuid;
 
 use crate::agent::DynAgent;
 use crate::agent_ref::DynAgentRef;
 use crate::constructor::Constructor;
 use crate::context::Context;
 use crate::handler::Handler;
+use crate::root::Root;
 use crate::serializer::{DefaultSerializer, Serializer};
 
 /// A message of any type.
@@ -24,6 +28,17 @@ impl DynMessage {
         }
         Err(self)
     }
+    /// The registered type name of this message, if it can be determined
+    /// without deserializing the message.
+    pub fn type_name(&self) -> Option<String> {
+        // Messages are serialized as a map with a single entry, keyed by the type name
+        DefaultSerializer
+            .deserialize::<BTreeMap<String, IgnoredAny>>(&self.0)
+            .ok()?
+            .into_iter()
+            .next()
+            .map(|(type_name, _)| type_name)
+    }
 }
 
 impl<M: Message> From<M> for DynMessage {
@@ -36,6 +51,31 @@ impl<M: Message> From<M> for DynMessage {
     }
 }
 
+/// A handle to a message which was scheduled for later delivery. The handle can
+/// be stored as part of an agent's state, and used to cancel or reschedule the
+/// message until it is delivered.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct MessageRef {
+    pub(crate) root: Root,
+    pub(crate) id: Uuid,
+}
+
+impl MessageRef {
+    /// Directly construct the handle from the root of the receiving agent and
+    /// the message ID.
+    pub const fn from_parts(root: Root, id: Uuid) -> Self {
+        Self { root, id }
+    }
+    /// Obtain the AgentDB root containing the receiving agent.
+    pub fn root(self) -> Root {
+        self.root
+    }
+    /// Obtain the ID of the message.
+    pub fn id(self) -> Uuid {
+        self.id
+    }
+}
+
 #[doc(hidden)]
 pub async fn deliver_message<M: Message>(
     message: M,
@@ -94,3 +134,24 @@ pub trait Message: DowncastSync {
 }
 
 impl_downcast!(sync Message);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::declare_message;
+
+    #[derive(Serialize, Deserialize)]
+    struct TestMessage;
+
+    declare_message!("test_message" => TestMessage);
+
+    #[test]
+    fn type_name_is_registered_name() {
+        // Spans are annotated using both of these, so they must agree
+        assert_eq!(TestMessage.typetag_name(), "test_message");
+        assert_eq!(
+            DynMessage::from(TestMessage).type_name().as_deref(),
+            Some("test_message")
+        );
+    }
+}
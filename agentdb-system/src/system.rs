@@ -1,7 +1,8 @@
 use std::sync::Arc;
 
 use agentdb_core::cancellation::CancellableHandle;
-use agentdb_core::{Error, Global, StateFnInput, StateFnOutput};
+use agentdb_core::{ClientConfig, Error, Global, StateFnInput, StateFnOutput};
+use tracing::Instrument;
 
 use crate::agent::DynAgent;
 use crate::context::Context;
@@ -25,21 +26,30 @@ pub(crate) async fn system_fn_fallible(
 
     for inbound_msg in messages {
         context.operation_id = inbound_msg.operation_id;
+        let span = tracing::debug_span!(
+            "deliver",
+            root = %input.root,
+            agent_id = %input.id,
+            operation_id = %inbound_msg.operation_id,
+            message_type = tracing::field::Empty,
+        );
         match DefaultSerializer.deserialize::<Box<dyn Message>>(&inbound_msg.data) {
             Ok(msg) => {
+                span.record("message_type", &msg.typetag_name());
                 msg._internal_deliver(agent_ref, &mut maybe_agent_state, &mut context)
+                    .instrument(span)
                     .await?;
             }
             Err(e) if e.to_string().starts_with("unknown variant") => {
                 // Allow delivery of message types that might not be known to
                 // our crate.
-                deliver_unknown_message(
-                    DynMessage(inbound_msg.data),
-                    agent_ref,
-                    &mut maybe_agent_state,
-                    &mut context,
-                )
-                .await?;
+                let msg = DynMessage(inbound_msg.data);
+                if let Some(message_type) = msg.type_name() {
+                    span.record("message_type", &message_type.as_str());
+                }
+                deliver_unknown_message(msg, agent_ref, &mut maybe_agent_state, &mut context)
+                    .instrument(span)
+                    .await?;
             }
             Err(e) => return Err(e),
         }
@@ -50,6 +60,7 @@ pub(crate) async fn system_fn_fallible(
     Ok(StateFnOutput {
         state: maybe_agent_state.map(|a| a.0),
         messages: context.messages,
+        message_changes: context.message_changes,
         commit_hook: Box::new(|hook_ctx| {
             for commit_hook in commit_hooks {
                 commit_hook(hook_ctx.clone());
@@ -58,27 +69,28 @@ pub(crate) async fn system_fn_fallible(
     })
 }
 
-async fn system_fn(input: StateFnInput<'_>) -> Result<StateFnOutput, ()> {
-    system_fn_fallible(input).await.map_err(|e| {
-        log::error!("{:?}", e);
-    })
-}
-
 /// Start the AgentDB client, and return a cancellable handle.
 pub fn start(
     client_name: String,
     global: Arc<Global>,
     root: Root,
+    config: ClientConfig,
 ) -> CancellableHandle<Result<(), Error>> {
     agentdb_core::start(
         client_name,
         global,
         root.to_string(),
-        Arc::new(|input| Box::pin(async move { system_fn(input).await })),
+        Arc::new(|input| Box::pin(async move { system_fn_fallible(input).await })),
+        config,
     )
 }
 
 /// Run the AgentDB client forever, or until an error is returned.
-pub async fn run(client_name: String, global: Arc<Global>, root: Root) -> Result<(), Error> {
-    start(client_name, global, root).await?
+pub async fn run(
+    client_name: String,
+    global: Arc<Global>,
+    root: Root,
+    config: ClientConfig,
+) -> Result<(), Error> {
+    start(client_name, global, root, config).await?
 }
@@ -1,13 +1,13 @@
 //! Utilities for testing agents
 use std::fmt::Debug;
 
-use agentdb_core::{id, Error, OutboundMessage, StateFnInput, StateFnOutput, Timestamp};
+use agentdb_core::{id, Error, OutboundMessage, Priority, StateFnInput, StateFnOutput, Timestamp};
 use uuid::Uuid;
 
 use crate::{
     serializer::{DefaultSerializer, Serializer},
     system::system_fn_fallible,
-    Agent, DynAgent, DynAgentRef, DynMessage, Message, Root,
+    Agent, DynAgent, DynAgentRef, DynMessage, Message, MessageRef, Root,
 };
 
 /// Builder struct for running unit tests on agents
@@ -46,6 +46,7 @@ impl TestBuilder {
             data: DefaultSerializer
                 .serialize(message)
                 .expect("Infallible serialization"),
+            attachment: None,
         });
         self
     }
@@ -129,6 +130,11 @@ impl TestOutboundMessage<'_> {
     pub fn recipient_ref(&self) -> DynAgentRef {
         DynAgentRef::from_parts(self.recipient_root(), self.recipient_id())
     }
+    /// Returns a handle to this message, which can be used to cancel or
+    /// reschedule it
+    pub fn message_ref(&self) -> MessageRef {
+        MessageRef::from_parts(self.recipient_root(), self.message.message_id)
+    }
     /// Returns the ID of the operation to which this message belongs
     pub fn operation_id(&self) -> Uuid {
         self.message.operation_id
@@ -141,6 +147,14 @@ impl TestOutboundMessage<'_> {
     pub fn immediate(&self) -> bool {
         self.message.when == Timestamp::zero()
     }
+    /// Returns the priority with which this message will be delivered
+    pub fn priority(&self) -> Priority {
+        self.message.priority
+    }
+    /// Returns the time after which this message will be dropped, if any
+    pub fn expires_at(&self) -> Option<Timestamp> {
+        self.message.expires_at
+    }
     /// Returns the content of this message.
     pub fn dyn_content(&self) -> DynMessage {
         DynMessage(self.message.content.clone())